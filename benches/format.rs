@@ -0,0 +1,41 @@
+// Formats documents shaped to expose work that grows faster than the input: a single list on one long line, where
+// anything that scans back to the start of the line for each node becomes quadratic, and many short forms.
+// Run with `cargo bench --bench format`.
+
+use criterion::{BenchmarkId, Criterion, Throughput, black_box, criterion_group, criterion_main};
+use sexpfmt::{PrinterConfig, format_str};
+
+fn long_line(atom_count: usize) -> String {
+	let atoms: Vec<String> = (0..atom_count).map(|i| format!("atom{}", i)).collect();
+	format!("({})\n", atoms.join(" "))
+}
+
+fn many_forms(form_count: usize) -> String {
+	(0..form_count)
+		.map(|i| format!("(item {} (name \"x{}\")\n\n  [a b c]) ; {}\n", i, i, i))
+		.collect()
+}
+
+fn bench_format(c: &mut Criterion) {
+	let config = PrinterConfig::default();
+	let mut group = c.benchmark_group("format");
+	// doubling the length of the line should double the time taken
+	for atom_count in [50_000, 100_000] {
+		let text = long_line(atom_count);
+		group.throughput(Throughput::Bytes(text.len() as u64));
+		group.bench_with_input(
+			BenchmarkId::new("long_line", atom_count),
+			&text,
+			|b, text| b.iter(|| format_str(black_box(text), &config).unwrap().len()),
+		);
+	}
+	let text = many_forms(10_000);
+	group.throughput(Throughput::Bytes(text.len() as u64));
+	group.bench_function("many_forms", |b| {
+		b.iter(|| format_str(black_box(&text), &config).unwrap().len())
+	});
+	group.finish();
+}
+
+criterion_group!(benches, bench_format);
+criterion_main!(benches);
@@ -0,0 +1,52 @@
+// Compares the owned parsing path with the borrowed one on a synthetic trace.
+// Run with `cargo bench --bench parse`.
+
+use criterion::{Criterion, Throughput, black_box, criterion_group, criterion_main};
+use sexpfmt::{FormReader, Loc, parse_borrowed, parse_form};
+
+fn trace(event_count: usize) -> String {
+	let mut text = String::new();
+	for i in 0..event_count {
+		text.push_str(&format!(
+			"(event {} (time {}.{:03}) (thread \"worker-{}\")\n  [args (size {}) (flags :read :write)]) ; {}\n",
+			i,
+			i / 1000,
+			i % 1000,
+			i % 8,
+			i * 37 % 4096,
+			i
+		));
+	}
+	text
+}
+
+fn bench_parse(c: &mut Criterion) {
+	let text = trace(10_000);
+	let mut group = c.benchmark_group("parse");
+	group.throughput(Throughput::Bytes(text.len() as u64));
+
+	group.bench_function("form_reader_and_parse_form", |b| {
+		b.iter(|| {
+			let mut reader = FormReader::new(black_box(text.as_bytes())).unwrap();
+			let mut count = 0;
+			while let Some((s, loc)) = reader.get().unwrap() {
+				count += parse_form(s, loc).unwrap().len();
+			}
+			count
+		})
+	});
+	group.bench_function("parse_form", |b| {
+		b.iter(|| {
+			parse_form(black_box(&text).clone(), Loc::new(0, 1, 1))
+				.unwrap()
+				.len()
+		})
+	});
+	group.bench_function("parse_borrowed", |b| {
+		b.iter(|| parse_borrowed(black_box(&text)).unwrap().len())
+	});
+	group.finish();
+}
+
+criterion_group!(benches, bench_parse);
+criterion_main!(benches);
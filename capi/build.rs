@@ -0,0 +1,17 @@
+// Generates the C header from the `extern "C"` items in `src/lib.rs` into `OUT_DIR`.
+// The header is also checked in as `include/sexpfmt.h`, so that C users do not need a Rust toolchain to read it; the
+// `test_header_is_current` test checks that it matches, and rewrites it when `SEXPFMT_UPDATE_HEADER` is set.
+
+fn main() {
+	let crate_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+	let out_dir = std::env::var("OUT_DIR").unwrap();
+	let config = cbindgen::Config::from_file(format!("{crate_dir}/cbindgen.toml")).unwrap();
+	cbindgen::Builder::new()
+		.with_crate(&crate_dir)
+		.with_config(config)
+		.generate()
+		.expect("Unable to generate C header")
+		.write_to_file(format!("{out_dir}/sexpfmt.h"));
+	println!("cargo:rerun-if-changed=src/lib.rs");
+	println!("cargo:rerun-if-changed=cbindgen.toml");
+}
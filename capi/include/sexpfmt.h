@@ -0,0 +1,103 @@
+#ifndef SEXPFMT_H
+#define SEXPFMT_H
+
+/* Generated by cbindgen from capi/src/lib.rs. Do not edit by hand. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+typedef enum SexpfmtStatus {
+  SEXPFMT_STATUS_OK = 0,
+  /**
+   * An argument was NULL or out of range.
+   */
+  SEXPFMT_STATUS_INVALID_ARGUMENT = 1,
+  SEXPFMT_STATUS_IO = 2,
+  SEXPFMT_STATUS_UTF8 = 3,
+  SEXPFMT_STATUS_FORM_READER = 4,
+  SEXPFMT_STATUS_PARSE = 5,
+  SEXPFMT_STATUS_MISMATCHED_BOOKENDS = 6,
+  SEXPFMT_STATUS_UNEXPECTED_EOF = 7,
+  SEXPFMT_STATUS_INVALID_INPUT = 8,
+  /**
+   * A bug in sexpfmt: please report it.
+   */
+  SEXPFMT_STATUS_INTERNAL = 9,
+} SexpfmtStatus;
+
+/**
+ * Options controlling the layout of formatted output.
+ * Later versions may add fields at the end, so options should be obtained from `sexpfmt_default_options`, which sets
+ * `struct_size` to the size of the structure this header declares.
+ */
+typedef struct SexpfmtOptions {
+  /**
+   * `sizeof(SexpfmtOptions)`, as the caller was compiled with.
+   */
+  size_t struct_size;
+  /**
+   * The width that lines should fit in, if possible.
+   */
+  int32_t margin_width;
+  /**
+   * The number of spaces by which each level of nesting is indented.
+   */
+  int32_t indent_width;
+} SexpfmtOptions;
+
+/**
+ * The result of formatting.
+ * On success, `text` holds the formatted text. On failure, `error_message` describes the error, and `error_line`,
+ * `error_column` and `error_offset` locate it in the input when known (lines and columns start at 1, and are 0 when
+ * unknown). Both strings are NUL-terminated, and their lengths exclude the terminator.
+ */
+typedef struct SexpfmtOutput {
+  char *text;
+  size_t text_len;
+  char *error_message;
+  size_t error_message_len;
+  size_t error_line;
+  size_t error_column;
+  size_t error_offset;
+} SexpfmtOutput;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Returns the options used by the `sexpfmt` command-line tool.
+ */
+struct SexpfmtOptions sexpfmt_default_options(void);
+
+/**
+ * Formats the `input_len` bytes of UTF-8 text at `input`, as the `sexpfmt` command-line tool would.
+ * If `options` is NULL, the default options are used. Fields past `options->struct_size` take their default values,
+ * so that callers built against the header of an older version keep working.
+ * `output` is always filled in, and must be released with `sexpfmt_output_free` whatever the returned status.
+ *
+ * # Safety
+ * `input` must point to `input_len` readable bytes (it may be NULL if `input_len` is 0), `options` must be NULL or
+ * point to valid options, and `output` must point to writable memory for a `SexpfmtOutput`.
+ */
+enum SexpfmtStatus sexpfmt_format(const char *input,
+                                  size_t input_len,
+                                  const struct SexpfmtOptions *options,
+                                  struct SexpfmtOutput *output);
+
+/**
+ * Releases the strings held by `output`, and resets it to its empty state. Does nothing if `output` is NULL.
+ *
+ * # Safety
+ * `output` must be NULL or point to a `SexpfmtOutput` filled in by `sexpfmt_format`.
+ */
+void sexpfmt_output_free(struct SexpfmtOutput *output);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* SEXPFMT_H */
@@ -0,0 +1,346 @@
+//! A C API for `sexpfmt`.
+//!
+//! The header `include/sexpfmt.h` is generated from this file by `build.rs`. To update it after changing this file, run
+//! `SEXPFMT_UPDATE_HEADER=1 cargo test -p sexpfmt-capi`.
+//! Every string handed out by this library is owned by the caller, and must be released with `sexpfmt_output_free`.
+
+use sexpfmt::{PrinterConfig, SexpfmtError, format_str};
+
+use std::ffi::c_char;
+use std::panic::{AssertUnwindSafe, catch_unwind};
+
+/// Options controlling the layout of formatted output.
+/// Later versions may add fields at the end, so options should be obtained from `sexpfmt_default_options`, which sets
+/// `struct_size` to the size of the structure this header declares.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SexpfmtOptions {
+	/// `sizeof(SexpfmtOptions)`, as the caller was compiled with.
+	pub struct_size: usize,
+	/// The width that lines should fit in, if possible.
+	pub margin_width: i32,
+	/// The number of spaces by which each level of nesting is indented.
+	pub indent_width: i32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SexpfmtStatus {
+	Ok = 0,
+	/// An argument was NULL or out of range.
+	InvalidArgument = 1,
+	Io = 2,
+	Utf8 = 3,
+	FormReader = 4,
+	Parse = 5,
+	MismatchedBookends = 6,
+	UnexpectedEof = 7,
+	InvalidInput = 8,
+	/// A bug in sexpfmt: please report it.
+	Internal = 9,
+}
+
+// The size of `SexpfmtOptions` in the first version to have `struct_size`, which ended with `indent_width`: smaller sizes
+// cannot be valid. It must not grow along with the structure, or callers built against that version would be rejected.
+const FIRST_OPTIONS_SIZE: usize =
+	std::mem::offset_of!(SexpfmtOptions, indent_width) + size_of::<i32>();
+
+/// The result of formatting.
+/// On success, `text` holds the formatted text. On failure, `error_message` describes the error, and `error_line`,
+/// `error_column` and `error_offset` locate it in the input when known (lines and columns start at 1, and are 0 when
+/// unknown). Both strings are NUL-terminated, and their lengths exclude the terminator.
+#[repr(C)]
+#[derive(Debug)]
+pub struct SexpfmtOutput {
+	pub text: *mut c_char,
+	pub text_len: usize,
+	pub error_message: *mut c_char,
+	pub error_message_len: usize,
+	pub error_line: usize,
+	pub error_column: usize,
+	pub error_offset: usize,
+}
+
+impl SexpfmtOutput {
+	fn empty() -> Self {
+		Self {
+			text: std::ptr::null_mut(),
+			text_len: 0,
+			error_message: std::ptr::null_mut(),
+			error_message_len: 0,
+			error_line: 0,
+			error_column: 0,
+			error_offset: 0,
+		}
+	}
+}
+
+/// Returns the options used by the `sexpfmt` command-line tool.
+#[unsafe(no_mangle)]
+pub extern "C" fn sexpfmt_default_options() -> SexpfmtOptions {
+	let config = PrinterConfig::default();
+	SexpfmtOptions {
+		struct_size: size_of::<SexpfmtOptions>(),
+		margin_width: config.margin_width,
+		indent_width: config.indent_width,
+	}
+}
+
+/// Formats the `input_len` bytes of UTF-8 text at `input`, as the `sexpfmt` command-line tool would.
+/// If `options` is NULL, the default options are used. Fields past `options->struct_size` take their default values,
+/// so that callers built against the header of an older version keep working.
+/// `output` is always filled in, and must be released with `sexpfmt_output_free` whatever the returned status.
+///
+/// # Safety
+/// `input` must point to `input_len` readable bytes (it may be NULL if `input_len` is 0), `options` must be NULL or
+/// point to valid options, and `output` must point to writable memory for a `SexpfmtOutput`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn sexpfmt_format(
+	input: *const c_char,
+	input_len: usize,
+	options: *const SexpfmtOptions,
+	output: *mut SexpfmtOutput,
+) -> SexpfmtStatus {
+	if output.is_null() {
+		return SexpfmtStatus::InvalidArgument;
+	}
+	// SAFETY: the caller guarantees that `output` is writable.
+	let output = unsafe {
+		output.write(SexpfmtOutput::empty());
+		&mut *output
+	};
+
+	if input.is_null() && input_len != 0 {
+		return fail(
+			output,
+			SexpfmtStatus::InvalidArgument,
+			"input is NULL".to_string(),
+			None,
+		);
+	}
+	let input = if input_len == 0 {
+		&[]
+	} else {
+		// SAFETY: the caller guarantees that `input` points to `input_len` readable bytes.
+		unsafe { std::slice::from_raw_parts(input as *const u8, input_len) }
+	};
+	let options = if options.is_null() {
+		sexpfmt_default_options()
+	} else {
+		// SAFETY: the caller guarantees that non-NULL `options` are valid, so at least `struct_size` is readable.
+		let struct_size = unsafe { (*options).struct_size };
+		if struct_size < FIRST_OPTIONS_SIZE {
+			return fail(
+				output,
+				SexpfmtStatus::InvalidArgument,
+				"options were not initialized by sexpfmt_default_options".to_string(),
+				None,
+			);
+		}
+		let mut known = sexpfmt_default_options();
+		// SAFETY: `struct_size` bytes of `options` are readable, and only as many as `known` holds are copied over it.
+		unsafe {
+			std::ptr::copy_nonoverlapping(
+				options as *const u8,
+				&mut known as *mut SexpfmtOptions as *mut u8,
+				struct_size.min(size_of::<SexpfmtOptions>()),
+			)
+		};
+		known
+	};
+	if options.margin_width < 0 || options.indent_width < 0 {
+		return fail(
+			output,
+			SexpfmtStatus::InvalidArgument,
+			"margin_width and indent_width must not be negative".to_string(),
+			None,
+		);
+	}
+
+	let config = PrinterConfig {
+		margin_width: options.margin_width,
+		indent_width: options.indent_width,
+		..PrinterConfig::default()
+	};
+	let res = catch_unwind(AssertUnwindSafe(|| {
+		let text = String::from_utf8(input.to_vec())?;
+		format_str(&text, &config)
+	}));
+	match res {
+		Ok(Ok(text)) => {
+			(output.text, output.text_len) = into_c_string(text);
+			SexpfmtStatus::Ok
+		}
+		Ok(Err(e)) => {
+			let status = status_of(&e);
+			let message = e.to_string();
+			fail(output, status, message, e.position())
+		}
+		Err(_) => fail(
+			output,
+			SexpfmtStatus::Internal,
+			"sexpfmt panicked while formatting".to_string(),
+			None,
+		),
+	}
+}
+
+/// Releases the strings held by `output`, and resets it to its empty state. Does nothing if `output` is NULL.
+///
+/// # Safety
+/// `output` must be NULL or point to a `SexpfmtOutput` filled in by `sexpfmt_format`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn sexpfmt_output_free(output: *mut SexpfmtOutput) {
+	if output.is_null() {
+		return;
+	}
+	// SAFETY: the caller guarantees that `output` was filled in by `sexpfmt_format`, so its strings were allocated by
+	// `into_c_string` with the recorded lengths.
+	unsafe {
+		let output = &mut *output;
+		free_c_string(output.text, output.text_len);
+		free_c_string(output.error_message, output.error_message_len);
+		*output = SexpfmtOutput::empty();
+	}
+}
+
+fn fail(
+	output: &mut SexpfmtOutput,
+	status: SexpfmtStatus,
+	message: String,
+	position: Option<sexpfmt::Loc>,
+) -> SexpfmtStatus {
+	(output.error_message, output.error_message_len) = into_c_string(message);
+	if let Some(position) = position {
+		output.error_line = position.line();
+		output.error_column = position.column();
+		output.error_offset = position.offset();
+	}
+	status
+}
+
+fn status_of(e: &SexpfmtError) -> SexpfmtStatus {
+	match e {
+		SexpfmtError::Io { .. } => SexpfmtStatus::Io,
+		SexpfmtError::Utf8 { .. } => SexpfmtStatus::Utf8,
+		SexpfmtError::FormReader { .. } => SexpfmtStatus::FormReader,
+		SexpfmtError::Parse { .. } => SexpfmtStatus::Parse,
+		SexpfmtError::MismatchedBookends { .. } => SexpfmtStatus::MismatchedBookends,
+		SexpfmtError::UnexpectedEof { .. } => SexpfmtStatus::UnexpectedEof,
+		SexpfmtError::InvalidInput { .. } => SexpfmtStatus::InvalidInput,
+		SexpfmtError::InvalidConfig { .. } => SexpfmtStatus::InvalidArgument,
+	}
+}
+
+fn into_c_string(s: String) -> (*mut c_char, usize) {
+	let len = s.len();
+	let mut bytes = s.into_bytes();
+	bytes.push(0);
+	(Box::into_raw(bytes.into_boxed_slice()) as *mut c_char, len)
+}
+
+unsafe fn free_c_string(ptr: *mut c_char, len: usize) {
+	if !ptr.is_null() {
+		// SAFETY: `ptr` was returned by `into_c_string` for a string of `len` bytes, plus the terminator.
+		drop(unsafe { Box::from_raw(std::ptr::slice_from_raw_parts_mut(ptr as *mut u8, len + 1)) });
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use std::ffi::CStr;
+
+	fn format(input: &str, options: Option<SexpfmtOptions>) -> (SexpfmtStatus, SexpfmtOutput) {
+		let mut output = SexpfmtOutput::empty();
+		let options_ptr = options
+			.as_ref()
+			.map_or(std::ptr::null(), |options| options as *const _);
+		let status = unsafe {
+			sexpfmt_format(
+				input.as_ptr() as *const c_char,
+				input.len(),
+				options_ptr,
+				&mut output,
+			)
+		};
+		(status, output)
+	}
+
+	#[test]
+	fn test_format_ok() {
+		let (status, mut output) = format("(hello\n  world)", None);
+		assert_eq!(status, SexpfmtStatus::Ok);
+		assert!(output.error_message.is_null());
+		let text = unsafe { CStr::from_ptr(output.text) };
+		assert_eq!(text.to_str().unwrap(), "(hello world)\n");
+		assert_eq!(output.text_len, 14);
+		unsafe { sexpfmt_output_free(&mut output) };
+		assert!(output.text.is_null());
+	}
+
+	#[test]
+	fn test_format_options() {
+		let options = SexpfmtOptions {
+			margin_width: 10,
+			indent_width: 4,
+			..sexpfmt_default_options()
+		};
+		let (status, mut output) = format("(hello world)", Some(options));
+		assert_eq!(status, SexpfmtStatus::Ok);
+		let text = unsafe { CStr::from_ptr(output.text) };
+		assert_eq!(text.to_str().unwrap(), "(hello\n    world)\n");
+		unsafe { sexpfmt_output_free(&mut output) };
+	}
+
+	#[test]
+	fn test_format_error() {
+		let (status, mut output) = format("(a)\n  (b", None);
+		assert_eq!(status, SexpfmtStatus::UnexpectedEof);
+		assert!(output.text.is_null());
+		let message = unsafe { CStr::from_ptr(output.error_message) };
+		assert!(message.to_str().unwrap().contains("unclosed bookends"));
+		assert_eq!(
+			(output.error_line, output.error_column, output.error_offset),
+			(2, 3, 6)
+		);
+		unsafe { sexpfmt_output_free(&mut output) };
+	}
+
+	#[test]
+	fn test_format_invalid_arguments() {
+		let options = SexpfmtOptions {
+			margin_width: -1,
+			..sexpfmt_default_options()
+		};
+		let (status, mut output) = format("()", Some(options));
+		assert_eq!(status, SexpfmtStatus::InvalidArgument);
+		unsafe { sexpfmt_output_free(&mut output) };
+
+		let options = SexpfmtOptions {
+			struct_size: 0,
+			..sexpfmt_default_options()
+		};
+		let (status, mut output) = format("()", Some(options));
+		assert_eq!(status, SexpfmtStatus::InvalidArgument);
+		unsafe { sexpfmt_output_free(&mut output) };
+
+		let status =
+			unsafe { sexpfmt_format(std::ptr::null(), 0, std::ptr::null(), std::ptr::null_mut()) };
+		assert_eq!(status, SexpfmtStatus::InvalidArgument);
+	}
+
+	#[test]
+	fn test_header_is_current() {
+		let generated = include_str!(concat!(env!("OUT_DIR"), "/sexpfmt.h"));
+		let path = concat!(env!("CARGO_MANIFEST_DIR"), "/include/sexpfmt.h");
+		if std::env::var_os("SEXPFMT_UPDATE_HEADER").is_some() {
+			std::fs::write(path, generated).unwrap();
+		}
+		assert!(
+			std::fs::read_to_string(path).unwrap() == generated,
+			"include/sexpfmt.h is out of date: run `SEXPFMT_UPDATE_HEADER=1 cargo test -p sexpfmt-capi`"
+		);
+	}
+}
@@ -0,0 +1,607 @@
+// An arena representation of S-expressions, for documents too large or too deeply nested for `SExp`.
+// All nodes live in one flat vector, in the order in which they start in the text, so that the descendants of a node
+// directly follow it. The elements of each list are a range of a second flat vector of node ids, and atoms are ranges
+// of the source text, which the arena owns.
+// Parsing, formatting and dropping an arena all take constant stack space, however deeply it is nested.
+
+use super::*;
+
+use std::collections::HashMap;
+use std::ops::Range;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NodeId(usize);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum ArenaNode {
+	/// The text of the atom is `text[start..end]`.
+	Atom { start: usize, end: usize },
+	/// The ids of the elements are `child_ids[children]`. An empty range is a null list.
+	List {
+		style: SExpBookendStyle,
+		children: Range<usize>,
+	},
+}
+
+#[derive(Clone, Debug)]
+pub struct SExpArena {
+	text: String,
+	nodes: Vec<ArenaNode>,
+	child_ids: Vec<NodeId>,
+	roots: Range<usize>,
+}
+
+impl SExpArena {
+	/// Parses every form of `text`, as `parse_borrowed` would.
+	pub fn parse(text: String) -> Result<Self> {
+		let mut nodes = Vec::new();
+		let mut child_ids = Vec::new();
+		// the ids of the elements of the lists being read, and of the top-level forms, in order
+		let mut pending: Vec<NodeId> = Vec::new();
+		// the lists being read, innermost last, with where they start and where their elements start in `pending`
+		let mut stack: Vec<(NodeId, usize, usize)> = Vec::new();
+
+		let mut scanner = Scanner::new(&text);
+		while let Some((start, token)) = scanner.next_token()? {
+			let id = match token {
+				Token::Open(style) => {
+					stack.push((NodeId(nodes.len()), start, pending.len()));
+					nodes.push(ArenaNode::List {
+						style,
+						children: 0..0,
+					});
+					continue;
+				}
+				Token::Close(close_style) => {
+					let Some((id, open_offset, first_pending)) = stack.pop() else {
+						return Err(scanner.unexpected_close(start));
+					};
+					let ArenaNode::List { style, children } = &mut nodes[id.0] else {
+						unreachable!("only lists are pushed onto the stack");
+					};
+					scanner.check_bookends(open_offset, *style, close_style)?;
+					*children = child_ids.len()..child_ids.len() + (pending.len() - first_pending);
+					child_ids.extend(pending.drain(first_pending..));
+					id
+				}
+				Token::Atom(end) => {
+					nodes.push(ArenaNode::Atom { start, end });
+					NodeId(nodes.len() - 1)
+				}
+			};
+			pending.push(id);
+		}
+		if let Some((_, open_offset, _)) = stack.first() {
+			return Err(scanner.unexpected_eof(*open_offset, stack.len()));
+		}
+		let roots = child_ids.len()..child_ids.len() + pending.len();
+		child_ids.append(&mut pending);
+		Ok(Self {
+			text,
+			nodes,
+			child_ids,
+			roots,
+		})
+	}
+
+	pub fn text(&self) -> &str {
+		&self.text
+	}
+
+	/// The top-level forms.
+	pub fn roots(&self) -> &[NodeId] {
+		&self.child_ids[self.roots.clone()]
+	}
+
+	/// The number of nodes.
+	pub fn len(&self) -> usize {
+		self.nodes.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.nodes.is_empty()
+	}
+
+	pub fn atom(&self, id: NodeId) -> Option<&str> {
+		match self.nodes[id.0] {
+			ArenaNode::Atom { start, end } => Some(&self.text[start..end]),
+			ArenaNode::List { .. } => None,
+		}
+	}
+
+	/// The elements of a list; atoms have none.
+	pub fn children(&self, id: NodeId) -> &[NodeId] {
+		match &self.nodes[id.0] {
+			ArenaNode::List { children, .. } => &self.child_ids[children.clone()],
+			ArenaNode::Atom { .. } => &[],
+		}
+	}
+
+	pub fn bookend_style(&self, id: NodeId) -> Option<SExpBookendStyle> {
+		match self.nodes[id.0] {
+			ArenaNode::List { style, .. } => Some(style),
+			ArenaNode::Atom { .. } => None,
+		}
+	}
+
+	/// Copies a node into an owned `SExp`. Beware that `SExp` is recursive: deeply nested trees may overflow the stack
+	/// when dropped.
+	pub fn to_sexp(&self, id: NodeId) -> SExp {
+		if let Some(s) = self.atom(id) {
+			return SExp::atom(s);
+		}
+		// the lists being copied, innermost last, with their elements copied so far
+		let mut stack: Vec<(NodeId, Vec<SExp>)> = vec![(id, Vec::new())];
+		loop {
+			let (list, es) = stack.last_mut().unwrap();
+			let children = self.children(*list);
+			if let Some(&child) = children.get(es.len()) {
+				match self.atom(child) {
+					Some(s) => es.push(SExp::atom(s)),
+					None => stack.push((child, Vec::with_capacity(self.children(child).len()))),
+				}
+				continue;
+			}
+			let (list, es) = stack.pop().unwrap();
+			let sexp = SExp::list_with_style(es, self.bookend_style(list).unwrap());
+			match stack.last_mut() {
+				Some((_, parent_es)) => parent_es.push(sexp),
+				None => return sexp,
+			}
+		}
+	}
+
+	/// Formats a node, as `format_sexp` would with the default layout options: only the margin and indent widths and
+	/// the indent style of `config` are used.
+	pub fn format(&self, id: NodeId, config: &PrinterConfig) -> String {
+		let mut layouter = ArenaLayouter {
+			arena: self,
+			root: id,
+			monoline_widths: self.monoline_widths(id),
+			margin_width: i64::from(config.margin_width),
+			indent_width: i64::from(config.indent_step()),
+			tab_width: config.tab_width(),
+			layouts: HashMap::new(),
+		};
+		let indent_width = layouter.indent_width;
+		let tab_width = layouter.tab_width;
+
+		let mut out = String::new();
+		let mut column = 0;
+		// the lists being printed, innermost last
+		let mut stack: Vec<PrintFrame> = Vec::new();
+		let root = layouter.layout(id, 0, doc::Indent::default(), 0, None);
+		let mut next = Some((id, root.broken, doc::Indent::default(), 0));
+		loop {
+			if let Some((n, broken, indent, trailing_width)) = next.take() {
+				match &self.nodes[n.0] {
+					ArenaNode::Atom { start, end } => {
+						let s = &self.text[*start..*end];
+						out.push_str(s);
+						column += s.chars().count() as i64;
+					}
+					ArenaNode::List { style, .. } => {
+						let (open_token, close_token) = style.bookends();
+						out.push(open_token);
+						column += 1;
+						stack.push(PrintFrame {
+							id: n,
+							indent,
+							trailing_width,
+							broken,
+							padded: false,
+							close_token,
+							next_child: 0,
+						});
+					}
+				}
+			}
+
+			let Some(frame) = stack.last_mut() else {
+				return out;
+			};
+			let children = self.children(frame.id);
+			let i = frame.next_child;
+			match children.get(i) {
+				Some(&child) => {
+					let child_indent = frame.indent.nested(indent_width, tab_width);
+					if i > 0 {
+						if frame.broken {
+							doc::newline(&mut out, child_indent);
+							column = child_indent.width(tab_width);
+						} else {
+							out.push(' ');
+							column += 1;
+						}
+					}
+					let trailing_width = if i + 1 == children.len() {
+						frame.trailing_width + 1 + i64::from(frame.padded)
+					} else {
+						0
+					};
+					let first = (i == 0).then_some(children.len() == 1);
+					let broken = frame.broken
+						&& layouter
+							.layout(child, column, child_indent, trailing_width, first)
+							.broken;
+					if i == 0 && broken {
+						// as in `format_sexp`, pad a broken list whose first element is also broken
+						frame.padded = true;
+						out.push(' ');
+						column += 1;
+					}
+					frame.next_child += 1;
+					let trailing_width = trailing_width + i64::from(first == Some(true) && broken);
+					next = Some((child, broken, child_indent, trailing_width));
+				}
+				None => {
+					if frame.padded {
+						out.push(' ');
+					}
+					out.push(frame.close_token);
+					column += 1 + i64::from(frame.padded);
+					stack.pop();
+				}
+			}
+		}
+	}
+
+	/// Formats every top-level form, each on its own line.
+	pub fn format_document(&self, config: &PrinterConfig) -> String {
+		let mut out = String::new();
+		for &root in self.roots() {
+			out.push_str(&self.format(root, config));
+			out.push('\n');
+		}
+		out
+	}
+
+	// The width of each node of the subtree at `id` when printed on a single line, indexed by id relative to `id`
+	fn monoline_widths(&self, id: NodeId) -> Vec<i64> {
+		// the descendants of a node directly follow it, ending with those of its last element
+		let mut last = id;
+		while let Some(&child) = self.children(last).last() {
+			last = child;
+		}
+
+		let mut widths = vec![0; last.0 + 1 - id.0];
+		for n in (id.0..=last.0).rev() {
+			widths[n - id.0] = match &self.nodes[n] {
+				ArenaNode::Atom { start, end } => self.text[*start..*end].chars().count() as i64,
+				ArenaNode::List { children, .. } if children.is_empty() => 2,
+				ArenaNode::List { children, .. } => {
+					let es = &self.child_ids[children.clone()];
+					let es_width: i64 = es.iter().map(|e| widths[e.0 - id.0]).sum();
+					1 + es_width + (es.len() as i64 - 1) + 1
+				}
+			};
+		}
+		widths
+	}
+}
+
+// Chooses the layout of each list of the subtree at `root` as the document printer does: flat if it fits on its line
+// along with whatever follows it there, the closing brackets of the lists it ends, and otherwise in whichever mode
+// costs less, first by the width of the text that starts past the margin, then by the number of lines.
+struct ArenaLayouter<'a> {
+	arena: &'a SExpArena,
+	root: NodeId,
+	monoline_widths: Vec<i64>,
+	margin_width: i64,
+	indent_width: i64,
+	tab_width: Option<i64>,
+	// the layout of each list, by its column, its indentation, and the width of what follows it on its line when flat
+	layouts: HashMap<(NodeId, i64, doc::Indent, i64), ArenaLayout>,
+}
+
+#[derive(Clone, Copy)]
+struct ArenaLayout {
+	broken: bool,
+	cost: doc::Cost,
+	// the column after the node
+	end: i64,
+}
+
+// A list being laid out broken, to find out what that costs
+struct LayoutFrame {
+	id: NodeId,
+	column: i64,
+	indent: doc::Indent,
+	trailing_width: i64,
+	pads_parent: bool,
+	next_child: usize,
+	padded: bool,
+	end: i64,
+	cost: doc::Cost,
+}
+
+impl ArenaLayouter<'_> {
+	fn monoline_width(&self, n: NodeId) -> i64 {
+		self.monoline_widths[n.0 - self.root.0]
+	}
+
+	// The cost of text `width` columns wide at `column`
+	fn overflow(&self, column: i64, width: i64) -> doc::Cost {
+		doc::Cost {
+			overflow: if column >= self.margin_width {
+				width
+			} else {
+				0
+			},
+			lines: 0,
+		}
+	}
+
+	// The layout of `n` at `column` in a broken list, with the lines it starts indented by `indent`, followed on its
+	// line by `trailing_width` columns. If it is the first element of its list, it is padded when broken, and if it is
+	// the only one, so is the end of its list.
+	fn layout(
+		&mut self,
+		n: NodeId,
+		column: i64,
+		indent: doc::Indent,
+		trailing_width: i64,
+		first: Option<bool>,
+	) -> ArenaLayout {
+		let mut stack: Vec<LayoutFrame> = Vec::new();
+		let mut next = Some((n, column, indent, trailing_width, first));
+		let mut done = None;
+		loop {
+			if let Some((n, column, indent, trailing_width, first)) = next.take() {
+				let flat = ArenaLayout {
+					broken: false,
+					cost: doc::Cost::default(),
+					end: column + self.monoline_width(n),
+				};
+				if let Some(&layout) = self.layouts.get(&(n, column, indent, trailing_width)) {
+					done = Some(layout);
+				} else if self.arena.children(n).is_empty()
+					|| flat.end + trailing_width <= self.margin_width
+				{
+					done = Some(flat);
+				} else {
+					let mut end = column;
+					let mut cost = doc::Cost::default();
+					if first.is_some() {
+						cost = cost + self.overflow(end, 1);
+						end += 1;
+					}
+					cost = cost + self.overflow(end, 1);
+					end += 1;
+					stack.push(LayoutFrame {
+						id: n,
+						column,
+						indent,
+						trailing_width,
+						pads_parent: first == Some(true),
+						next_child: 0,
+						padded: false,
+						end,
+						cost,
+					});
+				}
+			}
+
+			let Some(frame) = stack.last_mut() else {
+				return done.unwrap();
+			};
+			if let Some(child) = done.take() {
+				frame.cost = frame.cost + child.cost;
+				frame.end = child.end;
+				if frame.next_child == 1 {
+					frame.padded = child.broken;
+				}
+			}
+			let children = self.arena.children(frame.id);
+			let i = frame.next_child;
+			if let Some(&child) = children.get(i) {
+				let child_indent = frame.indent.nested(self.indent_width, self.tab_width);
+				if i > 0 {
+					frame.cost.lines += 1;
+					frame.end = child_indent.width(self.tab_width);
+				}
+				let trailing_width = if i + 1 == children.len() {
+					frame.trailing_width + i64::from(frame.pads_parent) + 1 + i64::from(frame.padded)
+				} else {
+					0
+				};
+				frame.next_child += 1;
+				let first = (i == 0).then_some(children.len() == 1);
+				next = Some((child, frame.end, child_indent, trailing_width, first));
+				continue;
+			}
+
+			let frame = stack.pop().unwrap();
+			let mut broken = ArenaLayout {
+				broken: true,
+				cost: frame.cost,
+				end: frame.end,
+			};
+			if frame.padded {
+				broken.cost = broken.cost + self.overflow(broken.end, 1);
+				broken.end += 1;
+			}
+			broken.cost = broken.cost + self.overflow(broken.end, 1);
+			broken.end += 1;
+			let flat = ArenaLayout {
+				broken: false,
+				cost: doc::Cost {
+					overflow: self.flat_overflow(frame.id, frame.column),
+					lines: 0,
+				},
+				end: frame.column + self.monoline_width(frame.id),
+			};
+			// along with what follows each on its line
+			let rest_overflow = |end: i64, width: i64| doc::Cost {
+				overflow: (end + width - end.max(self.margin_width)).max(0),
+				lines: 0,
+			};
+			let flat_cost = flat.cost + rest_overflow(flat.end, frame.trailing_width);
+			let broken_cost = broken.cost
+				+ rest_overflow(
+					broken.end,
+					frame.trailing_width + i64::from(frame.pads_parent),
+				);
+			let layout = if flat_cost <= broken_cost {
+				flat
+			} else {
+				broken
+			};
+			self.layouts.insert(
+				(frame.id, frame.column, frame.indent, frame.trailing_width),
+				layout,
+			);
+			done = Some(layout);
+		}
+	}
+
+	// The width of the text of `n`, printed on a single line from `column`, that starts past the margin
+	fn flat_overflow(&self, n: NodeId, mut column: i64) -> i64 {
+		let end = column + self.monoline_width(n);
+		// the lists being walked, innermost last, with the number of their elements walked
+		let mut stack: Vec<(NodeId, usize)> = Vec::new();
+		let mut next = Some(n);
+		loop {
+			if column >= self.margin_width {
+				return end - column;
+			}
+			if let Some(n) = next.take() {
+				let children = self.arena.children(n);
+				if children.is_empty() {
+					column += self.monoline_width(n);
+				} else {
+					column += 1;
+					stack.push((n, 0));
+				}
+				continue;
+			}
+			let Some((list, i)) = stack.last_mut() else {
+				return 0;
+			};
+			match self.arena.children(*list).get(*i) {
+				Some(&child) => {
+					if *i > 0 {
+						column += 1;
+					}
+					*i += 1;
+					next = Some(child);
+				}
+				None => {
+					column += 1;
+					stack.pop();
+				}
+			}
+		}
+	}
+}
+
+struct PrintFrame {
+	id: NodeId,
+	// the indentation of the lines that the list starts, before that of its elements
+	indent: doc::Indent,
+	// the width of what follows the list on its last line
+	trailing_width: i64,
+	broken: bool,
+	padded: bool,
+	close_token: char,
+	next_child: usize,
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use proptest::prelude::*;
+
+	fn check_matches_sexp_printer(text: &str, config: &PrinterConfig) {
+		let arena = SExpArena::parse(text.to_string()).unwrap();
+		let sexps = parse_borrowed(text).unwrap();
+		assert_eq!(arena.roots().len(), sexps.len());
+		for (&root, sexp) in arena.roots().iter().zip(sexps.iter()) {
+			let sexp = sexp.to_sexp();
+			assert_eq!(arena.to_sexp(root), sexp);
+			assert_eq!(arena.format(root, config), format_sexp(&sexp, config));
+		}
+	}
+
+	#[test]
+	fn test_arena_matches_sexp_printer() {
+		for entry in std::fs::read_dir("test").unwrap() {
+			let path = entry.unwrap().path();
+			if path.extension().is_some_and(|ext| ext == "sexp") {
+				let text = std::fs::read_to_string(&path).unwrap();
+				if parse_borrowed(&text).is_ok() {
+					check_matches_sexp_printer(&text, &PrinterConfig::default());
+					check_matches_sexp_printer(
+						&text,
+						&PrinterConfig {
+							margin_width: 20,
+							indent_width: 4,
+							..PrinterConfig::default()
+						},
+					);
+					check_matches_sexp_printer(
+						&text,
+						&PrinterConfig {
+							margin_width: 20,
+							indent_style: IndentStyle::Tabs(3),
+							..PrinterConfig::default()
+						},
+					);
+				}
+			}
+		}
+	}
+
+	#[test]
+	fn test_arena_accessors() {
+		let arena = SExpArena::parse("(a [b c] ()) d".to_string()).unwrap();
+		assert_eq!(arena.len(), 7);
+		let [list, d] = arena.roots() else {
+			panic!("expected two roots");
+		};
+		assert_eq!(arena.atom(*d), Some("d"));
+		let [a, bc, null] = arena.children(*list) else {
+			panic!("expected three elements");
+		};
+		assert_eq!(arena.atom(*a), Some("a"));
+		assert_eq!(
+			arena.bookend_style(*bc),
+			Some(SExpBookendStyle::SquareBrackets)
+		);
+		assert_eq!(arena.children(*bc).len(), 2);
+		assert!(arena.children(*null).is_empty());
+		assert_eq!(
+			arena.format_document(&PrinterConfig::default()),
+			"(a [b c] ())\nd\n"
+		);
+		assert!(matches!(
+			SExpArena::parse("(a\n  [b)".to_string()),
+			Err(SexpfmtError::MismatchedBookends { position, .. }) if position == Loc::new(5, 2, 3)
+		));
+	}
+
+	#[test]
+	fn test_arena_deep_nesting() {
+		const DEPTH: usize = 1_000_000;
+		let text = format!("{}x{}", "(".repeat(DEPTH), ")".repeat(DEPTH));
+		let arena = SExpArena::parse(text.clone()).unwrap();
+		assert_eq!(arena.len(), DEPTH + 1);
+		let formatted = arena.format(arena.roots()[0], &PrinterConfig::default());
+		let compacted: String = formatted.chars().filter(|c| !c.is_whitespace()).collect();
+		assert_eq!(compacted, text);
+	}
+
+	proptest! {
+		#[test]
+		fn test_arena_matches_sexp_printer_random(
+			text in "([a-z]{1,6}|[ \n()\\[\\]])*",
+			margin_width in 0..40i32,
+			indent_width in 0..5i32,
+		) {
+			if parse_borrowed(&text).is_ok() {
+				check_matches_sexp_printer(&text, &PrinterConfig { margin_width, indent_width, ..PrinterConfig::default() });
+			}
+		}
+	}
+}
@@ -0,0 +1,345 @@
+// Classification of atoms. `SExp::Atom` keeps the spelling of an atom so that it prints as written; this works out
+// what that spelling denotes, following Scheme's lexical conventions. The kind of an atom is worked out once, when it
+// is made, so that telling symbols apart, as the printer does for the head of every list, does not lex it again.
+
+use super::*;
+
+use std::fmt;
+use std::ops::Deref;
+
+/// The spelling of an atom, as written, along with its kind.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Atom {
+	spelling: String,
+	lexeme: Lexeme,
+}
+
+// The kind of an atom, without its value
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Lexeme {
+	Symbol,
+	String,
+	Integer,
+	Float,
+	Boolean,
+	Character,
+}
+
+impl Atom {
+	pub fn new<S: Into<String>>(spelling: S) -> Self {
+		let spelling = spelling.into();
+		let lexeme = lex(&spelling);
+		Self { spelling, lexeme }
+	}
+
+	pub fn as_str(&self) -> &str {
+		&self.spelling
+	}
+
+	pub fn into_string(self) -> String {
+		self.spelling
+	}
+
+	pub fn is_symbol(&self) -> bool {
+		self.lexeme == Lexeme::Symbol
+	}
+
+	/// What this atom denotes.
+	pub fn kind(&self) -> AtomKind {
+		decode(&self.spelling, self.lexeme)
+	}
+}
+
+// The value of an atom spelled `s`, which `lex` found to be a `lexeme`
+fn decode(s: &str, lexeme: Lexeme) -> AtomKind {
+	match lexeme {
+		Lexeme::Symbol => AtomKind::Symbol,
+		Lexeme::String => AtomKind::String(decode_string(&s[1..s.len() - 1])),
+		Lexeme::Integer => match s.parse() {
+			Ok(i) => AtomKind::Integer(i),
+			Err(_) => AtomKind::Float(s.parse().unwrap()),
+		},
+		Lexeme::Float => AtomKind::Float(s.parse().unwrap()),
+		Lexeme::Boolean => AtomKind::Boolean(matches!(s, "#t" | "#true")),
+		Lexeme::Character => AtomKind::Character(decode_character(&s[2..]).unwrap()),
+	}
+}
+
+impl Deref for Atom {
+	type Target = str;
+
+	fn deref(&self) -> &str {
+		&self.spelling
+	}
+}
+
+impl fmt::Debug for Atom {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		fmt::Debug::fmt(&self.spelling, f)
+	}
+}
+
+impl fmt::Display for Atom {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(&self.spelling)
+	}
+}
+
+impl PartialEq<str> for Atom {
+	fn eq(&self, other: &str) -> bool {
+		self.spelling == other
+	}
+}
+
+impl PartialEq<&str> for Atom {
+	fn eq(&self, other: &&str) -> bool {
+		self.spelling == *other
+	}
+}
+
+impl From<&str> for Atom {
+	fn from(s: &str) -> Self {
+		Atom::new(s)
+	}
+}
+
+impl From<String> for Atom {
+	fn from(s: String) -> Self {
+		Atom::new(s)
+	}
+}
+
+/// What an atom denotes.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AtomKind {
+	Symbol,
+	/// A string literal, with its escape sequences decoded.
+	String(String),
+	Integer(i64),
+	/// A decimal number with a fraction or an exponent, or an integer too large for `i64`.
+	Float(f64),
+	/// `#t` or `#f`, also spelled `#true` and `#false`.
+	Boolean(bool),
+	/// A character such as `#\a`, `#\space` or `#\x41`.
+	Character(char),
+}
+
+impl AtomKind {
+	pub fn classify(spelling: &str) -> Self {
+		decode(spelling, lex(spelling))
+	}
+}
+
+fn lex(spelling: &str) -> Lexeme {
+	if spelling.len() >= 2 && spelling.starts_with('"') && spelling.ends_with('"') {
+		return Lexeme::String;
+	}
+	if matches!(spelling, "#t" | "#true" | "#f" | "#false") {
+		return Lexeme::Boolean;
+	}
+	if let Some(name) = spelling.strip_prefix("#\\") {
+		return match decode_character(name) {
+			Some(_) => Lexeme::Character,
+			None => Lexeme::Symbol,
+		};
+	}
+	if is_integer(spelling) {
+		return Lexeme::Integer;
+	}
+	if is_float(spelling) {
+		return Lexeme::Float;
+	}
+	Lexeme::Symbol
+}
+
+impl SExp {
+	/// What this atom denotes, or `None` for a list.
+	pub fn atom_kind(&self) -> Option<AtomKind> {
+		match self {
+			SExp::Atom(atom) => Some(atom.kind()),
+			SExp::List(..) | SExp::Null(_) => None,
+		}
+	}
+
+	pub fn as_symbol(&self) -> Option<&str> {
+		match self {
+			SExp::Atom(atom) if atom.is_symbol() => Some(atom),
+			_ => None,
+		}
+	}
+
+	/// The decoded value of a string literal.
+	pub fn as_string(&self) -> Option<String> {
+		match self.atom_kind()? {
+			AtomKind::String(s) => Some(s),
+			_ => None,
+		}
+	}
+
+	pub fn as_integer(&self) -> Option<i64> {
+		match self.atom_kind()? {
+			AtomKind::Integer(i) => Some(i),
+			_ => None,
+		}
+	}
+
+	/// The value of a float or an integer.
+	pub fn as_float(&self) -> Option<f64> {
+		match self.atom_kind()? {
+			AtomKind::Float(x) => Some(x),
+			AtomKind::Integer(i) => Some(i as f64),
+			_ => None,
+		}
+	}
+
+	pub fn as_bool(&self) -> Option<bool> {
+		match self.atom_kind()? {
+			AtomKind::Boolean(b) => Some(b),
+			_ => None,
+		}
+	}
+
+	pub fn as_char(&self) -> Option<char> {
+		match self.atom_kind()? {
+			AtomKind::Character(c) => Some(c),
+			_ => None,
+		}
+	}
+}
+
+fn decode_string(content: &str) -> String {
+	let mut res = String::with_capacity(content.len());
+	let mut chars = content.chars();
+	while let Some(c) = chars.next() {
+		if c != '\\' {
+			res.push(c);
+			continue;
+		}
+		match chars.next() {
+			Some('n') => res.push('\n'),
+			Some('t') => res.push('\t'),
+			Some('r') => res.push('\r'),
+			Some('0') => res.push('\0'),
+			Some(c @ ('"' | '\\')) => res.push(c),
+			// unknown escapes are kept as written
+			Some(c) => {
+				res.push('\\');
+				res.push(c);
+			}
+			None => res.push('\\'),
+		}
+	}
+	res
+}
+
+fn decode_character(name: &str) -> Option<char> {
+	let mut chars = name.chars();
+	if let (Some(c), None) = (chars.next(), chars.next()) {
+		return Some(c);
+	}
+	match name {
+		"space" => Some(' '),
+		"newline" | "linefeed" => Some('\n'),
+		"tab" => Some('\t'),
+		"return" => Some('\r'),
+		"nul" | "null" => Some('\0'),
+		"alarm" => Some('\x07'),
+		"backspace" => Some('\x08'),
+		"escape" => Some('\x1B'),
+		"delete" => Some('\x7F'),
+		_ => {
+			let hex = name.strip_prefix('x')?;
+			char::from_u32(u32::from_str_radix(hex, 16).ok()?)
+		}
+	}
+}
+
+fn strip_sign(s: &str) -> &str {
+	s.strip_prefix(['+', '-']).unwrap_or(s)
+}
+
+fn is_digits(s: &str) -> bool {
+	!s.is_empty() && s.bytes().all(|b| b.is_ascii_digit())
+}
+
+fn is_integer(s: &str) -> bool {
+	is_digits(strip_sign(s))
+}
+
+// `[+-]?(D+|D+.D*|.D+)([eE][+-]?D+)?`, with a fraction or an exponent
+fn is_float(s: &str) -> bool {
+	let s = strip_sign(s);
+	let (mantissa, exponent) = match s.find(['e', 'E']) {
+		Some(i) => (&s[..i], Some(&s[i + 1..])),
+		None => (s, None),
+	};
+	if exponent.is_some_and(|e| !is_digits(strip_sign(e))) {
+		return false;
+	}
+	match mantissa.split_once('.') {
+		Some((int, frac)) => {
+			(int.is_empty() || is_digits(int))
+				&& (frac.is_empty() || is_digits(frac))
+				&& !(int.is_empty() && frac.is_empty())
+		}
+		None => exponent.is_some() && is_digits(mantissa),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_classify_atoms() {
+		let cases = [
+			("foo", AtomKind::Symbol),
+			("-", AtomKind::Symbol),
+			("...", AtomKind::Symbol),
+			("1+", AtomKind::Symbol),
+			("e10", AtomKind::Symbol),
+			(
+				r#""42 \"x\"\n""#,
+				AtomKind::String("42 \"x\"\n".to_string()),
+			),
+			(r#""a\qb""#, AtomKind::String("a\\qb".to_string())),
+			("42", AtomKind::Integer(42)),
+			("-7", AtomKind::Integer(-7)),
+			("+0", AtomKind::Integer(0)),
+			("99999999999999999999", AtomKind::Float(1e20)),
+			(".567", AtomKind::Float(0.567)),
+			("123.9870", AtomKind::Float(123.987)),
+			("1.", AtomKind::Float(1.0)),
+			("-1.5e-3", AtomKind::Float(-0.0015)),
+			("2E3", AtomKind::Float(2000.0)),
+			("#t", AtomKind::Boolean(true)),
+			("#false", AtomKind::Boolean(false)),
+			(r"#\space", AtomKind::Character(' ')),
+			(r"#\a", AtomKind::Character('a')),
+			(r"#\x41", AtomKind::Character('A')),
+			(r"#\bogus", AtomKind::Symbol),
+		];
+		for (spelling, kind) in cases {
+			let atom = Atom::new(spelling);
+			assert_eq!(atom.is_symbol(), kind == AtomKind::Symbol, "{}", spelling);
+			assert_eq!(atom.kind(), kind, "{}", spelling);
+			assert_eq!(AtomKind::classify(spelling), kind, "{}", spelling);
+		}
+	}
+
+	#[test]
+	fn test_sexp_atom_accessors() {
+		let e: SExp = r#"(size 3 2.5 "tall" #t #\x)"#.parse().unwrap();
+		assert_eq!(e[0].as_symbol(), Some("size"));
+		assert_eq!(e[1].as_integer(), Some(3));
+		assert_eq!(e[1].as_float(), Some(3.0));
+		assert_eq!(e[2].as_integer(), None);
+		assert_eq!(e[2].as_float(), Some(2.5));
+		assert_eq!(e[3].as_string().as_deref(), Some("tall"));
+		assert_eq!(e[3].as_atom(), Some("\"tall\""));
+		assert_eq!(e[4].as_bool(), Some(true));
+		assert_eq!(e[5].as_char(), Some('x'));
+		assert_eq!(e.atom_kind(), None);
+		assert_eq!(e.to_string(), r#"(size 3 2.5 "tall" #t #\x)"#);
+	}
+}
@@ -0,0 +1,299 @@
+// A borrowed variant of `SExp`, whose atoms are slices of the parsed text. Parsing one allocates only the element
+// vectors of lists, which makes it suitable for very large inputs, such as memory-mapped files:
+//
+//     let text = std::str::from_utf8(&mmap)?;
+//     let forms = parse_borrowed(text)?;
+
+use super::*;
+
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SExpRef<'a> {
+	List(Vec<SExpRef<'a>>, SExpBookendStyle),
+	/// The atom exactly as spelled in the source, string quotes included.
+	Atom(&'a str),
+	Null(SExpBookendStyle),
+}
+
+impl<'a> SExpRef<'a> {
+	pub fn as_atom(&self) -> Option<&'a str> {
+		match self {
+			SExpRef::Atom(s) => Some(s),
+			_ => None,
+		}
+	}
+
+	/// The elements of a list, which are none for `SExpRef::Null`.
+	pub fn as_list(&self) -> Option<&[SExpRef<'a>]> {
+		match self {
+			SExpRef::List(es, _) => Some(es),
+			SExpRef::Null(_) => Some(&[]),
+			SExpRef::Atom(_) => None,
+		}
+	}
+
+	/// Copies the tree into an owned `SExp`.
+	pub fn to_sexp(&self) -> SExp {
+		match self {
+			SExpRef::List(es, style) => SExp::List(es.iter().map(SExpRef::to_sexp).collect(), *style),
+			SExpRef::Atom(s) => SExp::atom(*s),
+			SExpRef::Null(style) => SExp::Null(*style),
+		}
+	}
+}
+
+impl fmt::Display for SExpRef<'_> {
+	/// Writes the expression on a single line, as `SExp` does.
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			SExpRef::Atom(s) => f.write_str(s),
+			SExpRef::Null(style) => {
+				let (lp, rp) = style.bookends();
+				write!(f, "{}{}", lp, rp)
+			}
+			SExpRef::List(es, style) => {
+				let (lp, rp) = style.bookends();
+				write!(f, "{}", lp)?;
+				for (i, e) in es.iter().enumerate() {
+					if i > 0 {
+						write!(f, " ")?;
+					}
+					write!(f, "{}", e)?;
+				}
+				write!(f, "{}", rp)
+			}
+		}
+	}
+}
+
+/// Parses every form of `text` without copying atoms. The result is the same as that of `parse_form`, but for
+/// borrowing, and for accepting lists that hold only whitespace or comments, such as `( )`, as empty lists.
+/// Lists nested more than `DEFAULT_MAX_DEPTH` deep are rejected; use `SExpArena` for such input.
+pub fn parse_borrowed(text: &str) -> Result<Vec<SExpRef<'_>>> {
+	let mut scanner = Scanner::new(text);
+	// the lists being read, innermost last, with the offset at which each starts
+	let mut stack: Vec<(Vec<SExpRef>, SExpBookendStyle, usize)> = Vec::new();
+	let mut top = Vec::new();
+	while let Some((start, token)) = scanner.next_token()? {
+		let sexp = match token {
+			Token::Open(style) => {
+				if stack.len() == DEFAULT_MAX_DEPTH {
+					return Err(SexpfmtError::nesting_too_deep(
+						Loc::at_offset(text, start),
+						DEFAULT_MAX_DEPTH,
+					));
+				}
+				stack.push((Vec::new(), style, start));
+				continue;
+			}
+			Token::Close(style) => {
+				let Some((es, open_style, open_offset)) = stack.pop() else {
+					return Err(scanner.unexpected_close(start));
+				};
+				scanner.check_bookends(open_offset, open_style, style)?;
+				if es.is_empty() {
+					SExpRef::Null(style)
+				} else {
+					SExpRef::List(es, style)
+				}
+			}
+			Token::Atom(end) => SExpRef::Atom(&text[start..end]),
+		};
+		match stack.last_mut() {
+			Some((es, _, _)) => es.push(sexp),
+			None => top.push(sexp),
+		}
+	}
+	if let Some((_, _, open_offset)) = stack.first() {
+		return Err(scanner.unexpected_eof(*open_offset, stack.len()));
+	}
+	Ok(top)
+}
+
+// Reports what `parse_borrowed` would, but for lists nested up to `max_depth` deep, without building the tree.
+pub(crate) fn check_balanced(text: &str, max_depth: usize) -> Result<()> {
+	let mut scanner = Scanner::new(text);
+	// the style of each list being read, innermost last, with the offset at which it starts
+	let mut stack: Vec<(SExpBookendStyle, usize)> = Vec::new();
+	while let Some((start, token)) = scanner.next_token()? {
+		match token {
+			Token::Open(_) if stack.len() == max_depth => {
+				return Err(SexpfmtError::nesting_too_deep(
+					Loc::at_offset(text, start),
+					max_depth,
+				));
+			}
+			Token::Open(style) => stack.push((style, start)),
+			Token::Close(style) => {
+				let Some((open_style, open_offset)) = stack.pop() else {
+					return Err(scanner.unexpected_close(start));
+				};
+				scanner.check_bookends(open_offset, open_style, style)?;
+			}
+			Token::Atom(_) => {}
+		}
+	}
+	if let Some((_, open_offset)) = stack.first() {
+		return Err(scanner.unexpected_eof(*open_offset, stack.len()));
+	}
+	Ok(())
+}
+
+pub(crate) enum Token {
+	Open(SExpBookendStyle),
+	Close(SExpBookendStyle),
+	/// An atom, ending at the given offset.
+	Atom(usize),
+}
+
+// Splits text into tokens, skipping whitespace and comments.
+// Scans bytes rather than going through nom, which is several times slower here: all delimiters are ASCII, so they
+// never occur inside multi-byte characters. Locations are only worked out for errors.
+pub(crate) struct Scanner<'a> {
+	text: &'a str,
+	pos: usize,
+}
+
+impl<'a> Scanner<'a> {
+	pub(crate) fn new(text: &'a str) -> Self {
+		Self { text, pos: 0 }
+	}
+
+	/// Returns the next token along with the offset at which it starts, or `None` at the end of the text.
+	pub(crate) fn next_token(&mut self) -> Result<Option<(usize, Token)>> {
+		let bytes = self.text.as_bytes();
+		while self.pos < bytes.len() {
+			let start = self.pos;
+			match bytes[start] {
+				b' ' | b'\t' | b'\r' | b'\n' => self.pos += 1,
+				b';' => self.pos = find_byte(bytes, start, |b| b == b'\n' || b == b'\r'),
+				b @ (b'(' | b'[' | b'{') => {
+					self.pos += 1;
+					let style = SExpBookendStyle::from_char(b as char).unwrap();
+					return Ok(Some((start, Token::Open(style))));
+				}
+				b @ (b')' | b']' | b'}') => {
+					self.pos += 1;
+					let style = SExpBookendStyle::from_char(b as char).unwrap();
+					return Ok(Some((start, Token::Close(style))));
+				}
+				b'"' => {
+					let mut i = start + 1;
+					loop {
+						match bytes.get(i) {
+							Some(b'\\') if bytes.get(i + 1) == Some(&b'"') => i += 2,
+							Some(b'"') => break,
+							Some(b'\n' | b'\r') | None => {
+								return Err(SexpfmtError::parse_error(
+									format!("Unterminated string: '{}'", &self.text[start..i]),
+									Loc::at_offset(self.text, start),
+									None,
+								));
+							}
+							Some(_) => i += 1,
+						}
+					}
+					self.pos = i + 1;
+					return Ok(Some((start, Token::Atom(self.pos))));
+				}
+				_ => {
+					self.pos = find_byte(bytes, start, |b| b"\"\n\r\t ()[]{};".contains(&b));
+					return Ok(Some((start, Token::Atom(self.pos))));
+				}
+			}
+		}
+		Ok(None)
+	}
+
+	pub(crate) fn check_bookends(
+		&self,
+		open_offset: usize,
+		open_style: SExpBookendStyle,
+		close_style: SExpBookendStyle,
+	) -> Result<()> {
+		if open_style != close_style {
+			return Err(SexpfmtError::mismatched_bookends(
+				Loc::at_offset(self.text, open_offset),
+				open_style,
+				close_style,
+			));
+		}
+		Ok(())
+	}
+
+	pub(crate) fn unexpected_close(&self, offset: usize) -> SexpfmtError {
+		SexpfmtError::parse_error(
+			"Unexpected closing bookend",
+			Loc::at_offset(self.text, offset),
+			None,
+		)
+	}
+
+	pub(crate) fn unexpected_eof(&self, open_offset: usize, unclosed_count: usize) -> SexpfmtError {
+		SexpfmtError::unexpected_eof(Loc::at_offset(self.text, open_offset), unclosed_count)
+	}
+}
+
+// The index of the first byte from `i` on that matches `pred`, or the length of `bytes`
+fn find_byte(bytes: &[u8], i: usize, pred: impl Fn(u8) -> bool) -> usize {
+	bytes[i..]
+		.iter()
+		.position(|&b| pred(b))
+		.map_or(bytes.len(), |n| i + n)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use proptest::prelude::*;
+
+	#[test]
+	fn test_parse_borrowed() {
+		let text = "; header\n(a \"b \\\"c\\\"\" ; note\n  [x .5]) {} d";
+		let forms = parse_borrowed(text).unwrap();
+		assert_eq!(
+			forms.iter().map(SExpRef::to_sexp).collect::<Vec<_>>(),
+			parse_form(text.to_string(), Loc::new(0, 1, 1)).unwrap()
+		);
+		// atoms point into the text
+		let atom = forms[0].as_list().unwrap()[1].as_atom().unwrap();
+		assert_eq!(atom, "\"b \\\"c\\\"\"");
+		assert!(text.as_bytes().as_ptr_range().contains(&atom.as_ptr()));
+		assert_eq!(forms[0].to_string(), "(a \"b \\\"c\\\"\" [x .5])");
+	}
+
+	proptest! {
+		#[test]
+		fn test_parse_borrowed_matches_cst(text in "[a-z ()\\[\\]{};\"\\\\\n]{0,32}") {
+			let borrowed = parse_borrowed(&text).map(|forms| forms.iter().map(SExpRef::to_sexp).collect());
+			let cst = parse_cst(&text).map(|nodes| cst_to_sexps(&nodes));
+			prop_assert_eq!(borrowed.ok(), cst.ok());
+		}
+	}
+
+	#[test]
+	fn test_parse_borrowed_errors() {
+		assert!(matches!(
+			parse_borrowed("(a\n  [b)"),
+			Err(SexpfmtError::MismatchedBookends { position, .. }) if position == Loc::new(5, 2, 3)
+		));
+		assert!(matches!(
+			parse_borrowed("((a)"),
+			Err(SexpfmtError::UnexpectedEof {
+				unclosed_count: 1,
+				..
+			})
+		));
+		assert!(matches!(
+			parse_borrowed("a \"b"),
+			Err(SexpfmtError::Parse { position, .. }) if position == Loc::new(2, 1, 3)
+		));
+		let deep = format!("{}{}", "(".repeat(100_000), ")".repeat(100_000));
+		assert!(matches!(
+			parse_borrowed(&deep),
+			Err(SexpfmtError::InvalidInput { position, .. }) if position.offset() == DEFAULT_MAX_DEPTH
+		));
+	}
+}
@@ -0,0 +1,366 @@
+// A lossless concrete syntax tree: unlike `SExp`, it keeps whitespace, comments and the exact spelling of every atom,
+// so that printing a parsed tree reproduces its source byte for byte.
+// The formatter reads each top-level form through it: `locate_cst` turns the tree into the `SExp`s to lay out, with the
+// blank lines and comments around each node recorded in their `LocTree`s for the printer to keep.
+
+use super::*;
+
+use std::fmt;
+
+use nom::branch::*;
+use nom::character::complete::*;
+use nom::combinator::*;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CstNode {
+	Whitespace(String),
+	/// A line comment, from its `;` up to but excluding the end of the line.
+	Comment(String),
+	Atom(String),
+	/// A list, with the whitespace and comments between its elements.
+	List(Vec<CstNode>, SExpBookendStyle),
+}
+
+impl CstNode {
+	/// Whether this is whitespace or a comment.
+	pub fn is_trivia(&self) -> bool {
+		matches!(self, CstNode::Whitespace(_) | CstNode::Comment(_))
+	}
+
+	/// Whether this is a comment, or a list containing one.
+	pub fn has_comments(&self) -> bool {
+		match self {
+			CstNode::Comment(_) => true,
+			CstNode::List(nodes, _) => nodes.iter().any(CstNode::has_comments),
+			CstNode::Whitespace(_) | CstNode::Atom(_) => false,
+		}
+	}
+
+	/// Drops whitespace and comments, giving the `SExp` that `parse_form` would produce. `None` for trivia.
+	pub fn to_sexp(&self) -> Option<SExp> {
+		match self {
+			CstNode::Whitespace(_) | CstNode::Comment(_) => None,
+			CstNode::Atom(s) => Some(SExp::atom(s.as_str())),
+			CstNode::List(nodes, style) => {
+				let es = cst_to_sexps(nodes);
+				Some(if es.is_empty() {
+					SExp::Null(*style)
+				} else {
+					SExp::List(es, *style)
+				})
+			}
+		}
+	}
+}
+
+impl fmt::Display for CstNode {
+	/// Prints the node exactly as it was written.
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			CstNode::Whitespace(s) | CstNode::Comment(s) | CstNode::Atom(s) => f.write_str(s),
+			CstNode::List(nodes, style) => {
+				let (lp, rp) = style.bookends();
+				write!(f, "{}", lp)?;
+				for node in nodes.iter() {
+					write!(f, "{}", node)?;
+				}
+				write!(f, "{}", rp)
+			}
+		}
+	}
+}
+
+/// Parses a whole document. Printing the result with `print_cst` gives back `text`. Lists nested more than
+/// `DEFAULT_MAX_DEPTH` deep are rejected.
+pub fn parse_cst(text: &str) -> Result<Vec<CstNode>> {
+	parse_cst_at(text, Loc::new(0, 1, 1), DEFAULT_MAX_DEPTH)
+}
+
+// Parses text that starts at `start` in some larger document, rejecting lists nested more than `max_depth` deep
+pub(crate) fn parse_cst_at(text: &str, start: Loc, max_depth: usize) -> Result<Vec<CstNode>> {
+	let mut cursor = LocCursor::new(text, start);
+	// the lists being read, innermost last, with where each starts
+	let mut stack: Vec<(Vec<CstNode>, SExpBookendStyle, Loc)> = Vec::new();
+	let mut top = Vec::new();
+	let mut input = LocSpan::new(text);
+	while !input.is_empty() {
+		let loc = cursor.at(input.location_offset());
+		let (rest, token) = cst_token(input).map_err(|_| {
+			SexpfmtError::parse_error(
+				format!("Unexpected input: '{}'", input.fragment()),
+				loc,
+				None,
+			)
+		})?;
+		input = rest;
+
+		let node = match token {
+			CstToken::Open(style) => {
+				if stack.len() == max_depth {
+					return Err(SexpfmtError::nesting_too_deep(loc, max_depth));
+				}
+				stack.push((Vec::new(), style, loc));
+				continue;
+			}
+			CstToken::Close(style) => match stack.pop() {
+				Some((nodes, open_style, open_loc)) => {
+					if open_style != style {
+						return Err(SexpfmtError::mismatched_bookends(
+							open_loc, open_style, style,
+						));
+					}
+					CstNode::List(nodes, style)
+				}
+				None => {
+					return Err(SexpfmtError::parse_error(
+						"Unexpected closing bookend",
+						loc,
+						None,
+					));
+				}
+			},
+			CstToken::Node(node) => node,
+		};
+		match stack.last_mut() {
+			Some((nodes, _, _)) => nodes.push(node),
+			None => top.push(node),
+		}
+	}
+	if let Some((_, _, open_loc)) = stack.first() {
+		return Err(SexpfmtError::unexpected_eof(*open_loc, stack.len()));
+	}
+	Ok(top)
+}
+
+/// Prints nodes exactly as they were written.
+pub fn print_cst(nodes: &[CstNode]) -> String {
+	nodes.iter().map(|node| node.to_string()).collect()
+}
+
+/// Drops whitespace and comments, giving the `SExp`s that `parse_form` would produce.
+pub fn cst_to_sexps(nodes: &[CstNode]) -> Vec<SExp> {
+	nodes.iter().filter_map(CstNode::to_sexp).collect()
+}
+
+/// Formats a document as `format_str` formats its text, keeping every comment.
+pub fn format_cst(nodes: &[CstNode], config: &PrinterConfig) -> Result<String> {
+	format_str(&print_cst(nodes), config)
+}
+
+// Gives the `SExp` of each element of `nodes`, which start at byte `offset` of the text that `cursor` is on, along with
+// its location and the blank lines and comments around it, then the comments on lines of their own after the last one.
+// `offset` is left past the nodes.
+pub(crate) fn locate_cst(
+	nodes: &[CstNode],
+	offset: &mut usize,
+	cursor: &mut LocCursor,
+) -> (Vec<(SExp, LocTree)>, Vec<Comment>) {
+	let mut located: Vec<(SExp, LocTree)> = Vec::new();
+	let mut comments = Vec::new();
+	// the line breaks since the last element or comment, if any
+	let mut line_breaks = 0;
+	for node in nodes.iter() {
+		let loc = cursor.at(*offset);
+		let (sexp, children, comments_at_end) = match node {
+			CstNode::Whitespace(s) => {
+				line_breaks += line_starts(s).count();
+				*offset += s.len();
+				continue;
+			}
+			CstNode::Comment(s) => {
+				*offset += s.len();
+				match located.last_mut() {
+					Some((_, locs)) if line_breaks == 0 && comments.is_empty() => {
+						locs.comment_after = Some(s.clone())
+					}
+					_ => comments.push(Comment {
+						text: s.clone(),
+						blank_lines_before: line_breaks.saturating_sub(1),
+					}),
+				}
+				line_breaks = 0;
+				continue;
+			}
+			CstNode::Atom(s) => {
+				*offset += s.len();
+				(SExp::atom(s.as_str()), Vec::new(), Vec::new())
+			}
+			CstNode::List(nodes, style) => {
+				*offset += 1;
+				let (elements, comments_at_end) = locate_cst(nodes, offset, cursor);
+				*offset += 1;
+				let (es, children): (Vec<SExp>, _) = elements.into_iter().unzip();
+				let sexp = if es.is_empty() {
+					SExp::Null(*style)
+				} else {
+					SExp::List(es, *style)
+				};
+				(sexp, children, comments_at_end)
+			}
+		};
+		let locs = LocTree {
+			loc,
+			end: cursor.at(*offset),
+			blank_lines_before: line_breaks.saturating_sub(1),
+			comments_before: std::mem::take(&mut comments),
+			comment_after: None,
+			comments_at_end,
+			children,
+		};
+		located.push((sexp, locs));
+		line_breaks = 0;
+	}
+	(located, comments)
+}
+
+enum CstToken {
+	Open(SExpBookendStyle),
+	Close(SExpBookendStyle),
+	Node(CstNode),
+}
+
+fn cst_token(input: LocSpan) -> IResult<CstToken> {
+	alt((
+		map(recognize(multispace1), |s: LocSpan| {
+			CstToken::Node(CstNode::Whitespace(s.fragment().to_string()))
+		}),
+		map(recognize(line_comment), |s: LocSpan| {
+			CstToken::Node(CstNode::Comment(s.fragment().to_string()))
+		}),
+		map_opt(one_of("([{"), |c| {
+			SExpBookendStyle::from_char(c).map(CstToken::Open)
+		}),
+		map_opt(one_of(")]}"), |c| {
+			SExpBookendStyle::from_char(c).map(CstToken::Close)
+		}),
+		map(recognize(atom), |s: LocSpan| {
+			CstToken::Node(CstNode::Atom(s.fragment().to_string()))
+		}),
+	))(input)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use proptest::prelude::*;
+
+	#[test]
+	fn test_parse_cst() {
+		let text = "; header\n(a \"b c\" ; note\n  [x]) {}";
+		let nodes = parse_cst(text).unwrap();
+		assert_eq!(nodes.len(), 5);
+		assert_eq!(nodes[0], CstNode::Comment("; header".to_string()));
+		assert_eq!(
+			nodes[2],
+			CstNode::List(
+				vec![
+					CstNode::Atom("a".to_string()),
+					CstNode::Whitespace(" ".to_string()),
+					CstNode::Atom("\"b c\"".to_string()),
+					CstNode::Whitespace(" ".to_string()),
+					CstNode::Comment("; note".to_string()),
+					CstNode::Whitespace("\n  ".to_string()),
+					CstNode::List(
+						vec![CstNode::Atom("x".to_string())],
+						SExpBookendStyle::SquareBrackets
+					),
+				],
+				SExpBookendStyle::Parentheses
+			)
+		);
+		assert_eq!(print_cst(&nodes), text);
+		assert_eq!(
+			cst_to_sexps(&nodes),
+			parse_form(text.to_string(), Loc::new(0, 1, 1)).unwrap()
+		);
+	}
+
+	#[test]
+	fn test_parse_cst_errors() {
+		assert!(matches!(
+			parse_cst("(a\n  [b)"),
+			Err(SexpfmtError::MismatchedBookends { position, .. }) if position == Loc::new(5, 2, 3)
+		));
+		assert!(matches!(
+			parse_cst("(a (b)"),
+			Err(SexpfmtError::UnexpectedEof {
+				unclosed_count: 1,
+				..
+			})
+		));
+		assert!(matches!(
+			parse_cst("a)"),
+			Err(SexpfmtError::Parse { position, .. }) if position == Loc::new(1, 1, 2)
+		));
+		let deep = format!("{}{}", "[".repeat(100_000), "]".repeat(100_000));
+		assert!(matches!(
+			parse_cst(&deep),
+			Err(SexpfmtError::InvalidInput { .. })
+		));
+	}
+
+	#[test]
+	fn test_format_cst() {
+		let nodes = parse_cst("; header\n\n(a\n   b)\n(c ; note\n   d)").unwrap();
+		assert_eq!(
+			format_cst(&nodes, &PrinterConfig::default()).unwrap(),
+			"; header\n\n(a b)\n(c ; note\n  d)\n"
+		);
+	}
+
+	fn document() -> impl Strategy<Value = String> {
+		let leaf = prop_oneof![
+			"[a-z0-9#.]{1,4}",
+			"\"([a-z ]|\\\\\"){0,4}\"",
+			"[ \t\n]{1,3}",
+			";[a-z ();]{0,6}\n",
+		];
+		let node = leaf.prop_recursive(4, 32, 6, |inner| {
+			(prop::collection::vec(inner, 0..6), 0..3usize).prop_map(|(nodes, style)| {
+				let (lp, rp) = ["()", "[]", "{}"][style].split_at(1);
+				format!("{}{}{}", lp, nodes.join(" "), rp)
+			})
+		});
+		prop::collection::vec(node, 0..6).prop_map(|nodes| nodes.join(" "))
+	}
+
+	proptest! {
+		#[test]
+		fn test_cst_round_trip(text in document()) {
+			let nodes = parse_cst(&text).unwrap();
+			prop_assert_eq!(print_cst(&nodes), text.as_str());
+			let sexps = parse_form(text.clone(), Loc::new(0, 1, 1)).unwrap();
+			prop_assert_eq!(cst_to_sexps(&nodes), sexps);
+		}
+
+		#[test]
+		fn test_format_keeps_comments(text in document()) {
+			let comments = |nodes: &[CstNode]| {
+				let mut res = Vec::new();
+				let mut stack: Vec<&CstNode> = nodes.iter().rev().collect();
+				while let Some(node) = stack.pop() {
+					match node {
+						CstNode::Comment(s) => res.push(s.trim_end().to_string()),
+						CstNode::List(nodes, _) => stack.extend(nodes.iter().rev()),
+						CstNode::Whitespace(_) | CstNode::Atom(_) => {}
+					}
+				}
+				res
+			};
+			let nodes = parse_cst(&text).unwrap();
+			let formatted = format_cst(&nodes, &PrinterConfig::default()).unwrap();
+			let formatted_nodes = parse_cst(&formatted).unwrap();
+			prop_assert_eq!(comments(&formatted_nodes), comments(&nodes));
+			prop_assert_eq!(cst_to_sexps(&formatted_nodes), cst_to_sexps(&nodes));
+			prop_assert_eq!(format_str(&formatted, &PrinterConfig::default()).unwrap(), formatted);
+		}
+
+		#[test]
+		fn test_cst_round_trip_arbitrary(text in "[a-z ()\\[\\]{};\"\\\\\n]{0,32}") {
+			if let Ok(nodes) = parse_cst(&text) {
+				prop_assert_eq!(print_cst(&nodes), text.as_str());
+			}
+		}
+	}
+}
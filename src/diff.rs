@@ -0,0 +1,597 @@
+// Structural diff between two sequences of S-expressions.
+// Both sides are compared as trees, so whitespace, comments and line breaks never show up as differences.
+// Within each list, elements are aligned using a longest common subsequence of equal subtrees.
+// Unaligned elements between two aligned ones are paired up when they look like edits of one another (same kind of
+// node, and for lists, the same bookends and head), in which case we recurse into them; otherwise they are reported
+// as deleted or inserted. Finally, a deleted subtree that is inserted unchanged elsewhere is reported as moved.
+
+use super::*;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::ops::Range;
+
+/// The position of a node in a document: the index of its top-level form, followed by the index of each list element
+/// on the way down to it.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct TreePath(pub Vec<usize>);
+
+impl TreePath {
+	fn child(&self, index: usize) -> Self {
+		let mut indices = self.0.clone();
+		indices.push(index);
+		Self(indices)
+	}
+}
+
+impl fmt::Display for TreePath {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		for index in self.0.iter() {
+			write!(f, "/{}", index)?;
+		}
+		Ok(())
+	}
+}
+
+/// One side of a change: where the subtree is, and what it is.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DiffNode {
+	pub path: TreePath,
+	pub loc: Option<Loc>,
+	pub sexp: SExp,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Change {
+	Inserted { new: DiffNode },
+	Deleted { old: DiffNode },
+	Moved { old: DiffNode, new: DiffNode },
+	Changed { old: DiffNode, new: DiffNode },
+}
+
+/// Compares two sequences of forms structurally. Nodes in the result carry no location.
+pub fn diff_sexps(old: &[SExp], new: &[SExp]) -> Vec<Change> {
+	let mut changes = Vec::new();
+	diff_seq(
+		old,
+		new,
+		&TreePath::default(),
+		&TreePath::default(),
+		&mut changes,
+	);
+	detect_moves(changes)
+}
+
+/// Reads every form from both inputs, then compares them structurally. Nodes in the result carry the location at
+/// which they start in their input.
+pub fn diff_documents<R1: io::Read, R2: io::Read>(old: R1, new: R2) -> Result<Vec<Change>> {
+	with_document_diff(old, new, DEFAULT_MAX_DEPTH, |changes| changes)
+}
+
+/// Compares both inputs as `diff_documents` does, accepting lists nested up to `max_depth` deep, then hands the changes
+/// to `f`, on a thread with the stack for comparing, walking and dropping them even if the inputs are deeply nested.
+pub fn with_document_diff<R1: io::Read, R2: io::Read, T: Send>(
+	old: R1,
+	new: R2,
+	max_depth: usize,
+	f: impl FnOnce(Vec<Change>) -> T + Send,
+) -> Result<T> {
+	let old = read_form_texts(old, max_depth)?;
+	let new = read_form_texts(new, max_depth)?;
+	let depth = old
+		.iter()
+		.chain(new.iter())
+		.map(|(_, _, depth)| *depth)
+		.max();
+	with_stack_for_depth(depth.unwrap_or_default(), || {
+		let (old_forms, old_locs) = parse_forms_with_locs(old, max_depth)?;
+		let (new_forms, new_locs) = parse_forms_with_locs(new, max_depth)?;
+		Ok(f(locate_changes(
+			diff_sexps(&old_forms, &new_forms),
+			&old_locs,
+			&new_locs,
+		)))
+	})?
+}
+
+// Reads the text of every form of `input`, with where it starts and how deeply it is nested
+fn read_form_texts<R: io::Read>(input: R, max_depth: usize) -> Result<Vec<(String, Loc, usize)>> {
+	let mut reader = FormReader::new(input)?;
+	reader.set_max_depth(max_depth);
+	let mut texts = Vec::new();
+	while let Some((s, position)) = reader.get()? {
+		let depth = check_depth(&s, position, max_depth)?;
+		texts.push((s, position, depth));
+	}
+	Ok(texts)
+}
+
+fn parse_forms_with_locs(
+	texts: Vec<(String, Loc, usize)>,
+	max_depth: usize,
+) -> Result<(Vec<SExp>, Vec<LocTree>)> {
+	let mut forms = Vec::new();
+	let mut locs = Vec::new();
+	for (s, position, _) in texts.into_iter() {
+		for (sexp, loc_tree) in parse_form_with_locs_and_max_depth(s, position, max_depth)?.into_iter()
+		{
+			forms.push(sexp);
+			locs.push(loc_tree);
+		}
+	}
+	Ok((forms, locs))
+}
+
+// Sets the location of each node of `changes` from the locations of both documents
+fn locate_changes(
+	mut changes: Vec<Change>,
+	old_locs: &[LocTree],
+	new_locs: &[LocTree],
+) -> Vec<Change> {
+	for change in changes.iter_mut() {
+		match change {
+			Change::Inserted { new } => {
+				new.loc = find_loc(new_locs, &new.path);
+			}
+			Change::Deleted { old } => {
+				old.loc = find_loc(old_locs, &old.path);
+			}
+			Change::Moved { old, new } | Change::Changed { old, new } => {
+				old.loc = find_loc(old_locs, &old.path);
+				new.loc = find_loc(new_locs, &new.path);
+			}
+		}
+	}
+	changes
+}
+
+fn find_loc(locs: &[LocTree], path: &TreePath) -> Option<Loc> {
+	let (first, rest) = path.0.split_first()?;
+	let mut loc_tree = locs.get(*first)?;
+	for index in rest.iter() {
+		loc_tree = loc_tree.children.get(*index)?;
+	}
+	Some(loc_tree.loc)
+}
+
+fn diff_seq(
+	old: &[SExp],
+	new: &[SExp],
+	old_path: &TreePath,
+	new_path: &TreePath,
+	changes: &mut Vec<Change>,
+) {
+	let (mut i, mut j) = (0, 0);
+	let anchors = longest_common_subsequence(old, new);
+	for (anchor_i, anchor_j) in anchors
+		.into_iter()
+		.chain(std::iter::once((old.len(), new.len())))
+	{
+		diff_gap(
+			old,
+			i..anchor_i,
+			new,
+			j..anchor_j,
+			old_path,
+			new_path,
+			changes,
+		);
+		i = anchor_i + 1;
+		j = anchor_j + 1;
+	}
+}
+
+// Handles a run of unaligned elements on both sides, between two aligned ones.
+fn diff_gap(
+	old: &[SExp],
+	old_range: Range<usize>,
+	new: &[SExp],
+	new_range: Range<usize>,
+	old_path: &TreePath,
+	new_path: &TreePath,
+	changes: &mut Vec<Change>,
+) {
+	let mut j = new_range.start;
+	for i in old_range {
+		let paired_j = (j..new_range.end).find(|&j| is_edit_of(&old[i], &new[j]));
+		match paired_j {
+			Some(paired_j) => {
+				push_inserted(new, j..paired_j, new_path, changes);
+				diff_pair(
+					&old[i],
+					&new[paired_j],
+					&old_path.child(i),
+					&new_path.child(paired_j),
+					changes,
+				);
+				j = paired_j + 1;
+			}
+			None => {
+				changes.push(Change::Deleted {
+					old: diff_node(old_path, i, &old[i]),
+				});
+			}
+		}
+	}
+	push_inserted(new, j..new_range.end, new_path, changes);
+}
+
+fn push_inserted(
+	new: &[SExp],
+	range: Range<usize>,
+	new_path: &TreePath,
+	changes: &mut Vec<Change>,
+) {
+	for (j, e) in range.clone().zip(new[range].iter()) {
+		changes.push(Change::Inserted {
+			new: diff_node(new_path, j, e),
+		});
+	}
+}
+
+fn diff_pair(
+	old: &SExp,
+	new: &SExp,
+	old_path: &TreePath,
+	new_path: &TreePath,
+	changes: &mut Vec<Change>,
+) {
+	match (old, new) {
+		(SExp::List(old_es, _), SExp::List(new_es, _)) => {
+			diff_seq(old_es, new_es, old_path, new_path, changes);
+		}
+		_ => changes.push(Change::Changed {
+			old: DiffNode {
+				path: old_path.clone(),
+				loc: None,
+				sexp: old.clone(),
+			},
+			new: DiffNode {
+				path: new_path.clone(),
+				loc: None,
+				sexp: new.clone(),
+			},
+		}),
+	}
+}
+
+fn diff_node(parent_path: &TreePath, index: usize, sexp: &SExp) -> DiffNode {
+	DiffNode {
+		path: parent_path.child(index),
+		loc: None,
+		sexp: sexp.clone(),
+	}
+}
+
+// Whether `new` looks like an edited version of `old` rather than an unrelated subtree.
+fn is_edit_of(old: &SExp, new: &SExp) -> bool {
+	match (old, new) {
+		(SExp::Atom(_), SExp::Atom(_)) => true,
+		(SExp::List(old_es, old_style), SExp::List(new_es, new_style)) => {
+			old_style == new_style && old_es.first() == new_es.first()
+		}
+		_ => false,
+	}
+}
+
+// Returns the index pairs of equal elements that form a longest common subsequence, in increasing order.
+fn longest_common_subsequence(old: &[SExp], new: &[SExp]) -> Vec<(usize, usize)> {
+	// Common prefixes and suffixes are by far the common case, so strip them before building the table.
+	let prefix_len = old
+		.iter()
+		.zip(new.iter())
+		.take_while(|(a, b)| a == b)
+		.count();
+	let suffix_len = old[prefix_len..]
+		.iter()
+		.rev()
+		.zip(new[prefix_len..].iter().rev())
+		.take_while(|(a, b)| a == b)
+		.count();
+	let old_mid = &old[prefix_len..old.len() - suffix_len];
+	let new_mid = &new[prefix_len..new.len() - suffix_len];
+
+	// Each distinct element is numbered, so that the quadratic search below compares numbers rather than trees.
+	let mut classes = EqualityClasses::default();
+	let old_ids: Vec<usize> = old_mid.iter().map(|e| classes.id(e)).collect();
+	let new_ids: Vec<usize> = new_mid.iter().map(|e| classes.id(e)).collect();
+
+	let mut pairs: Vec<(usize, usize)> = (0..prefix_len).map(|k| (k, k)).collect();
+	hirschberg(&old_ids, &new_ids, (prefix_len, prefix_len), &mut pairs);
+	for k in 0..suffix_len {
+		pairs.push((old.len() - suffix_len + k, new.len() - suffix_len + k));
+	}
+	pairs
+}
+
+// Pushes the index pairs of a longest common subsequence of `old` and `new`, offset by `offsets`, onto `pairs` in
+// increasing order. This is Hirschberg's algorithm: it takes time proportional to the product of the lengths, but
+// space only proportional to their sum, by splitting `old` in half and finding where its halves are best matched in
+// `new`.
+fn hirschberg(
+	old: &[usize],
+	new: &[usize],
+	offsets: (usize, usize),
+	pairs: &mut Vec<(usize, usize)>,
+) {
+	if old.is_empty() || new.is_empty() {
+		return;
+	}
+	if let [e] = old {
+		if let Some(j) = new.iter().position(|n| n == e) {
+			pairs.push((offsets.0, offsets.1 + j));
+		}
+		return;
+	}
+	let mid = old.len() / 2;
+	let before = lcs_lengths(old[..mid].iter(), new.iter());
+	let after = lcs_lengths(old[mid..].iter().rev(), new.iter().rev());
+	// where to split `new`: the split with the longest subsequence, the first of several
+	let split = (0..=new.len())
+		.max_by_key(|&j| (before[j] + after[new.len() - j], std::cmp::Reverse(j)))
+		.unwrap();
+	hirschberg(&old[..mid], &new[..split], offsets, pairs);
+	hirschberg(
+		&old[mid..],
+		&new[split..],
+		(offsets.0 + mid, offsets.1 + split),
+		pairs,
+	);
+}
+
+// The length of the longest common subsequence of `old` and each prefix of `new`, indexed by the length of the prefix
+fn lcs_lengths<'a>(
+	old: impl Iterator<Item = &'a usize>,
+	new: impl Iterator<Item = &'a usize> + Clone,
+) -> Vec<u32> {
+	let mut row = vec![0u32; new.clone().count() + 1];
+	let mut next_row = row.clone();
+	for o in old {
+		for (j, n) in new.clone().enumerate() {
+			next_row[j + 1] = if o == n {
+				row[j] + 1
+			} else {
+				row[j + 1].max(next_row[j])
+			};
+		}
+		std::mem::swap(&mut row, &mut next_row);
+	}
+	row
+}
+
+// Numbers expressions so that equal ones, and only those, get the same number
+#[derive(Default)]
+struct EqualityClasses<'a> {
+	// the expressions numbered so far, by hash, with their numbers
+	by_hash: HashMap<u64, Vec<(&'a SExp, usize)>>,
+	len: usize,
+}
+
+impl<'a> EqualityClasses<'a> {
+	fn id(&mut self, sexp: &'a SExp) -> usize {
+		let bucket = self.by_hash.entry(hash_sexp(sexp)).or_default();
+		if let Some(&(_, id)) = bucket.iter().find(|(e, _)| *e == sexp) {
+			return id;
+		}
+		bucket.push((sexp, self.len));
+		self.len += 1;
+		self.len - 1
+	}
+}
+
+// A hash of an expression that agrees with its equality, under which empty lists are null ones
+fn hash_sexp(sexp: &SExp) -> u64 {
+	let mut hasher = std::hash::DefaultHasher::new();
+	let mut stack = vec![sexp];
+	while let Some(sexp) = stack.pop() {
+		match sexp.as_atom() {
+			Some(s) => {
+				0u8.hash(&mut hasher);
+				s.hash(&mut hasher);
+			}
+			None => {
+				1u8.hash(&mut hasher);
+				sexp.bookend_style().unwrap().bookends().0.hash(&mut hasher);
+				sexp.len().hash(&mut hasher);
+				stack.extend(sexp.iter().rev());
+			}
+		}
+	}
+	hasher.finish()
+}
+
+// Turns each deletion of a subtree that is inserted unchanged elsewhere into a move.
+fn detect_moves(changes: Vec<Change>) -> Vec<Change> {
+	// the inserted subtrees, by hash, with the indices of their insertions in order
+	let mut inserted: HashMap<u64, Vec<usize>> = HashMap::new();
+	for (k, change) in changes.iter().enumerate() {
+		if let Change::Inserted { new } = change {
+			inserted.entry(hash_sexp(&new.sexp)).or_default().push(k);
+		}
+	}
+	let mut slots: Vec<Option<Change>> = changes.into_iter().map(Some).collect();
+	for i in 0..slots.len() {
+		let old = match &slots[i] {
+			Some(Change::Deleted { old }) => old.clone(),
+			_ => continue,
+		};
+		let Some(candidates) = inserted.get_mut(&hash_sexp(&old.sexp)) else {
+			continue;
+		};
+		let partner = candidates
+			.iter()
+			.position(|&k| matches!(&slots[k], Some(Change::Inserted { new }) if new.sexp == old.sexp));
+		if let Some(p) = partner
+			&& let Some(Change::Inserted { new }) = slots[candidates.remove(p)].take()
+		{
+			slots[i] = Some(Change::Moved { old, new });
+		}
+	}
+	slots.into_iter().flatten().collect()
+}
+
+impl fmt::Display for Change {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Change::Inserted { new } => {
+				write!(f, "inserted {}: ", DisplayNodePos(new))?;
+				write!(f, "{}", new.sexp)
+			}
+			Change::Deleted { old } => {
+				write!(f, "deleted {}: ", DisplayNodePos(old))?;
+				write!(f, "{}", old.sexp)
+			}
+			Change::Moved { old, new } => {
+				write!(
+					f,
+					"moved {} -> {}: ",
+					DisplayNodePos(old),
+					DisplayNodePos(new)
+				)?;
+				write!(f, "{}", new.sexp)
+			}
+			Change::Changed { old, new } => {
+				write!(
+					f,
+					"changed {} -> {}: ",
+					DisplayNodePos(old),
+					DisplayNodePos(new)
+				)?;
+				write!(f, "{}", old.sexp)?;
+				write!(f, " -> ")?;
+				write!(f, "{}", new.sexp)
+			}
+		}
+	}
+}
+
+struct DisplayNodePos<'a>(&'a DiffNode);
+
+impl fmt::Display for DisplayNodePos<'_> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}", self.0.path)?;
+		if let Some(loc) = self.0.loc {
+			write!(f, " ({}:{})", loc.line(), loc.column())?;
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use proptest::prelude::*;
+	use stringreader::StringReader;
+
+	fn parse(s: &str) -> Vec<SExp> {
+		parse_form(s.to_string(), Loc::new(0, 1, 1)).unwrap()
+	}
+
+	fn diff_strs(old: &str, new: &str) -> Vec<String> {
+		diff_sexps(&parse(old), &parse(new))
+			.iter()
+			.map(|change| change.to_string())
+			.collect()
+	}
+
+	#[test]
+	fn test_diff_identical() {
+		assert!(diff_strs("(a (b c) d)", "(a\n  (b   c)\n  d) ; comment").is_empty());
+	}
+
+	#[test]
+	fn test_diff_changed() {
+		assert_eq!(
+			diff_strs(
+				"(object (name \"latte\") (quantity 1))",
+				"(object (name \"latte\") (quantity 2))"
+			),
+			vec!["changed /0/2/1 -> /0/2/1: 1 -> 2"]
+		);
+	}
+
+	#[test]
+	fn test_diff_inserted_and_deleted() {
+		assert_eq!(
+			diff_strs("(a b c)", "(a c d)"),
+			vec!["deleted /0/1: b", "inserted /0/2: d"]
+		);
+		assert_eq!(
+			diff_strs("(a (size 1))", "(a (name x) (size 1))"),
+			vec!["inserted /0/1: (name x)"]
+		);
+	}
+
+	#[test]
+	fn test_diff_moved() {
+		assert_eq!(
+			diff_strs("(a (b 1) c) (d)", "(a c) (d (b 1))"),
+			vec!["moved /0/1 -> /1/1: (b 1)"]
+		);
+	}
+
+	#[test]
+	fn test_diff_documents_locations() {
+		let old = StringReader::new("(object\n  (name \"croissant\")\n  (quantity 2))\n");
+		let new = StringReader::new("(object (name \"croissant\") (quantity 3))\n");
+		let changes = diff_documents(old, new).unwrap();
+		assert_eq!(changes.len(), 1);
+		assert_eq!(
+			changes[0].to_string(),
+			"changed /0/2/1 (3:13) -> /0/2/1 (1:38): 2 -> 3"
+		);
+		// lines may end with a lone carriage return
+		let old = StringReader::new("(object\r  (name \"croissant\")\r  (quantity 2))\r");
+		let new = StringReader::new("(object\r\n  (name \"croissant\")\r\n  (quantity 3))\r\n");
+		let changes = diff_documents(old, new).unwrap();
+		assert_eq!(
+			changes[0].to_string(),
+			"changed /0/2/1 (3:13) -> /0/2/1 (3:13): 2 -> 3"
+		);
+	}
+
+	#[test]
+	fn test_diff_documents_max_depth() {
+		let deep = |atom| format!("{}{}{}", "(".repeat(10_000), atom, ")".repeat(10_000));
+		let (old, new) = (deep("a"), deep("b"));
+		let changes = with_document_diff(old.as_bytes(), new.as_bytes(), 10_000, |changes| {
+			changes
+				.iter()
+				.map(|change| change.to_string())
+				.collect::<Vec<_>>()
+		});
+		assert!(!changes.unwrap().is_empty());
+		assert!(matches!(
+			diff_documents(old.as_bytes(), new.as_bytes()),
+			Err(SexpfmtError::InvalidInput { .. })
+		));
+	}
+
+	proptest! {
+		#[test]
+		fn test_longest_common_subsequence_random(
+			old in proptest::collection::vec("[a-c]", 0..12),
+			new in proptest::collection::vec("[a-c]", 0..12),
+		) {
+			let old: Vec<SExp> = old.into_iter().map(SExp::atom).collect();
+			let new: Vec<SExp> = new.into_iter().map(SExp::atom).collect();
+			// the length of the longest common subsequence, from the full table
+			let mut table = vec![vec![0; new.len() + 1]; old.len() + 1];
+			for i in 0..old.len() {
+				for j in 0..new.len() {
+					table[i + 1][j + 1] = if old[i] == new[j] {
+						table[i][j] + 1
+					} else {
+						table[i][j + 1].max(table[i + 1][j])
+					};
+				}
+			}
+			let pairs = longest_common_subsequence(&old, &new);
+			prop_assert_eq!(pairs.len(), table[old.len()][new.len()]);
+			prop_assert!(pairs.iter().all(|&(i, j)| old[i] == new[j]));
+			prop_assert!(pairs.windows(2).all(|w| w[0].0 < w[1].0 && w[0].1 < w[1].1));
+		}
+	}
+}
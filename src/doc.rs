@@ -0,0 +1,880 @@
+// A document algebra for layout, after Wadler's "A prettier printer".
+// A document is text joined by line breaks, gathered into groups. Each group is either flat, with all of its line
+// breaks replaced by spaces (or by nothing, for soft line breaks), or broken, with all of its own line breaks taken,
+// and each taken line break is followed by the indentation set by the enclosing `Nest`s and `Align`s.
+// Rendering goes left to right, laying each group flat when that fits: the group itself, and whatever follows it up to
+// the next line break, such as closing brackets, must fit in what is left of the current line. A group that does not
+// fit is laid out in whichever mode costs less, with its own groups laid out the same way, along with the rest of its
+// last line. The cost of a layout is first the width of its text that starts past the width of the page, then its
+// number of lines, so a group whose contents run past the width either way, such as a long atom, stays flat. The
+// layout chosen for each group is kept by position, so that each is only laid out a few times.
+// A `Fill` is laid out like a paragraph instead, breaking the line before an item only if the item does not fit.
+
+use std::collections::{HashMap, HashSet};
+
+/// Names a group, so that `Doc::IfBroken` can refer to it from outside.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct GroupId(pub usize);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Doc {
+	Nil,
+	/// Text without line breaks.
+	Text(String),
+	/// A line break, or a space when flat.
+	Line,
+	/// A line break, or nothing when flat.
+	SoftLine,
+	/// A line break without indentation, which leaves an empty line when followed by another line break. It breaks
+	/// every group around it.
+	BlankLine,
+	/// Nothing, but it breaks every group around it, as a line comment must be followed by a line break.
+	BreakParent,
+	/// Increases the indentation after the line breaks within by the given width.
+	Nest(i32, Box<Doc>),
+	/// Sets the indentation after the line breaks within to the column at which it starts.
+	Align(Box<Doc>),
+	Group(Option<GroupId>, Box<Doc>),
+	/// The first document if the given group, or else the innermost enclosing group, is broken, and the second
+	/// otherwise. The given group must come before this, or enclose it.
+	IfBroken(Option<GroupId>, Box<Doc>, Box<Doc>),
+	Concat(Vec<Doc>),
+	/// Items separated by spaces, with a line break instead of each space after which the next item does not fit.
+	Fill(Vec<Doc>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Mode {
+	Flat,
+	Broken,
+}
+
+// What is left to lay out
+#[derive(Clone, Copy)]
+enum Cmd<'a> {
+	Doc(&'a Doc),
+	// the items of a fill after the first, each to be preceded by its separator
+	FillRest(&'a [Doc]),
+}
+
+impl Doc {
+	pub fn text(s: impl Into<String>) -> Self {
+		Doc::Text(s.into())
+	}
+
+	pub fn nest(indent: i32, doc: Doc) -> Self {
+		Doc::Nest(indent, Box::new(doc))
+	}
+
+	pub fn align(doc: Doc) -> Self {
+		Doc::Align(Box::new(doc))
+	}
+
+	pub fn group(doc: Doc) -> Self {
+		Doc::Group(None, Box::new(doc))
+	}
+
+	pub fn group_with_id(id: GroupId, doc: Doc) -> Self {
+		Doc::Group(Some(id), Box::new(doc))
+	}
+
+	pub fn if_broken(id: Option<GroupId>, broken: Doc, flat: Doc) -> Self {
+		Doc::IfBroken(id, Box::new(broken), Box::new(flat))
+	}
+
+	/// Lays out the document to fit in `width` columns, if possible. Line breaks outside of any group are taken.
+	pub fn render(&self, width: i32) -> String {
+		self.render_impl(width, None)
+	}
+
+	/// Like `render`, but indenting with tabs displayed `tab_width` columns wide: a `Nest` by a multiple of
+	/// `tab_width` adds a tab for each `tab_width` columns. Alignment, and any nesting within it, is made of spaces
+	/// after the tabs of the line aligned to.
+	pub fn render_with_tabs(&self, width: i32, tab_width: i32) -> String {
+		self.render_impl(width, Some(tab_width))
+	}
+
+	fn render_impl(&self, width: i32, tab_width: Option<i32>) -> String {
+		let mut layouter = Layouter {
+			width: i64::from(width),
+			tab_width: tab_width.map(i64::from),
+			group_modes: HashMap::new(),
+			flat_widths: HashMap::new(),
+			layouts: HashMap::new(),
+		};
+		let mut out = String::new();
+		layouter.lay_out(
+			vec![(Indent::default(), Mode::Broken, Cmd::Doc(self))],
+			&mut Pos::default(),
+			None,
+			Some(&mut out),
+		);
+		out
+	}
+}
+
+/// Indentation: tabs, one per level of nesting when indenting with tabs, followed by spaces for alignment.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub(crate) struct Indent {
+	tabs: i64,
+	spaces: i64,
+}
+
+impl Indent {
+	/// The indentation `by` columns deeper: tabs of `tab_width` columns if given, if `by` is a multiple of it and there
+	/// are no spaces yet, and spaces otherwise.
+	pub(crate) fn nested(self, by: i64, tab_width: Option<i64>) -> Self {
+		match tab_width {
+			Some(tab_width) if tab_width > 0 && self.spaces == 0 && by >= 0 && by % tab_width == 0 => {
+				Self {
+					tabs: self.tabs + by / tab_width,
+					spaces: 0,
+				}
+			}
+			_ => Self {
+				tabs: self.tabs,
+				spaces: self.spaces + by,
+			},
+		}
+	}
+
+	/// The indentation reaching `column` on a line indented by `self`, with the same tabs and spaces for the rest.
+	pub(crate) fn aligned(self, column: i64, tab_width: Option<i64>) -> Self {
+		Self {
+			tabs: self.tabs,
+			spaces: column - self.tabs * tab_width.unwrap_or(0),
+		}
+	}
+
+	/// The columns spanned, with tabs `tab_width` columns wide.
+	pub(crate) fn width(self, tab_width: Option<i64>) -> i64 {
+		self.tabs * tab_width.unwrap_or(0) + self.spaces
+	}
+
+	pub(crate) fn push_to(self, out: &mut String) {
+		for _ in 0..self.tabs {
+			out.push('\t');
+		}
+		for _ in 0..self.spaces {
+			out.push(' ');
+		}
+	}
+}
+
+// Starts a line indented by `indent`
+pub(crate) fn newline(out: &mut String, indent: Indent) {
+	out.push('\n');
+	indent.push_to(out);
+}
+
+// The cost of a layout: first the width of the text that starts past the width of the page, then the number of lines.
+// Text that starts before the width is not counted even if it runs past it, as no line break could have helped it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct Cost {
+	pub(crate) overflow: i64,
+	pub(crate) lines: i64,
+}
+
+impl std::ops::Add for Cost {
+	type Output = Cost;
+
+	fn add(self, other: Cost) -> Cost {
+		Cost {
+			overflow: self.overflow + other.overflow,
+			lines: self.lines + other.lines,
+		}
+	}
+}
+
+// Where the next text goes: its column, and the indentation of its line
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+struct Pos {
+	column: i64,
+	line_indent: Indent,
+}
+
+// How a group is laid out from some position, at what cost, and where it ends
+#[derive(Clone, Copy)]
+struct GroupLayout {
+	mode: Mode,
+	cost: Cost,
+	end: Pos,
+}
+
+type Frame<'a> = (Indent, Mode, Cmd<'a>);
+
+// What is left to lay out after a group: the frames of the layout it is part of, next last, then what is left after
+// that layout, if it is itself laid out to choose the mode of a group.
+struct Rest<'r, 'a> {
+	frames: &'r [Frame<'a>],
+	outer: Option<&'r Rest<'r, 'a>>,
+}
+
+impl<'a> Rest<'_, 'a> {
+	fn iter(&self) -> impl Iterator<Item = (Mode, Cmd<'a>)> + '_ {
+		let mut frames = [].iter();
+		let mut outer = Some(self);
+		std::iter::from_fn(move || {
+			loop {
+				if let Some(&(_, mode, cmd)) = frames.next_back() {
+					return Some((mode, cmd));
+				}
+				let rest = outer?;
+				frames = rest.frames.iter();
+				outer = rest.outer;
+			}
+		})
+	}
+}
+
+static SPACE: Doc = Doc::Line;
+
+struct Layouter {
+	width: i64,
+	tab_width: Option<i64>,
+	// the mode of each named group laid out so far
+	group_modes: HashMap<GroupId, Mode>,
+	// the width of the contents of each group measured, if they can be laid flat
+	flat_widths: HashMap<*const Doc, Option<i64>>,
+	// the layout chosen for each group, by where it starts, its indentation, and the width of the rest of its line
+	layouts: HashMap<(*const Doc, Pos, Indent, i64), GroupLayout>,
+}
+
+impl Layouter {
+	// Lays out `frames` from `pos`, writing to `out` if given and returning the cost. Each group in broken mode is laid
+	// flat if that fits, and otherwise in whichever mode costs less, along with the rest of its last line. Without
+	// `out`, the groups whose mode is chosen are skipped over, as their layouts are known.
+	fn lay_out<'a>(
+		&mut self,
+		mut stack: Vec<Frame<'a>>,
+		pos: &mut Pos,
+		outer: Option<&Rest<'_, 'a>>,
+		mut out: Option<&mut String>,
+	) -> Cost {
+		let mut cost = Cost::default();
+		while let Some((indent, mode, cmd)) = stack.pop() {
+			let doc = match cmd {
+				Cmd::Doc(doc) => doc,
+				Cmd::FillRest([]) => continue,
+				Cmd::FillRest([item, items @ ..]) => {
+					stack.push((indent, mode, Cmd::FillRest(items)));
+					// the rest of the line only matters after the last item
+					let rest = Rest {
+						frames: if items.is_empty() { &stack } else { &[] },
+						outer: if items.is_empty() { outer } else { None },
+					};
+					let item_mode =
+						if mode == Mode::Flat || self.fits(self.width - pos.column - 1, item, &rest) {
+							cost = cost + self.text(1, " ", pos, &mut out);
+							Mode::Flat
+						} else {
+							cost = cost + self.newline(indent, pos, &mut out);
+							if self.fits(self.width - pos.column, item, &rest) {
+								Mode::Flat
+							} else {
+								Mode::Broken
+							}
+						};
+					stack.push((indent, item_mode, Cmd::Doc(item)));
+					continue;
+				}
+			};
+			match doc {
+				Doc::Nil => {}
+				Doc::Text(s) => cost = cost + self.text(text_width(s), s, pos, &mut out),
+				Doc::Line | Doc::SoftLine => match mode {
+					Mode::Flat => {
+						if let Doc::Line = doc {
+							cost = cost + self.text(1, " ", pos, &mut out);
+						}
+					}
+					Mode::Broken => cost = cost + self.newline(indent, pos, &mut out),
+				},
+				Doc::BlankLine => {
+					if let Some(out) = &mut out {
+						out.push('\n');
+					}
+					*pos = Pos::default();
+					cost.lines += 1;
+				}
+				Doc::BreakParent => {}
+				Doc::Nest(i, d) => stack.push((
+					indent.nested(i64::from(*i), self.tab_width),
+					mode,
+					Cmd::Doc(d),
+				)),
+				Doc::Align(d) => {
+					let indent = pos.line_indent.aligned(pos.column, self.tab_width);
+					stack.push((indent, mode, Cmd::Doc(d)))
+				}
+				Doc::Group(id, d) => {
+					let layout = (mode == Mode::Broken).then(|| {
+						let rest = Rest {
+							frames: &stack,
+							outer,
+						};
+						self.choose_layout(doc, *id, d, indent, *pos, &rest)
+					});
+					let mode = layout.map_or(Mode::Flat, |layout| layout.mode);
+					if let Some(id) = id {
+						self.group_modes.insert(*id, mode);
+					}
+					match layout {
+						Some(layout) if out.is_none() => {
+							cost = cost + layout.cost;
+							*pos = layout.end;
+						}
+						_ => stack.push((indent, mode, Cmd::Doc(d))),
+					}
+				}
+				Doc::IfBroken(id, broken, flat) => {
+					let group_mode = match id {
+						Some(id) => self.group_modes.get(id).copied().unwrap_or(Mode::Flat),
+						None => mode,
+					};
+					let d = if group_mode == Mode::Broken {
+						broken
+					} else {
+						flat
+					};
+					stack.push((indent, mode, Cmd::Doc(d)));
+				}
+				Doc::Concat(ds) => stack.extend(ds.iter().rev().map(|d| (indent, mode, Cmd::Doc(d)))),
+				Doc::Fill(ds) => {
+					if let [item, items @ ..] = &ds[..] {
+						stack.push((indent, mode, Cmd::FillRest(items)));
+						let rest = Rest {
+							frames: if items.is_empty() { &stack } else { &[] },
+							outer: if items.is_empty() { outer } else { None },
+						};
+						let item_mode = if mode == Mode::Flat || self.fits(self.width - pos.column, item, &rest)
+						{
+							Mode::Flat
+						} else {
+							Mode::Broken
+						};
+						stack.push((indent, item_mode, Cmd::Doc(item)));
+					}
+				}
+			}
+		}
+		cost
+	}
+
+	fn text(&self, width: i64, s: &str, pos: &mut Pos, out: &mut Option<&mut String>) -> Cost {
+		if let Some(out) = out {
+			out.push_str(s);
+		}
+		let overflow = if pos.column >= self.width { width } else { 0 };
+		pos.column += width;
+		Cost { overflow, lines: 0 }
+	}
+
+	fn newline(&self, indent: Indent, pos: &mut Pos, out: &mut Option<&mut String>) -> Cost {
+		if let Some(out) = out {
+			newline(out, indent);
+		}
+		*pos = Pos {
+			column: indent.width(self.tab_width),
+			line_indent: indent,
+		};
+		Cost {
+			overflow: 0,
+			lines: 1,
+		}
+	}
+
+	// The layout of the group `group`, made of `d`, from `pos`: flat if that fits along with the rest of the line,
+	// and otherwise whichever mode costs less, counting the rest of the last line.
+	fn choose_layout<'a>(
+		&mut self,
+		group: &'a Doc,
+		id: Option<GroupId>,
+		d: &'a Doc,
+		indent: Indent,
+		pos: Pos,
+		rest: &Rest<'_, 'a>,
+	) -> GroupLayout {
+		let key = (group as *const Doc, pos, indent, self.rest_width(id, rest));
+		if let Some(&layout) = self.layouts.get(&key) {
+			return layout;
+		}
+		let flat = self
+			.flat_width(d, &mut HashSet::new())
+			.map(|width| GroupLayout {
+				mode: Mode::Flat,
+				cost: Cost::default(),
+				end: Pos {
+					column: pos.column + width,
+					..pos
+				},
+			});
+		if let Some(id) = id {
+			self.group_modes.insert(id, Mode::Flat);
+		}
+		let layout = match flat {
+			Some(flat) if self.fits(self.width - pos.column, d, rest) => flat,
+			_ => {
+				if let Some(id) = id {
+					self.group_modes.insert(id, Mode::Broken);
+				}
+				let mut end = pos;
+				let cost = self.lay_out(
+					vec![(indent, Mode::Broken, Cmd::Doc(d))],
+					&mut end,
+					Some(rest),
+					None,
+				);
+				let broken = GroupLayout {
+					mode: Mode::Broken,
+					cost,
+					end,
+				};
+				match flat {
+					None => broken,
+					Some(flat) => {
+						let flat = GroupLayout {
+							cost: Cost {
+								overflow: self.flat_overflow(d, pos.column, flat.end.column),
+								lines: 0,
+							},
+							..flat
+						};
+						let [flat_rest, broken_rest] =
+							self.rest_overflows(id, rest, [flat.end.column, broken.end.column]);
+						let with_rest =
+							|layout: GroupLayout, overflow| layout.cost + Cost { overflow, lines: 0 };
+						if with_rest(flat, flat_rest) <= with_rest(broken, broken_rest) {
+							flat
+						} else {
+							broken
+						}
+					}
+				}
+			}
+		};
+		self.layouts.insert(key, layout);
+		layout
+	}
+
+	// The width of `doc` laid flat, or nothing if it cannot be, where `flat_groups` are the named groups laid flat
+	// along with it so far
+	fn flat_width(&mut self, doc: &Doc, flat_groups: &mut HashSet<GroupId>) -> Option<i64> {
+		Some(match doc {
+			Doc::Nil | Doc::SoftLine => 0,
+			Doc::Text(s) => text_width(s),
+			Doc::Line => 1,
+			Doc::BlankLine | Doc::BreakParent => return None,
+			Doc::Nest(_, d) | Doc::Align(d) => self.flat_width(d, flat_groups)?,
+			Doc::Group(id, d) => {
+				if let Some(id) = id {
+					flat_groups.insert(*id);
+				}
+				let key = &**d as *const Doc;
+				match self.flat_widths.get(&key) {
+					Some(&width) => width?,
+					None => {
+						let width = self.flat_width(d, flat_groups);
+						self.flat_widths.insert(key, width);
+						width?
+					}
+				}
+			}
+			Doc::IfBroken(id, broken, flat) => {
+				let d = match self.flat_group_mode(*id, flat_groups) {
+					Mode::Broken => broken,
+					Mode::Flat => flat,
+				};
+				self.flat_width(d, flat_groups)?
+			}
+			Doc::Concat(ds) => {
+				let mut width = 0;
+				for d in ds {
+					width += self.flat_width(d, flat_groups)?;
+				}
+				width
+			}
+			Doc::Fill(ds) => {
+				let mut width = ds.len().saturating_sub(1) as i64;
+				for d in ds {
+					width += self.flat_width(d, flat_groups)?;
+				}
+				width
+			}
+		})
+	}
+
+	// The mode of the group `id`, or of the innermost one, for an `IfBroken` laid flat
+	fn flat_group_mode(&self, id: Option<GroupId>, flat_groups: &HashSet<GroupId>) -> Mode {
+		match id {
+			Some(id) if !flat_groups.contains(&id) => {
+				self.group_modes.get(&id).copied().unwrap_or(Mode::Flat)
+			}
+			_ => Mode::Flat,
+		}
+	}
+
+	// The width of the text of `doc`, laid flat from `start` to `end`, that starts past the width of the page
+	fn flat_overflow(&self, doc: &Doc, start: i64, end: i64) -> i64 {
+		let mut column = start;
+		let mut flat_groups = HashSet::new();
+		let mut stack = vec![doc];
+		while let Some(doc) = stack.pop() {
+			if column >= self.width {
+				return end - column;
+			}
+			match doc {
+				Doc::Text(s) => column += text_width(s),
+				Doc::Line => column += 1,
+				Doc::Nil | Doc::SoftLine | Doc::BlankLine | Doc::BreakParent => {}
+				Doc::Nest(_, d) | Doc::Align(d) => stack.push(d),
+				Doc::Group(id, d) => {
+					if let Some(id) = id {
+						flat_groups.insert(*id);
+					}
+					stack.push(d);
+				}
+				Doc::IfBroken(id, broken, flat) => {
+					stack.push(match self.flat_group_mode(*id, &flat_groups) {
+						Mode::Broken => broken,
+						Mode::Flat => flat,
+					})
+				}
+				Doc::Concat(ds) => stack.extend(ds.iter().rev()),
+				Doc::Fill(ds) => {
+					for (i, d) in ds.iter().enumerate().rev() {
+						stack.push(d);
+						if i > 0 {
+							stack.push(&SPACE);
+						}
+					}
+				}
+			}
+		}
+		0
+	}
+
+	// Whether `doc`, laid flat, fits in `remaining` columns along with the rest of the line after it, which is made of
+	// what is in `rest` up to the first line break taken.
+	fn fits(&self, mut remaining: i64, doc: &Doc, rest: &Rest) -> bool {
+		let mut stack = vec![(Mode::Flat, Cmd::Doc(doc))];
+		let mut rest = rest.iter();
+		let mut flat_groups = HashSet::new();
+		while remaining >= 0 {
+			let Some((mode, cmd)) = stack.pop().or_else(|| rest.next()) else {
+				return true;
+			};
+			let doc = match cmd {
+				Cmd::Doc(doc) => doc,
+				Cmd::FillRest([]) => continue,
+				// the separator may be a line break
+				Cmd::FillRest(_) if mode == Mode::Broken => return true,
+				Cmd::FillRest([item, items @ ..]) => {
+					remaining -= 1;
+					stack.push((mode, Cmd::FillRest(items)));
+					stack.push((mode, Cmd::Doc(item)));
+					continue;
+				}
+			};
+			match doc {
+				Doc::Nil => {}
+				Doc::Text(s) => remaining -= text_width(s),
+				Doc::Line | Doc::SoftLine | Doc::BlankLine if mode == Mode::Broken => return true,
+				Doc::BlankLine => return false,
+				Doc::BreakParent if mode == Mode::Flat => return false,
+				Doc::BreakParent => {}
+				Doc::Line => remaining -= 1,
+				Doc::SoftLine => {}
+				Doc::Group(Some(id), d) if mode == Mode::Flat => {
+					flat_groups.insert(*id);
+					stack.push((mode, Cmd::Doc(d)));
+				}
+				Doc::Nest(_, d) | Doc::Align(d) | Doc::Group(_, d) => stack.push((mode, Cmd::Doc(d))),
+				Doc::IfBroken(id, broken, flat) => {
+					let group_mode = match (mode, id) {
+						(Mode::Flat, _) => self.flat_group_mode(*id, &flat_groups),
+						(Mode::Broken, Some(id)) => self.group_modes.get(id).copied().unwrap_or(Mode::Flat),
+						(Mode::Broken, None) => Mode::Broken,
+					};
+					let d = if group_mode == Mode::Broken {
+						broken
+					} else {
+						flat
+					};
+					stack.push((mode, Cmd::Doc(d)));
+				}
+				Doc::Concat(ds) => stack.extend(ds.iter().rev().map(|d| (mode, Cmd::Doc(d)))),
+				Doc::Fill(ds) => {
+					if let [item, items @ ..] = &ds[..] {
+						stack.push((mode, Cmd::FillRest(items)));
+						stack.push((mode, Cmd::Doc(item)));
+					}
+				}
+			}
+		}
+		false
+	}
+
+	// The width of the text that starts past the width of the page on the rest of the line after the group `id`, when
+	// laid flat and when broken, ending at `columns`. Once both have passed the width, the rest costs each the same,
+	// and is left out.
+	fn rest_overflows(&self, id: Option<GroupId>, rest: &Rest, mut columns: [i64; 2]) -> [i64; 2] {
+		let mut overflows = [0; 2];
+		self.for_each_rest_text(id, rest, |widths| {
+			for i in 0..2 {
+				if columns[i] >= self.width {
+					overflows[i] += widths[i];
+				}
+				columns[i] += widths[i];
+			}
+			columns.iter().any(|&column| column < self.width)
+		});
+		overflows
+	}
+
+	// The width of the rest of the line after the group `id` laid flat, up to one more than the width of the page, past
+	// which it makes no difference to the layout of the group
+	fn rest_width(&self, id: Option<GroupId>, rest: &Rest) -> i64 {
+		let mut width = 0;
+		self.for_each_rest_text(id, rest, |[flat, _]| {
+			width += flat;
+			width <= self.width
+		});
+		width.min(self.width + 1)
+	}
+
+	// Calls `f` with the width of each text on the rest of the line after the group `id`, when laid flat and when
+	// broken, until it returns false.
+	fn for_each_rest_text(
+		&self,
+		id: Option<GroupId>,
+		rest: &Rest,
+		mut f: impl FnMut([i64; 2]) -> bool,
+	) {
+		let mut stack = Vec::new();
+		let mut rest = rest.iter();
+		while let Some((mode, cmd)) = stack.pop().or_else(|| rest.next()) {
+			let doc = match cmd {
+				Cmd::Doc(doc) => doc,
+				Cmd::FillRest([]) => continue,
+				Cmd::FillRest(_) if mode == Mode::Broken => return,
+				Cmd::FillRest([item, items @ ..]) => {
+					stack.push((mode, Cmd::FillRest(items)));
+					stack.push((mode, Cmd::Doc(item)));
+					if !f([1; 2]) {
+						return;
+					}
+					continue;
+				}
+			};
+			let widths = match doc {
+				Doc::Line | Doc::SoftLine | Doc::BlankLine if mode == Mode::Broken => return,
+				Doc::BlankLine => return,
+				Doc::Text(s) => [text_width(s); 2],
+				Doc::Line => [1; 2],
+				Doc::Nil | Doc::SoftLine | Doc::BreakParent => continue,
+				Doc::Nest(_, d) | Doc::Align(d) | Doc::Group(_, d) => {
+					stack.push((mode, Cmd::Doc(d)));
+					continue;
+				}
+				Doc::IfBroken(if_id, broken, flat) => {
+					// what depends on the mode of the group itself, such as padding, differs between the two
+					if *if_id == id
+						&& if_id.is_some()
+						&& let (Some(flat), Some(broken)) = (plain_width(flat), plain_width(broken))
+					{
+						[flat, broken]
+					} else {
+						let group_mode = match if_id {
+							Some(if_id) if Some(*if_id) != id => {
+								self.group_modes.get(if_id).copied().unwrap_or(Mode::Flat)
+							}
+							Some(_) => Mode::Flat,
+							None => mode,
+						};
+						let d = if group_mode == Mode::Broken {
+							broken
+						} else {
+							flat
+						};
+						stack.push((mode, Cmd::Doc(d)));
+						continue;
+					}
+				}
+				Doc::Concat(ds) => {
+					stack.extend(ds.iter().rev().map(|d| (mode, Cmd::Doc(d))));
+					continue;
+				}
+				Doc::Fill(ds) => {
+					if let [item, items @ ..] = &ds[..] {
+						stack.push((mode, Cmd::FillRest(items)));
+						stack.push((mode, Cmd::Doc(item)));
+					}
+					continue;
+				}
+			};
+			if !f(widths) {
+				return;
+			}
+		}
+	}
+}
+
+// The width of `doc` if it is only text
+fn plain_width(doc: &Doc) -> Option<i64> {
+	match doc {
+		Doc::Nil => Some(0),
+		Doc::Text(s) => Some(text_width(s)),
+		Doc::Concat(ds) => ds.iter().map(plain_width).sum(),
+		_ => None,
+	}
+}
+
+fn text_width(s: &str) -> i64 {
+	s.chars().count().try_into().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// `(head arg ...)` with the arguments on separate lines when broken
+	fn call(head: &str, args: Vec<Doc>) -> Doc {
+		let mut body = vec![Doc::text(head)];
+		for arg in args {
+			body.push(Doc::Line);
+			body.push(arg);
+		}
+		Doc::group(Doc::Concat(vec![
+			Doc::text("("),
+			Doc::nest(2, Doc::Concat(body)),
+			Doc::text(")"),
+		]))
+	}
+
+	#[test]
+	fn test_render_groups() {
+		let doc = call(
+			"f",
+			vec![Doc::text("aaaa"), call("g", vec![Doc::text("bbbb")])],
+		);
+		assert_eq!(doc.render(20), "(f aaaa (g bbbb))");
+		assert_eq!(doc.render(11), "(f\n  aaaa\n  (g bbbb))");
+		// the closing brackets that follow a group count against its width
+		assert_eq!(doc.render(10), "(f\n  aaaa\n  (g\n    bbbb))");
+	}
+
+	#[test]
+	fn test_render_by_cost() {
+		let long = "a".repeat(20);
+		// breaking either list leaves the closing brackets past the width
+		let doc = call("f", vec![call("g", vec![Doc::text(&long)])]);
+		assert_eq!(doc.render(20), format!("(f (g {long}))"));
+		// breaking the outer one brings `b` back within it
+		let doc = call("f", vec![call("g", vec![Doc::text(&long)]), Doc::text("b")]);
+		assert_eq!(doc.render(20), format!("(f\n  (g {long})\n  b)"));
+		// with the text that runs past it unchanged, fewer lines are better
+		let doc = call("f", vec![Doc::text(&long)]);
+		assert_eq!(doc.render(20), format!("(f {long})"));
+		assert_eq!(doc.render(23), format!("(f\n  {long})"));
+	}
+
+	#[test]
+	fn test_render_soft_line_and_if_broken() {
+		let inner = Doc::group_with_id(
+			GroupId(0),
+			Doc::Concat(vec![
+				Doc::text("["),
+				Doc::nest(2, Doc::Concat(vec![Doc::SoftLine, Doc::text("x y z")])),
+				Doc::SoftLine,
+				Doc::text("]"),
+			]),
+		);
+		let doc = Doc::Concat(vec![
+			inner,
+			Doc::if_broken(Some(GroupId(0)), Doc::text(" ;broken"), Doc::text(" ;flat")),
+		]);
+		assert_eq!(doc.render(13), "[x y z] ;flat");
+		assert_eq!(doc.render(6), "[\n  x y z\n] ;broken");
+	}
+
+	#[test]
+	fn test_render_align() {
+		let doc = Doc::Concat(vec![
+			Doc::text("  (f "),
+			Doc::align(Doc::group(Doc::Concat(vec![
+				Doc::text("a"),
+				Doc::Line,
+				Doc::text("b"),
+			]))),
+			Doc::text(")"),
+		]);
+		assert_eq!(doc.render(10), "  (f a b)");
+		assert_eq!(doc.render(8), "  (f a\n     b)");
+	}
+
+	#[test]
+	fn test_render_with_tabs() {
+		let doc = call(
+			"f",
+			vec![call("g", vec![Doc::text("aaaa"), Doc::text("bbbb")])],
+		);
+		assert_eq!(
+			doc.render_with_tabs(10, 2),
+			"(f\n\t(g\n\t\taaaa\n\t\tbbbb))"
+		);
+		// nesting by less than a tab is made of spaces
+		assert_eq!(
+			doc.render_with_tabs(10, 3),
+			"(f\n  (g\n    aaaa\n    bbbb))"
+		);
+		// as is alignment, after the tabs of the line aligned to
+		let doc = call(
+			"f",
+			vec![Doc::Concat(vec![
+				Doc::text("(g "),
+				Doc::align(Doc::Concat(vec![
+					Doc::text("aaaa"),
+					Doc::Line,
+					call("h", vec![Doc::text("bbbb")]),
+				])),
+				Doc::text(")"),
+			])],
+		);
+		assert_eq!(
+			doc.render_with_tabs(12, 2),
+			"(f\n\t(g aaaa\n\t   (h\n\t     bbbb)))"
+		);
+	}
+
+	#[test]
+	fn test_render_blank_line() {
+		let doc = call(
+			"f",
+			vec![
+				Doc::Concat(vec![Doc::text("a"), Doc::BlankLine]),
+				Doc::text("b"),
+			],
+		);
+		assert_eq!(doc.render(80), "(f\n  a\n\n  b)");
+	}
+
+	#[test]
+	fn test_render_fill() {
+		let doc = Doc::Concat(vec![
+			Doc::text("["),
+			Doc::align(Doc::Fill(
+				["1", "22", "333", "4", "55", "6"]
+					.into_iter()
+					.map(Doc::text)
+					.collect(),
+			)),
+			Doc::text("]"),
+		]);
+		assert_eq!(doc.render(20), "[1 22 333 4 55 6]");
+		assert_eq!(doc.render(9), "[1 22 333\n 4 55 6]");
+		// the closing bracket follows the last item
+		assert_eq!(doc.render(7), "[1 22\n 333 4\n 55 6]");
+		assert_eq!(doc.render(5), "[1 22\n 333\n 4 55\n 6]");
+	}
+}
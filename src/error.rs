@@ -46,6 +46,9 @@ pub enum SexpfmtError {
 
 	#[error("Invalid input at {position}: {message}")]
 	InvalidInput { message: String, position: Loc },
+
+	#[error("Invalid configuration: {message}")]
+	InvalidConfig { message: String },
 }
 
 impl fmt::Display for Loc {
@@ -108,6 +111,32 @@ impl SexpfmtError {
 			position,
 		}
 	}
+
+	pub fn invalid_config<S: Into<String>>(message: S) -> Self {
+		Self::InvalidConfig {
+			message: message.into(),
+		}
+	}
+
+	/// Input whose lists are nested more than `max_depth` deep, which would otherwise risk overflowing the stack.
+	pub fn nesting_too_deep(position: Loc, max_depth: usize) -> Self {
+		Self::invalid_input(
+			format!("Lists are nested more than {} deep", max_depth),
+			position,
+		)
+	}
+
+	/// The location of the offending input, if known.
+	pub fn position(&self) -> Option<Loc> {
+		match self {
+			Self::Io { .. } | Self::Utf8 { .. } | Self::InvalidConfig { .. } => None,
+			Self::FormReader { position, .. } => *position,
+			Self::Parse { position, .. }
+			| Self::MismatchedBookends { position, .. }
+			| Self::UnexpectedEof { position, .. }
+			| Self::InvalidInput { position, .. } => Some(*position),
+		}
+	}
 }
 
 // Convenience type alias
@@ -138,20 +167,81 @@ impl Loc {
 		self.column
 	}
 
-	pub fn in_form(start_of_form_loc: Self, span: nom_locate::LocatedSpan<&str>) -> Self {
-		// Calculate position within the form based on nom's position
-		let offset_in_form = span.location_offset();
-		let line_in_form = span.location_line() as usize;
-		let column_in_form = span.get_column();
+	/// The location of byte `offset` of `text`. Lines end with `\n`, `\r\n` or `\r`.
+	pub fn at_offset(text: &str, offset: usize) -> Self {
+		Self::in_form(Self::new(0, 1, 1), text, offset)
+	}
+
+	/// The location of byte `offset` of `form`, which starts at `start_of_form_loc`.
+	pub fn in_form(start_of_form_loc: Self, form: &str, offset: usize) -> Self {
+		LocCursor::new(form, start_of_form_loc).at(offset)
+	}
+}
 
-		// Adjust position to be relative to the original input
+/// The offsets at which the lines of `text` after the first start. Lines end with `\n`, `\r\n` or `\r`.
+pub(crate) fn line_starts(text: &str) -> LineStarts<'_> {
+	LineStarts {
+		bytes: text.as_bytes(),
+		offset: 0,
+	}
+}
+
+pub(crate) struct LineStarts<'a> {
+	bytes: &'a [u8],
+	// where to look for the next line break
+	offset: usize,
+}
+
+impl Iterator for LineStarts<'_> {
+	type Item = usize;
+
+	fn next(&mut self) -> Option<usize> {
+		while self.offset < self.bytes.len() {
+			let i = self.offset;
+			self.offset += 1;
+			match self.bytes[i] {
+				b'\n' => return Some(i + 1),
+				b'\r' if self.bytes.get(i + 1) != Some(&b'\n') => return Some(i + 1),
+				_ => {}
+			}
+		}
+		None
+	}
+}
+
+/// Works out the locations of the bytes of a text in increasing order, scanning each byte once, where `Loc::in_form`
+/// scans the text from its start every time.
+pub(crate) struct LocCursor<'a> {
+	start: Loc,
+	line_starts: std::iter::Peekable<LineStarts<'a>>,
+	line: usize,
+	// where the line of the last location asked for starts, unless it is the first line
+	line_start: Option<usize>,
+}
+
+impl<'a> LocCursor<'a> {
+	/// A cursor on `text`, which starts at `start`.
+	pub(crate) fn new(text: &'a str, start: Loc) -> Self {
 		Self {
-			offset: start_of_form_loc.offset() + offset_in_form,
-			line: start_of_form_loc.line() + line_in_form - 1,
-			column: if line_in_form == 1 {
-				start_of_form_loc.column() + column_in_form - 1
-			} else {
-				column_in_form
+			start,
+			line_starts: line_starts(text).peekable(),
+			line: start.line,
+			line_start: None,
+		}
+	}
+
+	/// The location of byte `offset` of the text, which must not be before the previous one asked for.
+	pub(crate) fn at(&mut self, offset: usize) -> Loc {
+		while let Some(line_start) = self.line_starts.next_if(|&line_start| line_start <= offset) {
+			self.line += 1;
+			self.line_start = Some(line_start);
+		}
+		Loc {
+			offset: self.start.offset + offset,
+			line: self.line,
+			column: match self.line_start {
+				Some(line_start) => 1 + offset - line_start,
+				None => self.start.column + offset,
 			},
 		}
 	}
@@ -0,0 +1,407 @@
+use super::*;
+
+use std::io;
+use std::ops::Range;
+
+/// Formats every form read from `input`, writing each top-level form to `output` on its own line, with the blank lines
+/// between forms, the line endings, the byte order mark and the final line break as set out by `config`.
+/// The output is flushed after each top-level form, so that forms are emitted as soon as they are complete; the line
+/// break after a form is only written once the next one starts, or at the end of the input.
+pub fn format_stream<R: io::Read, W: io::Write>(
+	input: R,
+	mut output: W,
+	config: &PrinterConfig,
+) -> Result<()> {
+	config.check()?;
+	let mut reader = FormReader::new(input)?;
+	reader.set_max_depth(config.max_depth);
+	let line_ending = |reader: &FormReader<R>| {
+		config
+			.line_ending
+			.or(reader.line_ending())
+			.unwrap_or(LineEnding::Lf)
+	};
+	let mut first = true;
+	let mut after_comment = false;
+	loop {
+		let line_breaks = reader.peek_line_breaks()?;
+		let formatted = match format_next_form(&mut reader, config) {
+			Ok(next) => next,
+			Err(e) => {
+				// the forms written so far are still complete lines
+				if !first {
+					output.write_all(line_ending(&reader).as_str().as_bytes())?;
+				}
+				return Err(e);
+			}
+		};
+		let Some((formatted, is_comment)) = formatted else {
+			if !first && config.ends_with_line_break(line_breaks > 0) {
+				output.write_all(line_ending(&reader).as_str().as_bytes())?;
+			}
+			break;
+		};
+		if config.line_ending.is_none() && reader.line_ending().is_none() && formatted.contains('\n') {
+			// the line break after the form may tell
+			reader.peek_line_breaks()?;
+		}
+		let line_ending = line_ending(&reader);
+		if first {
+			if config.keep_bom && reader.has_bom() {
+				output.write_all("\u{FEFF}".as_bytes())?;
+			}
+		} else if is_comment && line_breaks == 0 {
+			// a comment after a form on its line stays there
+			output.write_all(b" ")?;
+		} else {
+			let blank_lines = line_breaks.saturating_sub(1);
+			// comments on lines of their own keep to what they precede
+			let blank_lines = if after_comment {
+				blank_lines.min(config.max_blank_lines)
+			} else {
+				config.blank_lines_between_forms(blank_lines)
+			};
+			output.write_all(line_ending.as_str().repeat(1 + blank_lines).as_bytes())?;
+		}
+		first = false;
+		after_comment = is_comment;
+		output.write_all(line_ending.apply(formatted).as_bytes())?;
+		output.flush()?;
+	}
+	Ok(())
+}
+
+// Reads and formats the next top-level form, if any, along with whether it is a comment between forms, which is kept as
+// written but for trailing whitespace.
+fn format_next_form<R: io::Read>(
+	reader: &mut FormReader<R>,
+	config: &PrinterConfig,
+) -> Result<Option<(String, bool)>> {
+	let Some((s, position)) = reader.get()? else {
+		return Ok(None);
+	};
+	if s.starts_with(';') {
+		return Ok(Some((s.trim_end().to_string(), true)));
+	}
+	let formatted = with_located_form(s, position, config.max_depth, |form| {
+		form
+			.iter()
+			.map(|(sexp, locs)| format_located_sexp(sexp, locs, config))
+			.collect()
+	})?;
+	Ok(Some((formatted, false)))
+}
+
+/// Formats every form in `text`, as `format_stream` would.
+pub fn format_str(text: &str, config: &PrinterConfig) -> Result<String> {
+	let mut output = Vec::new();
+	format_stream(text.as_bytes(), &mut output, config)?;
+	Ok(String::from_utf8(output)?)
+}
+
+/// Returns the byte range spanning the (1-based, inclusive) lines `first_line` through `last_line` of `text`.
+pub fn line_range_to_byte_range(text: &str, first_line: usize, last_line: usize) -> Range<usize> {
+	let line_start = |line: usize| {
+		if line <= 1 {
+			return 0;
+		}
+		line_starts(text).nth(line - 2).unwrap_or(text.len())
+	};
+	line_start(first_line)..line_start(last_line + 1)
+}
+
+/// Returns an edit for each top-level form overlapping the bytes `range` of `text` that is not already formatted: the
+/// byte span of the form, and the text to replace it with. An empty `range` selects the form containing its start.
+/// Forms after the end of `range` are not read, so errors there are not reported.
+pub fn format_range_edits(
+	text: &str,
+	range: Range<usize>,
+	config: &PrinterConfig,
+) -> Result<Vec<(Range<usize>, String)>> {
+	config.check()?;
+	let until = range.end.max(range.start + 1);
+	let line_ending = config
+		.line_ending
+		.or(LineEnding::detect(text))
+		.unwrap_or(LineEnding::Lf);
+	let mut reader = FormReader::new(text.as_bytes())?;
+	reader.set_max_depth(config.max_depth);
+	let mut edits = Vec::new();
+	while reader.peek_loc()?.is_some_and(|loc| loc.offset() < until) {
+		let Some((s, position)) = reader.get()? else {
+			break;
+		};
+		let span = position.offset()..position.offset() + s.len();
+		// comments are read as forms that parse to nothing, and must be left alone
+		if span.end <= range.start || s.starts_with(';') {
+			continue;
+		}
+		// formatted where the form is parsed, so that deeply nested forms are dropped with the stack for it
+		let formatted: String = with_located_form(s, position, config.max_depth, |form| {
+			form
+				.iter()
+				.map(|(sexp, locs)| format_located_sexp(sexp, locs, config))
+				.collect()
+		})?;
+		let formatted = line_ending.apply(formatted);
+		if formatted != text[span.clone()] {
+			edits.push((span, formatted));
+		}
+	}
+	Ok(edits)
+}
+
+/// Reformats the top-level forms overlapping the bytes `range` of `text`, leaving everything else untouched.
+pub fn format_range(text: &str, range: Range<usize>, config: &PrinterConfig) -> Result<String> {
+	let mut res = String::with_capacity(text.len());
+	let mut copied_up_to = 0;
+	for (span, formatted) in format_range_edits(text, range, config)?.into_iter() {
+		res.push_str(&text[copied_up_to..span.start]);
+		res.push_str(&formatted);
+		copied_up_to = span.end;
+	}
+	res.push_str(&text[copied_up_to..]);
+	Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_format_str() {
+		assert_eq!(
+			format_str("(a\n  b ; comment\n)  [c]", &PrinterConfig::default()).unwrap(),
+			"(a\n  b ; comment\n)\n[c]\n"
+		);
+	}
+
+	#[test]
+	fn test_format_str_config() {
+		let mut config = PrinterConfig {
+			margin_width: 17,
+			indent_width: 4,
+			..PrinterConfig::default()
+		};
+		assert_eq!(
+			format_str("(hello (wide world))", &config).unwrap(),
+			"(hello\n    (wide world))\n"
+		);
+		// the closing brackets after `(wide world)` no longer fit
+		config.margin_width = 16;
+		assert_eq!(
+			format_str("(hello (wide world))", &config).unwrap(),
+			"(hello\n    (wide\n        world))\n"
+		);
+	}
+
+	#[test]
+	fn test_format_str_blank_lines() {
+		let text = "\n\n(a)\n\n\n\n(b\n\n\n c d\n\n e) (f)\n\n";
+		let mut config = PrinterConfig::default();
+		assert_eq!(
+			format_str(text, &config).unwrap(),
+			"(a)\n\n(b\n\n  c\n  d\n\n  e)\n(f)\n"
+		);
+		config.max_blank_lines = 2;
+		assert_eq!(
+			format_str(text, &config).unwrap(),
+			"(a)\n\n\n(b\n\n\n  c\n  d\n\n  e)\n(f)\n"
+		);
+		config.max_blank_lines = 0;
+		assert_eq!(format_str(text, &config).unwrap(), "(a)\n(b c d e)\n(f)\n");
+		config.separate_top_level_forms = true;
+		assert_eq!(
+			format_str(text, &config).unwrap(),
+			"(a)\n\n(b c d e)\n\n(f)\n"
+		);
+	}
+
+	#[test]
+	fn test_format_str_top_level_comments() {
+		let mut config = PrinterConfig::default();
+		assert_eq!(
+			format_str("(a)\n\n;c  \n\n\n(b)\n", &config).unwrap(),
+			"(a)\n\n;c\n\n(b)\n"
+		);
+		assert_eq!(
+			format_str("(a)\n;c\n(b)", &config).unwrap(),
+			"(a)\n;c\n(b)\n"
+		);
+		assert_eq!(
+			format_str("(a)   ; c d\n(b)", &config).unwrap(),
+			"(a) ; c d\n(b)\n"
+		);
+		assert_eq!(
+			format_str("; header\n(a)\n; footer", &config).unwrap(),
+			"; header\n(a)\n; footer\n"
+		);
+		// bookends in strings and comments are not counted
+		assert_eq!(
+			format_str("(a \")\\\"\" b)\n(c ; (\n d)\n\"e )\"", &config).unwrap(),
+			"(a \")\\\"\" b)\n(c ; (\n  d)\n\"e )\"\n"
+		);
+		// comments on lines of their own keep to the forms after them
+		config.separate_top_level_forms = true;
+		assert_eq!(
+			format_str("(a)\n;c\n(b)", &config).unwrap(),
+			"(a)\n\n;c\n(b)\n"
+		);
+		assert_eq!(
+			format_str("(a)\n\n;c\n\n(b)\n", &config).unwrap(),
+			"(a)\n\n;c\n\n(b)\n"
+		);
+	}
+
+	#[test]
+	fn test_format_str_comments() {
+		let text = "(define (f x) ; doc\n\n  ;; body\n\n  ; more\n  (g x (h ; y\n y))\n\n  ; end\n  )\n( ; empty\n)";
+		let mut config = PrinterConfig::default();
+		assert_eq!(
+			format_str(text, &config).unwrap(),
+			"(define\n  (f x) ; doc\n\n  ;; body\n\n  ; more\n  (g\n    x\n    (h ; y\n      y))\n\n  ; end\n)\n(; empty\n)\n"
+		);
+		config.layout = Layout::Lisp;
+		assert_eq!(
+			format_str(text, &config).unwrap(),
+			"(define (f x) ; doc\n\n  ;; body\n\n  ; more\n  (g x\n     (h ; y\n      y))\n\n  ; end\n)\n(; empty\n)\n"
+		);
+		// a comment after the head of a call puts its arguments on the lines after
+		assert_eq!(
+			format_str("(foo ; c\n a b)\n(let ; c\n ((a 1)) a)", &config).unwrap(),
+			"(foo ; c\n a\n b)\n(let ; c\n    ((a 1))\n  a)\n"
+		);
+		// and so does a comment before its first argument, or after its head alone
+		assert_eq!(
+			format_str("(foo\n ; c\n a)\n(foo\n\n ; c\n ; d\n)", &config).unwrap(),
+			"(foo\n ; c\n a)\n(foo\n ; c\n ; d\n)\n"
+		);
+		// lists with comments are neither filled nor laid out as tables
+		config.fill_atom_lists = true;
+		assert_eq!(
+			format_str("[1 ; one\n 2 3]", &config).unwrap(),
+			"[1 ; one\n 2\n 3]\n"
+		);
+		config.fill_atom_lists = false;
+		config.align_tables = true;
+		assert_eq!(
+			format_str("(t (a bb) ; first\n (ccc d))", &config).unwrap(),
+			"(t (a bb) ; first\n   (ccc d))\n"
+		);
+		// a first element after comments is on a line of its own, whatever the style of multiline heads
+		let mut config = PrinterConfig {
+			margin_width: 8,
+			..PrinterConfig::default()
+		};
+		let text = "(; c\n (\"a\" bcd) e)";
+		let formatted = "(; c\n  (\"a\"\n    bcd)\n  e)\n";
+		assert_eq!(format_str(text, &config).unwrap(), formatted);
+		config.multiline_heads = MultilineHeads::OwnLine;
+		assert_eq!(format_str(text, &config).unwrap(), formatted);
+	}
+
+	#[test]
+	fn test_format_str_line_endings() {
+		let text = "\u{FEFF}(a\r\n b)\r\n\r\n\r\n(c)";
+		let mut config = PrinterConfig::default();
+		assert_eq!(
+			format_str(text, &config).unwrap(),
+			"\u{FEFF}(a b)\r\n\r\n(c)\r\n"
+		);
+		config.line_ending = Some(LineEnding::Lf);
+		config.keep_bom = false;
+		config.final_newline = FinalNewline::Preserve;
+		assert_eq!(format_str(text, &config).unwrap(), "(a b)\n\n(c)");
+		assert_eq!(format_str("(a)\r\n  ", &config).unwrap(), "(a)\n");
+		config.line_ending = None;
+		config.final_newline = FinalNewline::Never;
+		config.margin_width = 6;
+		// the line ending is found after the first form, which needs one
+		assert_eq!(
+			format_str("(a bcd)\r(e)\r", &config).unwrap(),
+			"(a\r  bcd)\r(e)"
+		);
+	}
+
+	#[test]
+	fn test_format_str_error_position() {
+		let err = format_str("(a)\n(b", &PrinterConfig::default()).unwrap_err();
+		assert_eq!(err.position(), Some(Loc::new(4, 2, 1)));
+	}
+
+	#[test]
+	fn test_format_str_max_depth() {
+		let deep = format!("{}x{}", "(".repeat(100_000), ")".repeat(100_000));
+		assert!(matches!(
+			format_str(&deep, &PrinterConfig::default()),
+			Err(SexpfmtError::InvalidInput { .. })
+		));
+		let deepest = format!(
+			"{}x{}",
+			"(".repeat(DEFAULT_MAX_DEPTH),
+			")".repeat(DEFAULT_MAX_DEPTH)
+		);
+		assert!(format_str(&deepest, &PrinterConfig::default()).is_ok());
+		// deeper input is accepted when allowed, however small the stack of the calling thread
+		let deeper = format!("{}x{}", "(".repeat(10_000), ")".repeat(10_000));
+		let config = PrinterConfig {
+			max_depth: 10_000,
+			..PrinterConfig::default()
+		};
+		assert!(format_str(&deeper, &config).is_ok());
+		assert!(format_range(&deeper, 0..1, &config).is_ok());
+		let config = PrinterConfig {
+			max_depth: 2,
+			..PrinterConfig::default()
+		};
+		assert_eq!(format_str("((a)) b", &config).unwrap(), "((a))\nb\n");
+		let err = format_str("((a)) ((b (c)))", &config).unwrap_err();
+		assert!(matches!(err, SexpfmtError::InvalidInput { .. }));
+		assert_eq!(err.position(), Some(Loc::new(10, 1, 11)));
+	}
+
+	#[test]
+	fn test_format_range() {
+		let text = "(a\n  b)\n\n;keep\n(c\n    d)   (e\n f)\n(g\n  h";
+		let config = PrinterConfig::default();
+		let c_start = text.find("(c").unwrap();
+		assert_eq!(
+			format_range(text, c_start + 1..c_start + 2, &config).unwrap(),
+			"(a\n  b)\n\n;keep\n(c d)   (e\n f)\n(g\n  h"
+		);
+		assert_eq!(
+			format_range(text, c_start..text.find("f)").unwrap(), &config).unwrap(),
+			"(a\n  b)\n\n;keep\n(c d)   (e f)\n(g\n  h"
+		);
+		// the unclosed form after the range is never read
+		assert!(format_range(text, 0..text.len(), &config).is_err());
+		// comments in the range are kept
+		assert_eq!(
+			format_range(text, 0..c_start, &config).unwrap(),
+			"(a b)\n\n;keep\n(c\n    d)   (e\n f)\n(g\n  h"
+		);
+	}
+
+	#[test]
+	fn test_format_range_line_endings() {
+		let text = "(a\r\n  b)\r\n(c d e)";
+		let config = PrinterConfig {
+			margin_width: 6,
+			..PrinterConfig::default()
+		};
+		assert_eq!(
+			format_range(text, 0..text.len(), &config).unwrap(),
+			"(a b)\r\n(c\r\n  d\r\n  e)"
+		);
+	}
+
+	#[test]
+	fn test_line_range_to_byte_range() {
+		let text = "a\nbb\nccc\n";
+		assert_eq!(line_range_to_byte_range(text, 1, 1), 0..2);
+		assert_eq!(line_range_to_byte_range(text, 2, 3), 2..9);
+		assert_eq!(line_range_to_byte_range(text, 3, 10), 5..9);
+		assert_eq!(line_range_to_byte_range("a\rb\rc", 2, 2), 2..4);
+		assert_eq!(line_range_to_byte_range("a\r\nb\rc\n", 2, 3), 3..7);
+	}
+}
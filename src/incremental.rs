@@ -0,0 +1,342 @@
+// Incremental reformatting, for editors that reformat on every keystroke.
+// The formatter keeps the source text along with each top-level form's byte span and formatted output. When the text
+// is edited, only the top-level forms from the one touching the edit onwards are re-read, and only until the reader
+// reaches the start of an old form that lies wholly after the edit: since `FormReader` carries no state from one
+// top-level form to the next, every form from there on is unchanged but for its offset.
+
+use super::*;
+
+use std::ops::Range;
+
+/// Replaces the bytes `range` of a text with `new_text`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TextEdit {
+	pub range: Range<usize>,
+	pub new_text: String,
+}
+
+pub struct IncrementalFormatter {
+	config: PrinterConfig,
+	text: String,
+	// `None` if the text could not be parsed after the last edit
+	forms: Option<Vec<CachedForm>>,
+}
+
+struct CachedForm {
+	start: usize,
+	end: usize,
+	// the number of line breaks between the form and the one before it
+	line_breaks_before: usize,
+	// empty for a comment between forms
+	sexps: Vec<SExp>,
+	formatted: String,
+}
+
+// Where reading stopped at an old form: its index among the unaffected forms, and the line breaks now before it
+struct Resync {
+	index: usize,
+	line_breaks_before: usize,
+}
+
+impl IncrementalFormatter {
+	pub fn new(text: String, config: PrinterConfig) -> Result<Self> {
+		config.check()?;
+		let mut res = Self {
+			config,
+			text,
+			forms: None,
+		};
+		res.forms = Some(res.read_forms(0, &[], 0)?.0);
+		Ok(res)
+	}
+
+	pub fn text(&self) -> &str {
+		&self.text
+	}
+
+	/// The top-level forms of the text, as parsed before the last edit that could be parsed.
+	pub fn forms(&self) -> impl Iterator<Item = &SExp> {
+		self
+			.forms
+			.iter()
+			.flatten()
+			.flat_map(|form| form.sexps.iter())
+	}
+
+	/// Applies `edit` to the text, then re-reads the top-level forms it affects. Returns the number of top-level forms
+	/// that were re-read. If the edited text cannot be parsed, the edit is still applied, and the error is returned;
+	/// the next edit will then re-read the whole text.
+	pub fn apply_edit(&mut self, edit: &TextEdit) -> Result<usize> {
+		let TextEdit { range, new_text } = edit;
+		if range.start > range.end
+			|| !self.text.is_char_boundary(range.start)
+			|| !self.text.is_char_boundary(range.end)
+		{
+			return Err(SexpfmtError::invalid_input(
+				format!(
+					"Edit range {}..{} is out of bounds or splits a character",
+					range.start, range.end
+				),
+				Loc::at_offset(&self.text, floor_char_boundary(&self.text, range.start)),
+			));
+		}
+		self.text.replace_range(range.clone(), new_text);
+
+		let Some(old_forms) = self.forms.take() else {
+			let forms = self.read_forms(0, &[], 0)?.0;
+			let reread_count = forms.len();
+			self.forms = Some(forms);
+			return Ok(reread_count);
+		};
+		// forms ending right before the edit are affected too, since the edit may extend them
+		let first_affected = old_forms.partition_point(|form| form.end < range.start);
+		// reading from the end of the form before, so as to count the blank lines before the first form read
+		let resume = first_affected
+			.checked_sub(1)
+			.map_or(0, |k| old_forms[k].end);
+		let delta = new_text.len() as isize - range.len() as isize;
+		let unaffected_tail: Vec<&CachedForm> = old_forms[first_affected..]
+			.iter()
+			.filter(|form| form.start >= range.end)
+			.collect();
+
+		let (new_forms, resync) = self.read_forms(resume, &unaffected_tail, delta)?;
+		let resync_start = resync
+			.as_ref()
+			.map(|resync| unaffected_tail[resync.index].start);
+
+		let mut forms = old_forms;
+		let tail: Vec<CachedForm> = match (resync_start, resync) {
+			(Some(resync_start), Some(resync)) => {
+				let tail_start = forms.partition_point(|form| form.start < resync_start);
+				let mut tail: Vec<CachedForm> = forms
+					.drain(tail_start..)
+					.map(|form| CachedForm {
+						start: form.start.wrapping_add_signed(delta),
+						end: form.end.wrapping_add_signed(delta),
+						..form
+					})
+					.collect();
+				// the edit may have changed what is between the first of them and the form before
+				tail[0].line_breaks_before = resync.line_breaks_before;
+				tail
+			}
+			_ => Vec::new(),
+		};
+		let reread_count = new_forms.len();
+		forms.truncate(first_affected);
+		forms.extend(new_forms);
+		forms.extend(tail);
+		self.forms = Some(forms);
+		Ok(reread_count)
+	}
+
+	/// Returns the formatted text, the same as `format_str` would.
+	pub fn format(&mut self) -> Result<String> {
+		if self.forms.is_none() {
+			self.forms = Some(self.read_forms(0, &[], 0)?.0);
+		}
+		let forms = self.forms.as_deref().unwrap_or_default();
+		let mut res = String::new();
+		if self.config.keep_bom && self.text.starts_with('\u{FEFF}') && !forms.is_empty() {
+			res.push('\u{FEFF}');
+		}
+		for (i, form) in forms.iter().enumerate() {
+			let is_comment = form.sexps.is_empty();
+			if i > 0 && is_comment && form.line_breaks_before == 0 {
+				res.push(' ');
+			} else if i > 0 {
+				let blank_lines = form.line_breaks_before.saturating_sub(1);
+				let blank_lines = if forms[i - 1].sexps.is_empty() {
+					blank_lines.min(self.config.max_blank_lines)
+				} else {
+					self.config.blank_lines_between_forms(blank_lines)
+				};
+				res.push_str(&"\n".repeat(1 + blank_lines));
+			}
+			res.push_str(&form.formatted);
+		}
+		if let Some(last) = forms.last() {
+			let input_does = self.text[last.end..].contains(['\n', '\r']);
+			if self.config.ends_with_line_break(input_does) {
+				res.push('\n');
+			}
+		}
+		let line_ending = self
+			.config
+			.line_ending
+			.or(LineEnding::detect(&self.text))
+			.unwrap_or(LineEnding::Lf);
+		Ok(line_ending.apply(res))
+	}
+
+	// Reads forms starting at byte `resume`, until EOF or until reaching the start of one of the old forms
+	// `unaffected_tail` (shifted by `delta`), which is then returned along with the forms read.
+	fn read_forms(
+		&self,
+		resume: usize,
+		unaffected_tail: &[&CachedForm],
+		delta: isize,
+	) -> Result<(Vec<CachedForm>, Option<Resync>)> {
+		let mut reader = FormReader::with_start_loc(
+			&self.text.as_bytes()[resume..],
+			Loc::at_offset(&self.text, resume),
+		)?;
+		reader.set_max_depth(self.config.max_depth);
+		let mut forms = Vec::new();
+		let mut k = 0;
+		while let Some(loc) = reader.peek_loc()? {
+			while k < unaffected_tail.len()
+				&& unaffected_tail[k].start.wrapping_add_signed(delta) < loc.offset()
+			{
+				k += 1;
+			}
+			if k < unaffected_tail.len()
+				&& unaffected_tail[k].start.wrapping_add_signed(delta) == loc.offset()
+			{
+				return Ok((
+					forms,
+					Some(Resync {
+						index: k,
+						line_breaks_before: reader.peek_line_breaks()?,
+					}),
+				));
+			}
+
+			let line_breaks_before = reader.peek_line_breaks()?;
+			let Some((s, position)) = reader.get()? else {
+				break;
+			};
+			let end = position.offset() + s.len();
+			if s.starts_with(';') {
+				forms.push(CachedForm {
+					start: position.offset(),
+					end,
+					line_breaks_before,
+					sexps: Vec::new(),
+					formatted: s.trim_end().to_string(),
+				});
+				continue;
+			}
+			let located = parse_form_with_locs_and_max_depth(s, position, self.config.max_depth)?;
+			let formatted = located
+				.iter()
+				.map(|(sexp, locs)| format_located_sexp(sexp, locs, &self.config))
+				.collect();
+			forms.push(CachedForm {
+				start: position.offset(),
+				end,
+				line_breaks_before,
+				sexps: located.into_iter().map(|(sexp, _)| sexp).collect(),
+				formatted,
+			});
+		}
+		Ok((forms, None))
+	}
+}
+
+fn floor_char_boundary(text: &str, offset: usize) -> usize {
+	(0..=offset.min(text.len()))
+		.rev()
+		.find(|&i| text.is_char_boundary(i))
+		.unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn edit(formatter: &mut IncrementalFormatter, old: &str, new: &str) -> usize {
+		let start = formatter.text().find(old).unwrap();
+		let count = formatter
+			.apply_edit(&TextEdit {
+				range: start..start + old.len(),
+				new_text: new.to_string(),
+			})
+			.unwrap();
+		let expected = format_str(formatter.text(), &PrinterConfig::default()).unwrap();
+		assert_eq!(formatter.format().unwrap(), expected);
+		count
+	}
+
+	#[test]
+	fn test_incremental_rereads_only_affected_forms() {
+		let text = "(a 1)\n(b\n  2)\n(c 3)\n(d 4)\n".to_string();
+		let mut formatter = IncrementalFormatter::new(text, PrinterConfig::default()).unwrap();
+		assert_eq!(edit(&mut formatter, "2", "22 (x y)"), 1);
+		assert_eq!(edit(&mut formatter, "(c 3)", "(c 3) (c2)"), 2);
+		assert_eq!(edit(&mut formatter, "\n(d", "(d"), 2);
+		assert_eq!(edit(&mut formatter, "(a 1)", ""), 0);
+		assert_eq!(formatter.text(), "\n(b\n  22 (x y))\n(c 3) (c2)(d 4)\n");
+	}
+
+	#[test]
+	fn test_incremental_blank_lines() {
+		let text = "(a 1)\n\n(b\n\n  2)\n(c 3)\n(d 4)\n".to_string();
+		let mut formatter = IncrementalFormatter::new(text, PrinterConfig::default()).unwrap();
+		assert_eq!(edit(&mut formatter, "\n(c", "\n\n\n(c"), 2);
+		assert_eq!(edit(&mut formatter, "\n\n(b", " (b"), 2);
+		assert_eq!(edit(&mut formatter, "\n\n  2", " 2"), 1);
+		// the form after the edit is not re-read, but the blank lines before it are
+		assert_eq!(edit(&mut formatter, "3)\n", "3)\n\n"), 1);
+		assert_eq!(
+			formatter.format().unwrap(),
+			"(a 1)\n(b 2)\n\n(c 3)\n\n(d 4)\n"
+		);
+	}
+
+	#[test]
+	fn test_incremental_top_level_comments() {
+		let text = "; header\n(a 1)\n;c\n(b 2)\n".to_string();
+		let mut formatter = IncrementalFormatter::new(text, PrinterConfig::default()).unwrap();
+		assert_eq!(formatter.format().unwrap(), "; header\n(a 1)\n;c\n(b 2)\n");
+		edit(&mut formatter, ";c\n", "\n;c\n\n");
+		edit(&mut formatter, "(a 1)", "(a  1) ; (");
+		assert_eq!(
+			formatter.format().unwrap(),
+			"; header\n(a 1) ; (\n\n;c\n\n(b 2)\n"
+		);
+	}
+
+	#[test]
+	fn test_incremental_line_endings() {
+		let text = "\u{FEFF}(a 1)\r\n(b\r\n  2)".to_string();
+		let mut formatter = IncrementalFormatter::new(text, PrinterConfig::default()).unwrap();
+		assert_eq!(formatter.format().unwrap(), "\u{FEFF}(a 1)\r\n(b 2)\r\n");
+		assert_eq!(edit(&mut formatter, "(a 1)", "(a\r\n\r\n 1)"), 1);
+		assert_eq!(
+			formatter.format().unwrap(),
+			"\u{FEFF}(a\r\n\r\n  1)\r\n(b 2)\r\n"
+		);
+	}
+
+	#[test]
+	fn test_incremental_bracket_changes() {
+		let text = "(a 1)\n(b 2)\n(c 3)\n".to_string();
+		let mut formatter = IncrementalFormatter::new(text, PrinterConfig::default()).unwrap();
+		// an unclosed list swallows the following forms...
+		assert!(
+			formatter
+				.apply_edit(&TextEdit {
+					range: 0..5,
+					new_text: "(a 1".to_string()
+				})
+				.is_err()
+		);
+		assert!(formatter.format().is_err());
+		// ...until it is closed again
+		assert_eq!(edit(&mut formatter, "(c 3)", "(c 3))"), 1);
+		assert_eq!(formatter.forms().count(), 1);
+	}
+
+	#[test]
+	fn test_incremental_invalid_edit() {
+		let mut formatter =
+			IncrementalFormatter::new("(é)".to_string(), PrinterConfig::default()).unwrap();
+		let res = formatter.apply_edit(&TextEdit {
+			range: 2..3,
+			new_text: String::new(),
+		});
+		assert!(matches!(res, Err(SexpfmtError::InvalidInput { .. })));
+	}
+}
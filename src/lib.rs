@@ -1,15 +1,43 @@
+mod arena;
+mod atom;
+mod borrowed;
+mod cst;
+mod diff;
+mod doc;
 mod error;
+mod format;
+mod incremental;
+#[cfg(feature = "lsp")]
+mod lsp;
 
 mod parser;
 mod printer;
+mod query;
 mod reader;
+mod reindent;
+mod rewrite;
 mod sexp;
-
+mod stack;
+
+pub use arena::*;
+pub use atom::*;
+pub use borrowed::*;
+pub use cst::*;
+pub use diff::*;
+pub use doc::*;
 pub use error::*;
+pub use format::*;
+pub use incremental::*;
+#[cfg(feature = "lsp")]
+pub use lsp::*;
 pub use parser::*;
 pub use printer::*;
+pub use query::*;
 pub use reader::*;
+pub use reindent::*;
+pub use rewrite::*;
 pub use sexp::*;
+use stack::*;
 
 #[cfg(test)]
 mod error_tests {
@@ -46,7 +74,7 @@ mod error_tests {
 	fn test_form_reader_error_with_position() {
 		let position = Loc::new(42, 3, 15);
 
-		let err = SexpfmtError::form_reader_error("Test error message", Some(position.clone()), None);
+		let err = SexpfmtError::form_reader_error("Test error message", Some(position), None);
 
 		let display_str = format!("{}", err);
 		assert!(display_str.contains("Form reader error at line 3, column 15 (offset 42)"));
@@ -67,7 +95,7 @@ mod error_tests {
 	fn test_parse_error_with_position() {
 		let position = Loc::new(10, 2, 5);
 
-		let err = SexpfmtError::parse_error("Unexpected token", position.clone(), None);
+		let err = SexpfmtError::parse_error("Unexpected token", position, None);
 
 		let display_str = format!("{}", err);
 		assert!(display_str.contains("Parse error at line 2, column 5 (offset 10)"));
@@ -79,7 +107,7 @@ mod error_tests {
 		let position = Loc::new(5, 1, 6);
 
 		let err = SexpfmtError::mismatched_bookends(
-			position.clone(),
+			position,
 			SExpBookendStyle::Parentheses,
 			SExpBookendStyle::SquareBrackets,
 		);
@@ -94,7 +122,7 @@ mod error_tests {
 	fn test_unexpected_eof_error() {
 		let position = Loc::new(100, 5, 1);
 
-		let err = SexpfmtError::unexpected_eof(position.clone(), 3);
+		let err = SexpfmtError::unexpected_eof(position, 3);
 
 		let display_str = format!("{}", err);
 		assert!(display_str.contains("Unexpected EOF at line 5, column 1 (offset 100)"));
@@ -105,7 +133,7 @@ mod error_tests {
 	fn test_invalid_input_error() {
 		let position = Loc::new(25, 3, 10);
 
-		let err = SexpfmtError::invalid_input("Invalid character '@'", position.clone());
+		let err = SexpfmtError::invalid_input("Invalid character '@'", position);
 
 		let display_str = format!("{}", err);
 		assert!(display_str.contains("Invalid input at line 3, column 10 (offset 25)"));
@@ -0,0 +1,572 @@
+// A Language Server Protocol server, speaking JSON-RPC over a pair of streams (usually stdin and stdout).
+// Documents are synchronized in full on every change. Positions in the protocol count UTF-16 code units, while `Loc`s
+// count bytes, so every position goes through a `LineIndex` of its document.
+// Formatting uses the default `PrinterConfig`, except that the indentation follows the `FormattingOptions` that the
+// editor sends with each request.
+// A message that cannot be parsed is answered with a parse error, and the server goes on reading.
+
+use super::*;
+
+use serde_json::{Value, json};
+use std::collections::HashMap;
+use std::io;
+
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+/// Serves LSP requests read from `input` until the client sends `exit`.
+pub fn run_lsp_server<R: io::BufRead, W: io::Write>(mut input: R, mut output: W) -> Result<()> {
+	let mut server = LspServer::default();
+	while let Some(message) = read_message(&mut input)? {
+		let message = match message {
+			Ok(message) => message,
+			Err(e) => {
+				let response = json!({
+					"jsonrpc": "2.0",
+					"id": Value::Null,
+					"error": { "code": PARSE_ERROR, "message": e },
+				});
+				write_message(&mut output, &response)?;
+				continue;
+			}
+		};
+		let method = message["method"].as_str().unwrap_or_default();
+		let params = &message["params"];
+		match message.get("id") {
+			Some(id) => {
+				let response = match server.handle_request(method, params) {
+					Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+					Err((code, message)) => json!({
+						"jsonrpc": "2.0",
+						"id": id,
+						"error": { "code": code, "message": message },
+					}),
+				};
+				write_message(&mut output, &response)?;
+			}
+			None => {
+				if method == "exit" {
+					return Ok(());
+				}
+				for notification in server.handle_notification(method, params) {
+					write_message(&mut output, &notification)?;
+				}
+			}
+		}
+	}
+	Ok(())
+}
+
+// Reads the next message, or an error message if it is malformed. Fails only if `input` does.
+fn read_message<R: io::BufRead>(
+	input: &mut R,
+) -> Result<Option<std::result::Result<Value, String>>> {
+	let mut content_length = None;
+	loop {
+		let mut line = String::new();
+		if input.read_line(&mut line)? == 0 {
+			return Ok(None);
+		}
+		let line = line.trim_end();
+		if line.is_empty() {
+			break;
+		}
+		if let Some((name, value)) = line.split_once(':')
+			&& name.eq_ignore_ascii_case("Content-Length")
+		{
+			content_length = value.trim().parse::<usize>().ok();
+		}
+	}
+	let Some(content_length) = content_length else {
+		return Ok(Some(Err(
+			"LSP message without a Content-Length header".to_string(),
+		)));
+	};
+	let mut content = vec![0; content_length];
+	input.read_exact(&mut content)?;
+	Ok(Some(
+		serde_json::from_slice(&content).map_err(|e| e.to_string()),
+	))
+}
+
+fn write_message<W: io::Write>(output: &mut W, message: &Value) -> Result<()> {
+	let content = message.to_string();
+	write!(
+		output,
+		"Content-Length: {}\r\n\r\n{}",
+		content.len(),
+		content
+	)?;
+	output.flush()?;
+	Ok(())
+}
+
+#[derive(Default)]
+struct LspServer {
+	documents: HashMap<String, String>,
+}
+
+type RequestResult = std::result::Result<Value, (i64, String)>;
+
+impl LspServer {
+	fn handle_request(&mut self, method: &str, params: &Value) -> RequestResult {
+		match method {
+			"initialize" => Ok(json!({
+				"capabilities": {
+					"textDocumentSync": 1,
+					"documentFormattingProvider": true,
+					"documentRangeFormattingProvider": true,
+					"documentOnTypeFormattingProvider": {
+						"firstTriggerCharacter": ")",
+						"moreTriggerCharacter": ["]", "}"],
+					},
+					"foldingRangeProvider": true,
+					"selectionRangeProvider": true,
+				},
+				"serverInfo": { "name": "sexpfmt", "version": env!("CARGO_PKG_VERSION") },
+			})),
+			"shutdown" => Ok(Value::Null),
+			"textDocument/formatting" => {
+				let text = self.document(params)?;
+				Ok(match format_str(text, &formatting_config(params)) {
+					Ok(formatted) if formatted == *text => json!([]),
+					Ok(formatted) => {
+						let index = LineIndex::new(text);
+						json!([{ "range": index.range(0, text.len()), "newText": formatted }])
+					}
+					Err(_) => Value::Null,
+				})
+			}
+			"textDocument/rangeFormatting" => {
+				let text = self.document(params)?;
+				let index = LineIndex::new(text);
+				let start = index.offset(&params["range"]["start"]);
+				let end = index.offset(&params["range"]["end"]);
+				Ok(format_forms_in_range(
+					text,
+					&index,
+					start,
+					end,
+					&formatting_config(params),
+				))
+			}
+			"textDocument/onTypeFormatting" => {
+				// format the top-level form that the typed character closed
+				let text = self.document(params)?;
+				let index = LineIndex::new(text);
+				let offset = index.offset(&params["position"]).saturating_sub(1);
+				Ok(format_forms_in_range(
+					text,
+					&index,
+					offset,
+					offset,
+					&formatting_config(params),
+				))
+			}
+			"textDocument/foldingRange" => {
+				let text = self.document(params)?;
+				let index = LineIndex::new(text);
+				let mut ranges = Vec::new();
+				if let Ok(loc_trees) = read_loc_trees(text) {
+					for loc_tree in loc_trees.iter() {
+						collect_folding_ranges(loc_tree, &index, &mut ranges);
+					}
+				}
+				Ok(Value::Array(ranges))
+			}
+			"textDocument/selectionRange" => {
+				let text = self.document(params)?;
+				let index = LineIndex::new(text);
+				let Ok(loc_trees) = read_loc_trees(text) else {
+					return Ok(Value::Null);
+				};
+				let positions = params["positions"].as_array().cloned().unwrap_or_default();
+				let ranges = positions
+					.iter()
+					.map(|position| selection_range(&loc_trees, &index, index.offset(position)))
+					.collect();
+				Ok(Value::Array(ranges))
+			}
+			_ => Err((METHOD_NOT_FOUND, format!("Unsupported method '{}'", method))),
+		}
+	}
+
+	// Returns the notifications to send in response.
+	fn handle_notification(&mut self, method: &str, params: &Value) -> Vec<Value> {
+		let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+		match method {
+			"textDocument/didOpen" => {
+				let text = params["textDocument"]["text"].as_str().unwrap_or_default();
+				self.documents.insert(uri.to_string(), text.to_string());
+				vec![self.diagnostics(uri)]
+			}
+			"textDocument/didChange" => {
+				// with full synchronization, the last change holds the whole document
+				let changes = params["contentChanges"].as_array();
+				if let Some(text) = changes.and_then(|c| c.last()?["text"].as_str()) {
+					self.documents.insert(uri.to_string(), text.to_string());
+				}
+				vec![self.diagnostics(uri)]
+			}
+			"textDocument/didClose" => {
+				self.documents.remove(uri);
+				vec![json!({
+					"jsonrpc": "2.0",
+					"method": "textDocument/publishDiagnostics",
+					"params": { "uri": uri, "diagnostics": [] },
+				})]
+			}
+			_ => Vec::new(),
+		}
+	}
+
+	fn document(&self, params: &Value) -> std::result::Result<&String, (i64, String)> {
+		let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+		self
+			.documents
+			.get(uri)
+			.ok_or_else(|| (INVALID_PARAMS, format!("Unknown document '{}'", uri)))
+	}
+
+	fn diagnostics(&self, uri: &str) -> Value {
+		let text = self
+			.documents
+			.get(uri)
+			.map(String::as_str)
+			.unwrap_or_default();
+		let diagnostics = match read_loc_trees(text) {
+			Ok(_) => Vec::new(),
+			Err(e) => {
+				let index = LineIndex::new(text);
+				let start = e.position().map_or(0, |p| p.offset());
+				let end = text
+					.get(start..)
+					.and_then(|rest| rest.chars().next())
+					.map_or(start, |c| start + c.len_utf8());
+				vec![json!({
+					"range": index.range(start, end),
+					"severity": 1,
+					"source": "sexpfmt",
+					"message": e.to_string(),
+				})]
+			}
+		};
+		json!({
+			"jsonrpc": "2.0",
+			"method": "textDocument/publishDiagnostics",
+			"params": { "uri": uri, "diagnostics": diagnostics },
+		})
+	}
+}
+
+// The configuration for a formatting request, indenting as its `FormattingOptions` say: by `tabSize` spaces, or by a
+// tab per level if `insertSpaces` is false.
+fn formatting_config(params: &Value) -> PrinterConfig {
+	let options = &params["options"];
+	let default = PrinterConfig::default();
+	let Some(tab_size) = options["tabSize"]
+		.as_u64()
+		.and_then(|n| i32::try_from(n).ok())
+		.filter(|&n| n > 0)
+	else {
+		return default;
+	};
+	let indent_style = match options["insertSpaces"].as_bool() {
+		Some(false) => IndentStyle::Tabs(tab_size),
+		_ => IndentStyle::Spaces,
+	};
+	PrinterConfig {
+		indent_width: tab_size,
+		indent_style,
+		..default
+	}
+}
+
+// Returns LSP edits that reformat each top-level form overlapping the bytes `start..end`, or null if the document
+// cannot be parsed.
+fn format_forms_in_range(
+	text: &str,
+	index: &LineIndex,
+	start: usize,
+	end: usize,
+	config: &PrinterConfig,
+) -> Value {
+	match format_range_edits(text, start..end, config) {
+		Ok(edits) => edits
+			.into_iter()
+			.map(
+				|(span, formatted)| json!({ "range": index.range(span.start, span.end), "newText": formatted }),
+			)
+			.collect(),
+		Err(_) => Value::Null,
+	}
+}
+
+// Reads the locations of every node of the document, failing at its first error.
+fn read_loc_trees(text: &str) -> Result<Vec<LocTree>> {
+	let mut reader = FormReader::new(text.as_bytes())?;
+	let mut loc_trees = Vec::new();
+	while let Some((s, position)) = reader.get()? {
+		let form = parse_form_with_locs(s, position)?;
+		loc_trees.extend(form.into_iter().map(|(_, loc_tree)| loc_tree));
+	}
+	Ok(loc_trees)
+}
+
+fn collect_folding_ranges(loc_tree: &LocTree, index: &LineIndex, ranges: &mut Vec<Value>) {
+	let start_line = index.position(loc_tree.loc.offset()).0;
+	let end_line = index.position(loc_tree.end.offset()).0;
+	if end_line > start_line {
+		ranges.push(json!({ "startLine": start_line, "endLine": end_line }));
+	}
+	for child in loc_tree.children.iter() {
+		collect_folding_ranges(child, index, ranges);
+	}
+}
+
+// Returns the chain of nodes enclosing `offset`, as an LSP `SelectionRange`.
+fn selection_range(loc_trees: &[LocTree], index: &LineIndex, offset: usize) -> Value {
+	let mut enclosing = Vec::new();
+	let contains =
+		|loc_tree: &LocTree| loc_tree.loc.offset() <= offset && offset < loc_tree.end.offset();
+	let mut candidates: Vec<&LocTree> = loc_trees.iter().collect();
+	while let Some(loc_tree) = candidates.into_iter().find(|loc_tree| contains(loc_tree)) {
+		enclosing.push(loc_tree);
+		candidates = loc_tree.children.iter().collect();
+	}
+
+	// wrap each range in the next inner one, so that the innermost range comes first
+	let mut res: Option<Value> = None;
+	for loc_tree in enclosing.into_iter() {
+		let range = index.range(loc_tree.loc.offset(), loc_tree.end.offset());
+		res = Some(match res {
+			None => json!({ "range": range }),
+			Some(parent) => json!({ "range": range, "parent": parent }),
+		});
+	}
+	res.unwrap_or_else(|| json!({ "range": index.range(offset, offset) }))
+}
+
+// Converts between byte offsets and LSP positions (zero-based lines, and columns in UTF-16 code units).
+struct LineIndex<'a> {
+	text: &'a str,
+	line_starts: Vec<usize>,
+}
+
+impl<'a> LineIndex<'a> {
+	fn new(text: &'a str) -> Self {
+		let line_starts = std::iter::once(0).chain(line_starts(text)).collect();
+		Self { text, line_starts }
+	}
+
+	fn position(&self, offset: usize) -> (usize, usize) {
+		let offset = offset.min(self.text.len());
+		let line = self.line_starts.partition_point(|&start| start <= offset) - 1;
+		let column = self.text[self.line_starts[line]..offset]
+			.encode_utf16()
+			.count();
+		(line, column)
+	}
+
+	fn range(&self, start: usize, end: usize) -> Value {
+		let (start_line, start_column) = self.position(start);
+		let (end_line, end_column) = self.position(end);
+		json!({
+			"start": { "line": start_line, "character": start_column },
+			"end": { "line": end_line, "character": end_column },
+		})
+	}
+
+	fn offset(&self, position: &Value) -> usize {
+		let line = position["line"].as_u64().unwrap_or_default() as usize;
+		let column = position["character"].as_u64().unwrap_or_default() as usize;
+		let Some(&line_start) = self.line_starts.get(line) else {
+			return self.text.len();
+		};
+		let mut units = 0;
+		for (i, c) in self.text[line_start..].char_indices() {
+			if units >= column || c == '\n' || c == '\r' {
+				return line_start + i;
+			}
+			units += c.len_utf16();
+		}
+		self.text.len()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn frame(message: Value) -> String {
+		let content = message.to_string();
+		format!("Content-Length: {}\r\n\r\n{}", content.len(), content)
+	}
+
+	// Runs a session that opens `text` as `file:///a.sexp`, then sends `requests`, returning every message sent back.
+	fn session(text: &str, requests: Vec<(&str, Value)>) -> Vec<Value> {
+		let mut input = frame(json!({
+			"jsonrpc": "2.0",
+			"method": "textDocument/didOpen",
+			"params": { "textDocument": { "uri": "file:///a.sexp", "text": text } },
+		}));
+		for (id, (method, mut params)) in requests.into_iter().enumerate() {
+			params["textDocument"] = json!({ "uri": "file:///a.sexp" });
+			input += &frame(json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params }));
+		}
+		input += &frame(json!({ "jsonrpc": "2.0", "method": "exit" }));
+
+		let mut output = Vec::new();
+		run_lsp_server(input.as_bytes(), &mut output).unwrap();
+		let mut output = output.as_slice();
+		let mut messages = Vec::new();
+		while let Some(message) = read_message(&mut output).unwrap() {
+			messages.push(message.unwrap());
+		}
+		messages
+	}
+
+	#[test]
+	fn test_lsp_formatting() {
+		let messages = session("(a\n  b)\n", vec![("textDocument/formatting", json!({}))]);
+		assert_eq!(messages[0]["params"]["diagnostics"], json!([]));
+		assert_eq!(
+			messages[1]["result"],
+			json!([{
+				"range": { "start": { "line": 0, "character": 0 }, "end": { "line": 2, "character": 0 } },
+				"newText": "(a b)\n",
+			}])
+		);
+	}
+
+	#[test]
+	fn test_lsp_formatting_options() {
+		let text =
+			"(define (f x)\n(g x-long-argument-name y-long-argument-name z-long-argument-name))\n";
+		let tabs = json!({ "options": { "tabSize": 4, "insertSpaces": false } });
+		let spaces = json!({ "options": { "tabSize": 4, "insertSpaces": true } });
+		let messages = session(
+			text,
+			vec![
+				("textDocument/formatting", tabs),
+				("textDocument/formatting", spaces),
+			],
+		);
+		let config = PrinterConfig {
+			indent_width: 4,
+			indent_style: IndentStyle::Tabs(4),
+			..PrinterConfig::default()
+		};
+		let formatted = format_str(text, &config).unwrap();
+		assert!(formatted.contains("\n\t("));
+		assert_eq!(messages[1]["result"][0]["newText"], formatted);
+		let config = PrinterConfig {
+			indent_width: 4,
+			..PrinterConfig::default()
+		};
+		let formatted = format_str(text, &config).unwrap();
+		assert!(formatted.contains("\n    ("));
+		assert_eq!(messages[2]["result"][0]["newText"], formatted);
+	}
+
+	#[test]
+	fn test_lsp_parse_error() {
+		let mut input = "Content-Length: 3\r\n\r\n{]}".to_string();
+		input += &frame(json!({ "jsonrpc": "2.0", "id": 1, "method": "shutdown" }));
+		input += &frame(json!({ "jsonrpc": "2.0", "method": "exit" }));
+		let mut output = Vec::new();
+		run_lsp_server(input.as_bytes(), &mut output).unwrap();
+		let mut output = output.as_slice();
+		let error = read_message(&mut output).unwrap().unwrap().unwrap();
+		assert_eq!(error["id"], Value::Null);
+		assert_eq!(error["error"]["code"], json!(PARSE_ERROR));
+		let response = read_message(&mut output).unwrap().unwrap().unwrap();
+		assert_eq!(
+			response,
+			json!({ "jsonrpc": "2.0", "id": 1, "result": null })
+		);
+	}
+
+	#[test]
+	fn test_lsp_range_formatting() {
+		let range =
+			json!({ "start": { "line": 1, "character": 0 }, "end": { "line": 1, "character": 1 } });
+		let messages = session(
+			"(a\n  b)\n(c\n  d)\n",
+			vec![("textDocument/rangeFormatting", json!({ "range": range }))],
+		);
+		assert_eq!(
+			messages[1]["result"],
+			json!([{
+				"range": { "start": { "line": 0, "character": 0 }, "end": { "line": 1, "character": 4 } },
+				"newText": "(a b)",
+			}])
+		);
+		// lines may end with a lone carriage return
+		let messages = session(
+			"(a\r  b)\r(c\r  d)\r",
+			vec![("textDocument/rangeFormatting", json!({ "range": range }))],
+		);
+		assert_eq!(
+			messages[1]["result"][0]["range"],
+			json!({ "start": { "line": 0, "character": 0 }, "end": { "line": 1, "character": 4 } })
+		);
+	}
+
+	#[test]
+	fn test_lsp_diagnostics() {
+		let messages = session("(a)\n(b ]", vec![]);
+		let diagnostics = &messages[0]["params"]["diagnostics"];
+		assert_eq!(diagnostics.as_array().unwrap().len(), 1);
+		assert_eq!(
+			diagnostics[0]["range"]["start"],
+			json!({ "line": 1, "character": 0 })
+		);
+		assert!(
+			diagnostics[0]["message"]
+				.as_str()
+				.unwrap()
+				.contains("Mismatched bookends")
+		);
+	}
+
+	#[test]
+	fn test_lsp_folding_and_selection_ranges() {
+		let text = "(a\n  (b\n    c))";
+		let position = json!({ "line": 2, "character": 4 });
+		let messages = session(
+			text,
+			vec![
+				("textDocument/foldingRange", json!({})),
+				(
+					"textDocument/selectionRange",
+					json!({ "positions": [position] }),
+				),
+			],
+		);
+		assert_eq!(
+			messages[1]["result"],
+			json!([{ "startLine": 0, "endLine": 2 }, { "startLine": 1, "endLine": 2 }])
+		);
+
+		let selection = &messages[2]["result"][0];
+		assert_eq!(
+			selection["range"]["start"],
+			json!({ "line": 2, "character": 4 })
+		);
+		assert_eq!(
+			selection["range"]["end"],
+			json!({ "line": 2, "character": 5 })
+		);
+		assert_eq!(
+			selection["parent"]["range"]["start"],
+			json!({ "line": 1, "character": 2 })
+		);
+		assert_eq!(
+			selection["parent"]["parent"]["range"]["start"],
+			json!({ "line": 0, "character": 0 })
+		);
+		assert_eq!(selection["parent"]["parent"]["parent"], Value::Null);
+	}
+}
@@ -3,20 +3,164 @@ use sexpfmt::*;
 use std::error::Error;
 use std::io::Write;
 
-fn main_inner() -> Result<()> {
+const USAGE: &str = "USAGE:
+  sexpfmt --max-depth N ...  # accepts lists nested up to N deep (4096 by default) in the commands below
+  sexpfmt                 # formats S-expressions from stdin to stdout
+  sexpfmt --check         # exits with 1, naming the first line that would change, if stdin is not formatted
+  sexpfmt --lines A:B     # formats only the top-level forms overlapping lines A through B (counting from 1)
+  sexpfmt --bytes A:B     # formats only the top-level forms overlapping bytes A up to B (counting from 0)
+  sexpfmt --reindent      # fixes only the indentation and trailing whitespace of each line from stdin
+  sexpfmt diff OLD NEW    # prints structural differences between two files
+  sexpfmt query EXPR      # prints each subtree from stdin selected by EXPR, e.g. '**/(quantity _)'
+  sexpfmt lsp             # runs a Language Server Protocol server over stdin and stdout
+  sexpfmt rewrite RULES   # rewrites S-expressions from stdin, e.g. '(old ?x ...) => (new ?x ...)'";
+
+fn main_inner(args: &[String]) -> Result<i32> {
+	let mut config = PrinterConfig::default();
+	let args = match args {
+		[flag, max_depth, rest @ ..] if flag == "--max-depth" => match max_depth.parse() {
+			Ok(max_depth) => {
+				config.max_depth = max_depth;
+				rest
+			}
+			Err(_) => return invalid_arguments(),
+		},
+		_ => args,
+	};
+	match args {
+		[] => {
+			format_stdin(&config)?;
+			Ok(0)
+		}
+		[flag] if flag == "--check" => check_stdin(&config),
+		[flag, range] if flag == "--lines" || flag == "--bytes" => match parse_range(range) {
+			Some((start, end)) => {
+				format_stdin_range(flag == "--lines", start, end, &config)?;
+				Ok(0)
+			}
+			None => invalid_arguments(),
+		},
+		[flag] if flag == "--reindent" => {
+			reindent_stdin(&config)?;
+			Ok(0)
+		}
+		[cmd, old, new] if cmd == "diff" => diff_files(old, new, config.max_depth),
+		[cmd, expr] if cmd == "query" => {
+			query_stdin(&expr.parse()?, config.max_depth)?;
+			Ok(0)
+		}
+		#[cfg(feature = "lsp")]
+		[cmd] if cmd == "lsp" => {
+			run_lsp_server(std::io::stdin().lock(), std::io::stdout())?;
+			Ok(0)
+		}
+		[cmd, rules @ ..] if cmd == "rewrite" && !rules.is_empty() => {
+			rewrite_stdin(&rules.join("\n").parse()?, &config)?;
+			Ok(0)
+		}
+		_ => invalid_arguments(),
+	}
+}
+
+fn invalid_arguments() -> Result<i32> {
+	eprintln!("ERROR: Invalid arguments.");
+	eprintln!("{USAGE}");
+	Ok(1)
+}
+
+fn parse_range(range: &str) -> Option<(usize, usize)> {
+	let (start, end) = range.split_once(':')?;
+	let (start, end) = (start.parse().ok()?, end.parse().ok()?);
+	(start <= end).then_some((start, end))
+}
+
+fn format_stdin(config: &PrinterConfig) -> Result<()> {
+	format_stream(std::io::stdin(), std::io::stdout(), config)
+}
+
+fn check_stdin(config: &PrinterConfig) -> Result<i32> {
+	let mut text = String::new();
+	std::io::Read::read_to_string(&mut std::io::stdin(), &mut text)?;
+	let formatted = format_str(&text, config)?;
+	if formatted == text {
+		return Ok(0);
+	}
+	// lines are compared with their line breaks, so that a missing or extra one counts
+	let line = text
+		.split_inclusive('\n')
+		.zip(formatted.split_inclusive('\n'))
+		.take_while(|(line, formatted_line)| line == formatted_line)
+		.count()
+		+ 1;
+	eprintln!("stdin:{line}: not formatted");
+	Ok(1)
+}
+
+fn format_stdin_range(
+	is_line_range: bool,
+	start: usize,
+	end: usize,
+	config: &PrinterConfig,
+) -> Result<()> {
+	let mut text = String::new();
+	std::io::Read::read_to_string(&mut std::io::stdin(), &mut text)?;
+	let range = if is_line_range {
+		line_range_to_byte_range(&text, start, end)
+	} else {
+		start..end
+	};
+	print!("{}", format_range(&text, range, config)?);
+	Ok(())
+}
+
+fn reindent_stdin(config: &PrinterConfig) -> Result<()> {
+	let mut text = String::new();
+	std::io::Read::read_to_string(&mut std::io::stdin(), &mut text)?;
+	print!("{}", reindent_str(&text, config)?);
+	Ok(())
+}
+
+fn query_stdin(query: &Query, max_depth: usize) -> Result<()> {
 	let mut reader = FormReader::new(std::io::stdin())?;
+	reader.set_max_depth(max_depth);
 	while let Some((s, position)) = reader.get()? {
-		let output = parse_form(s, position)?;
-		print_sexp(output);
-		println!();
+		let output = with_form(s, position, max_depth, |forms| {
+			let config = PrinterConfig::default();
+			query
+				.select(&forms)
+				.into_iter()
+				.map(|sexp| format_sexp(sexp, &config) + "\n")
+				.collect::<String>()
+		})?;
+		print!("{output}");
 		std::io::stdout().flush()?;
 	}
 	Ok(())
 }
 
+fn rewrite_stdin(rules: &RewriteRules, config: &PrinterConfig) -> Result<()> {
+	let mut text = String::new();
+	std::io::Read::read_to_string(&mut std::io::stdin(), &mut text)?;
+	print!("{}", rules.rewrite_str(&text, config)?);
+	Ok(())
+}
+
+fn diff_files(old: &str, new: &str, max_depth: usize) -> Result<i32> {
+	let (old, new) = (std::fs::File::open(old)?, std::fs::File::open(new)?);
+	let output = with_document_diff(old, new, max_depth, |changes| {
+		changes
+			.iter()
+			.map(|change| format!("{change}\n"))
+			.collect::<String>()
+	})?;
+	print!("{output}");
+	Ok(if output.is_empty() { 0 } else { 1 })
+}
+
 fn main() {
-	match main_inner() {
-		Ok(()) => {}
+	let args: Vec<String> = std::env::args().skip(1).collect();
+	match main_inner(&args) {
+		Ok(exit_code) => std::process::exit(exit_code),
 		Err(e) => {
 			eprintln!("ERROR: {e}");
 
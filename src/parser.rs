@@ -1,31 +1,128 @@
 use super::*;
 
+use nom::Finish;
 use nom::branch::*;
 use nom::bytes::complete::*;
 use nom::character::complete::*;
 use nom::combinator::*;
 use nom::multi::*;
 use nom::sequence::*;
-use nom::Finish;
 
 use nom_locate::LocatedSpan;
 
-type LocSpan<'a> = LocatedSpan<&'a str>;
-type IResult<'a, T> = nom::IResult<LocSpan<'a>, T>;
+pub(crate) type LocSpan<'a> = LocatedSpan<&'a str>;
+pub(crate) type IResult<'a, T> = nom::IResult<LocSpan<'a>, T>;
+
+/// The deepest nesting of lists accepted by default. The parser and printer recurse on nested lists, and run on a
+/// thread with a stack sized for deeply nested input, so deeper input is rejected with `SexpfmtError::InvalidInput`
+/// to bound the memory that takes.
+pub const DEFAULT_MAX_DEPTH: usize = 4096;
 
 pub fn parse_form(text: String, start_of_form_loc: Loc) -> Result<Vec<SExp>> {
-	let located_span = LocSpan::new(text.as_str());
+	parse_str(text.as_str(), start_of_form_loc, DEFAULT_MAX_DEPTH)
+}
+
+/// Like `parse_form`, but accepting lists nested up to `max_depth` deep. Beware that each level of nesting takes
+/// stack space when the result is dropped.
+pub fn parse_form_with_max_depth(
+	text: String,
+	start_of_form_loc: Loc,
+	max_depth: usize,
+) -> Result<Vec<SExp>> {
+	parse_str(text.as_str(), start_of_form_loc, max_depth)
+}
+
+/// The location of a parsed node, along with the locations of its elements if it is a list, and the comments around it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LocTree {
+	pub loc: Loc,
+	/// The location just past the end of the node.
+	pub end: Loc,
+	/// The number of blank lines between the node and what comes before it in its list or form, or the last of
+	/// `comments_before`.
+	pub blank_lines_before: usize,
+	/// The comments on lines of their own between the node and what comes before it in its list or form.
+	pub comments_before: Vec<Comment>,
+	/// The comment after the node on the same line, if any, from its `;` up to the end of the line.
+	pub comment_after: Option<String>,
+	/// The comments on lines of their own after the last element of a list, before its closing bracket.
+	pub comments_at_end: Vec<Comment>,
+	pub children: Vec<LocTree>,
+}
+
+/// A line comment on a line of its own.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Comment {
+	/// From the `;` up to the end of the line.
+	pub text: String,
+	/// The number of blank lines between the comment and what comes before it.
+	pub blank_lines_before: usize,
+}
+
+/// Like `parse_form`, but also reports where each node of each parsed form starts, and the comments around it.
+pub fn parse_form_with_locs(text: String, start_of_form_loc: Loc) -> Result<Vec<(SExp, LocTree)>> {
+	parse_form_with_locs_and_max_depth(text, start_of_form_loc, DEFAULT_MAX_DEPTH)
+}
+
+pub(crate) fn parse_form_with_locs_and_max_depth(
+	text: String,
+	start_of_form_loc: Loc,
+	max_depth: usize,
+) -> Result<Vec<(SExp, LocTree)>> {
+	with_located_form(text, start_of_form_loc, max_depth, |form| form)
+}
+
+/// Parses a form as `parse_form_with_locs_and_max_depth` does, then hands it to `f`, on a thread with the stack for both
+/// even if the form is deeply nested, so that `f` can drop it as well.
+pub(crate) fn with_located_form<T: Send>(
+	text: String,
+	start_of_form_loc: Loc,
+	max_depth: usize,
+	f: impl FnOnce(Vec<(SExp, LocTree)>) -> T + Send,
+) -> Result<T> {
+	let text = text.as_str();
+	let depth = check_depth(text, start_of_form_loc, max_depth)?;
+	with_stack_for_depth(depth, || {
+		let nodes = parse_cst_at(text, start_of_form_loc, max_depth)?;
+		let mut cursor = LocCursor::new(text, start_of_form_loc);
+		let (form, _) = locate_cst(&nodes, &mut 0, &mut cursor);
+		Ok(f(form))
+	})?
+}
+
+/// Parses a form as `parse_form_with_max_depth` does, then hands it to `f`, on a thread with the stack for both even if
+/// the form is deeply nested, so that `f` can walk and drop it as well.
+pub fn with_form<T: Send>(
+	text: String,
+	start_of_form_loc: Loc,
+	max_depth: usize,
+	f: impl FnOnce(Vec<SExp>) -> T + Send,
+) -> Result<T> {
+	let text = text.as_str();
+	let depth = check_depth(text, start_of_form_loc, max_depth)?;
+	with_stack_for_depth(depth, || Ok(f(parse_nested(text, start_of_form_loc)?)))?
+}
+
+fn parse_str(text: &str, start_of_form_loc: Loc, max_depth: usize) -> Result<Vec<SExp>> {
+	let depth = check_depth(text, start_of_form_loc, max_depth)?;
+	with_stack_for_depth(depth, || parse_nested(text, start_of_form_loc))?
+}
+
+// Parses text that `check_depth` has accepted, recursing on each level of nesting
+fn parse_nested(text: &str, start_of_form_loc: Loc) -> Result<Vec<SExp>> {
+	let located_span = LocSpan::new(text);
 	let res = file(located_span).finish().map_err(|e| {
 		SexpfmtError::parse_error(
 			format!("Parse error: {:?}", e.code),
-			Loc::in_form(start_of_form_loc, e.input),
+			Loc::in_form(start_of_form_loc, text, e.input.location_offset()),
 			None,
 		)
 	})?;
 
 	let (remaining_span, form_vec) = res;
 	if !remaining_span.is_empty() {
-		let remaining_span_loc = Loc::in_form(start_of_form_loc, remaining_span);
+		let remaining_span_loc =
+			Loc::in_form(start_of_form_loc, text, remaining_span.location_offset());
 		return Err(SexpfmtError::parse_error(
 			format!("Unexpected input: '{}'", remaining_span.fragment()),
 			remaining_span_loc,
@@ -36,6 +133,31 @@ pub fn parse_form(text: String, start_of_form_loc: Loc) -> Result<Vec<SExp>> {
 	Ok(form_vec)
 }
 
+// Rejects text nested too deeply before the recursive parser sees it, returning how deeply it is nested otherwise.
+// Other errors are left to the parser to report.
+pub(crate) fn check_depth(text: &str, start_of_form_loc: Loc, max_depth: usize) -> Result<usize> {
+	let mut scanner = Scanner::new(text);
+	let mut depth = 0;
+	let mut deepest = 0;
+	while let Ok(Some((start, token))) = scanner.next_token() {
+		match token {
+			Token::Open(_) if depth == max_depth => {
+				return Err(SexpfmtError::nesting_too_deep(
+					Loc::in_form(start_of_form_loc, text, start),
+					max_depth,
+				));
+			}
+			Token::Open(_) => {
+				depth += 1;
+				deepest = deepest.max(depth);
+			}
+			Token::Close(_) => depth = depth.saturating_sub(1),
+			Token::Atom(_) => {}
+		}
+	}
+	Ok(deepest)
+}
+
 fn file(input: LocSpan) -> IResult<Vec<SExp>> {
 	map(
 		tuple((many0(sexp_with_opt_prefix_space), opt(nonempty_skip))),
@@ -64,8 +186,8 @@ fn basic_list(
 ) -> impl FnMut(LocSpan) -> IResult<SExp> {
 	move |input| {
 		map(
-			tuple((char(lp), opt(sexp_seq), char(rp))),
-			|(lp, res, _)| match res {
+			tuple((char(lp), opt(sexp_seq), opt(nonempty_skip), char(rp))),
+			|(lp, res, _, _)| match res {
 				Some(terms) => SExp::List(terms, sexp_bookend_style),
 				None => SExp::Null(match lp {
 					'(' => SExpBookendStyle::Parentheses,
@@ -84,8 +206,8 @@ fn sexp_seq(input: LocSpan) -> IResult<Vec<SExp>> {
 	)(input)
 }
 
-fn atom(input: LocSpan) -> IResult<SExp> {
-	map(alt((simple_atom, string_atom)), SExp::Atom)(input)
+pub(crate) fn atom(input: LocSpan) -> IResult<SExp> {
+	map(alt((simple_atom, string_atom)), SExp::atom)(input)
 }
 fn simple_atom(input: LocSpan) -> IResult<String> {
 	map(many1(none_of("\"\n\r\t ()[]{};")), |x| {
@@ -126,7 +248,7 @@ fn string_escape_element(input: LocSpan) -> IResult<[char; 2]> {
 pub fn nonempty_skip(input: LocSpan) -> IResult<()> {
 	map(many1(alt((multispace1, line_comment))), |_| ())(input)
 }
-fn line_comment(input: LocSpan) -> IResult<LocSpan> {
+pub(crate) fn line_comment(input: LocSpan) -> IResult<LocSpan> {
 	map(
 		tuple((char(';'), take_till(|c| c == '\n' || c == '\r'))),
 		|pair: (char, LocSpan)| {
@@ -148,10 +270,7 @@ mod tests {
 		let position = Loc::new(0, 1, 1);
 		assert_eq!(
 			parse_form("; a simple message\nhello world".to_string(), position).unwrap(),
-			vec![
-				SExp::Atom("hello".to_string()),
-				SExp::Atom("world".to_string()),
-			]
+			vec![SExp::atom("hello"), SExp::atom("world"),]
 		);
 	}
 
@@ -161,9 +280,9 @@ mod tests {
 		assert_eq!(
 			parse_form("1234 .567 123.9870".to_string(), position).unwrap(),
 			vec![
-				SExp::Atom("1234".to_string()),
-				SExp::Atom(".567".to_string()),
-				SExp::Atom("123.9870".to_string()),
+				SExp::atom("1234"),
+				SExp::atom(".567"),
+				SExp::atom("123.9870"),
 			]
 		);
 	}
@@ -174,7 +293,7 @@ mod tests {
 		let position = Loc::new(0, 1, 1);
 		assert_eq!(
 			parse_form(s.to_string(), position).unwrap(),
-			vec![SExp::Atom(s.to_string())]
+			vec![SExp::atom(s)]
 		);
 	}
 
@@ -184,7 +303,7 @@ mod tests {
 		let position = Loc::new(0, 1, 1);
 		assert_eq!(
 			parse_form(s.to_string(), position).unwrap(),
-			vec![SExp::Atom(s.to_string())]
+			vec![SExp::atom(s)]
 		);
 	}
 
@@ -192,15 +311,15 @@ mod tests {
 	fn test_parse_atom_5() {
 		let position = Loc::new(0, 1, 1);
 		assert_eq!(
-			parse_form("()".into(), position.clone()).unwrap(),
+			parse_form("()".into(), position).unwrap(),
 			vec![SExp::Null(SExpBookendStyle::Parentheses)]
 		);
 		assert_eq!(
-			parse_form("[]".into(), position.clone()).unwrap(),
+			parse_form("[]".into(), position).unwrap(),
 			vec![SExp::Null(SExpBookendStyle::SquareBrackets)]
 		);
 		assert_eq!(
-			parse_form("{}".into(), position.clone()).unwrap(),
+			parse_form("{}".into(), position).unwrap(),
 			vec![SExp::Null(SExpBookendStyle::CurlyBraces)]
 		);
 	}
@@ -208,7 +327,7 @@ mod tests {
 	#[test]
 	fn test_parse_list_1() {
 		let s = r#"(hello world) [hello world] {hello world}"#;
-		let e = vec![SExp::Atom("hello".into()), SExp::Atom("world".into())];
+		let e = vec![SExp::atom("hello"), SExp::atom("world")];
 		let position = Loc::new(0, 1, 1);
 		assert_eq!(
 			parse_form(s.to_string(), position).unwrap(),
@@ -220,6 +339,103 @@ mod tests {
 		);
 	}
 
+	#[test]
+	fn test_parse_form_with_locs() {
+		let position = Loc::new(6, 2, 1);
+		let res = parse_form_with_locs("(a\n  ; note\n  [b c]) d".to_string(), position).unwrap();
+		assert_eq!(res.len(), 2);
+
+		let (_, list_locs) = &res[0];
+		assert_eq!(list_locs.loc, Loc::new(6, 2, 1));
+		assert_eq!(list_locs.end, Loc::new(26, 4, 9));
+		assert_eq!(list_locs.children[0].loc, Loc::new(7, 2, 2));
+		assert_eq!(list_locs.children[1].loc, Loc::new(20, 4, 3));
+		assert_eq!(list_locs.children[1].children[1].loc, Loc::new(23, 4, 6));
+
+		let (atom, atom_locs) = &res[1];
+		assert_eq!(atom, &SExp::atom("d"));
+		assert_eq!(atom_locs.loc, Loc::new(27, 4, 10));
+		assert_eq!(atom_locs.end, Loc::new(28, 4, 11));
+	}
+
+	#[test]
+	fn test_parse_form_with_locs_blank_lines() {
+		let res = parse_form_with_locs(
+			"(a\n\n  b ; note\n\n\n  ; more\n  c d)".to_string(),
+			Loc::new(0, 1, 1),
+		)
+		.unwrap();
+		let blank_lines: Vec<usize> = res[0]
+			.1
+			.children
+			.iter()
+			.map(|child| child.blank_lines_before)
+			.collect();
+		// the blank lines before `; more` are its own
+		assert_eq!(blank_lines, vec![0, 1, 0, 0]);
+		let children = &res[0].1.children;
+		assert_eq!(children[1].comment_after.as_deref(), Some("; note"));
+		assert_eq!(
+			children[2].comments_before,
+			vec![Comment {
+				text: "; more".to_string(),
+				blank_lines_before: 2,
+			}]
+		);
+		let res = parse_form_with_locs("(a\r\r b\r\n\r\n c)".to_string(), Loc::new(0, 1, 1)).unwrap();
+		let blank_lines: Vec<usize> = res[0]
+			.1
+			.children
+			.iter()
+			.map(|child| child.blank_lines_before)
+			.collect();
+		assert_eq!(blank_lines, vec![0, 1, 1]);
+	}
+
+	#[test]
+	fn test_parse_max_depth() {
+		let position = Loc::new(10, 2, 5);
+		let text = format!("(a\n {}b{})", "[".repeat(4), "]".repeat(4));
+		assert_eq!(
+			parse_form_with_max_depth(text.clone(), position, 5)
+				.unwrap()
+				.len(),
+			1
+		);
+		assert!(matches!(
+			parse_form_with_max_depth(text, position, 4),
+			Err(SexpfmtError::InvalidInput { position, .. }) if position == Loc::new(17, 3, 5)
+		));
+
+		// tests run on threads with 2 MiB stacks
+		let deepest = format!(
+			"{}x{}",
+			"(".repeat(DEFAULT_MAX_DEPTH),
+			")".repeat(DEFAULT_MAX_DEPTH)
+		);
+		assert!(parse_form(deepest, position).is_ok());
+		let deep = format!("{}x{}", "(".repeat(100_000), ")".repeat(100_000));
+		assert!(matches!(
+			parse_form(deep, position),
+			Err(SexpfmtError::InvalidInput { .. })
+		));
+	}
+
+	#[test]
+	fn test_with_form() {
+		// the form is walked and dropped on a thread with the stack for it
+		let deep = format!("{}x{}", "(".repeat(10_000), ")".repeat(10_000));
+		let depths = with_form(deep, Loc::new(0, 1, 1), 10_000, |forms| {
+			forms.iter().map(SExp::depth).collect::<Vec<_>>()
+		});
+		assert_eq!(depths.unwrap(), vec![10_000]);
+		assert!(matches!(
+			with_form("((a))".to_string(), Loc::new(0, 1, 1), 1, |forms| forms
+				.len()),
+			Err(SexpfmtError::InvalidInput { .. })
+		));
+	}
+
 	#[test]
 	fn test_parse_error_with_location() {
 		let position = Loc::new(10, 2, 5);
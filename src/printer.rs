@@ -1,11 +1,7 @@
-// TODO: implement a printer using the following greedy algorithm:
-// Each term is to be printed at an indentation level.
-// We will recursively compute the length of a node in the tree such that one of the following holds true:
-// - the entire list is placed on a single line, including each element list.
-// - except for the first, each list entry is placed on a separate line with increased indentation level.
-// When an intermediate element of a list is pushed to the next line, it does not matter if it exceeds the margin if it
-// is an atom.
-// NOTE: an awkward case is when the first element of a list is also a list that may be broken multi-line.
+// Each S-expression is printed through a `Doc`, so that it is laid out with the help of what follows it on its line.
+// A list is a group: either all on a single line, or with each element after the first on a separate line, indented
+// by the indent width. Nested lists are only broken over several lines if their parent is.
+// NOTE: an awkward case is when the first element of a list is also a list that is broken over several lines.
 // E.g.
 //   ((this is a long list)
 //     arg1
@@ -18,127 +14,1062 @@
 //       list)
 //     arg1
 //     arg2 )
-// Note the insertion of a leading and trailing space.
+// Note the insertion of a leading and trailing space. See `MultilineHeads` for the alternatives.
+// The Lisp layout instead indents relative to the opening bracket of each list, following Emacs' conventions: the
+// arguments of a call are aligned under the first one, and special forms such as `let` keep a number of distinguished
+// arguments on the line of their head, with the rest of their arguments forming an indented body.
+// Either layout may fill some lists instead, putting as many elements on each line as fit, like the words of a
+// paragraph, or lay out lists of rows as tables, with the cells of each column padded to the same width.
 
 use super::*;
 
-const NULL_TEXT: &str = "()";
+use std::collections::{HashMap, HashSet};
+
 const INDENT_WIDTH: i32 = 2;
 const MARGIN_WIDTH: i32 = 80;
 
-enum PrintPlan {
-	Null,
-	Atom(i32),
-	List(i32, Vec<PrintPlan>, ListPrintPlan),
-}
-enum ListPrintPlan {
-	Monoline,
-	Multiline,
+/// Options controlling the layout of printed S-expressions.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PrinterConfig {
+	/// The width that lines should fit in, if possible.
+	pub margin_width: i32,
+	/// The number of columns by which each level of nesting is indented, unless indenting with tabs.
+	pub indent_width: i32,
+	pub indent_style: IndentStyle,
+	/// The deepest nesting of lists accepted when parsing input to format. Deeper input is rejected with
+	/// `SexpfmtError::InvalidInput`, as each level of nesting takes stack space.
+	pub max_depth: usize,
+	pub layout: Layout,
+	/// How `Layout::Lisp` indents lists, by their head symbol. Lists headed by other symbols are `IndentSpec::Hanging`.
+	pub indent_specs: HashMap<String, IndentSpec>,
+	/// Whether to fill lists that hold no lists, such as vectors of numbers.
+	pub fill_atom_lists: bool,
+	/// Lists headed by these symbols are filled, whatever they hold.
+	pub fill_heads: HashSet<String>,
+	/// Whether to lay out broken lists of rows as tables. Rows are lists with the same length and bookends, at least two
+	/// of them, following an optional leading atom. Each row is kept on a single line.
+	pub align_tables: bool,
+	pub closing_brackets: ClosingBrackets,
+	pub multiline_heads: MultilineHeads,
+	/// The most blank lines kept where the input has more, between top-level forms and between the elements of broken
+	/// lists. A list with blank lines between its elements is always broken, and neither filled nor laid out as a table.
+	pub max_blank_lines: usize,
+	/// Whether to put exactly one blank line between top-level forms, whatever the input has.
+	pub separate_top_level_forms: bool,
+	/// The line ending to write, or `None` for the first one in the input, falling back on `LineEnding::Lf`.
+	pub line_ending: Option<LineEnding>,
+	/// Whether to start the output with a UTF-8 byte order mark if the input starts with one. It is skipped when
+	/// reading either way.
+	pub keep_bom: bool,
+	pub final_newline: FinalNewline,
 }
-impl PrintPlan {
-	fn width(&self) -> i32 {
-		match self {
-			PrintPlan::Null => NULL_TEXT.len().try_into().unwrap(),
-			PrintPlan::Atom(w) => *w,
-			PrintPlan::List(w, _, _) => *w,
+impl Default for PrinterConfig {
+	fn default() -> Self {
+		Self {
+			margin_width: MARGIN_WIDTH,
+			indent_width: INDENT_WIDTH,
+			indent_style: IndentStyle::Spaces,
+			max_depth: DEFAULT_MAX_DEPTH,
+			layout: Layout::FixedStep,
+			indent_specs: default_indent_specs(),
+			fill_atom_lists: false,
+			fill_heads: HashSet::new(),
+			align_tables: false,
+			closing_brackets: ClosingBrackets::Stacked,
+			multiline_heads: MultilineHeads::Padded,
+			max_blank_lines: 1,
+			separate_top_level_forms: false,
+			line_ending: None,
+			keep_bom: true,
+			final_newline: FinalNewline::Always,
 		}
 	}
 }
 
+/// What indentation is made of.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IndentStyle {
+	#[default]
+	Spaces,
+	/// One tab per level of nesting, displayed the given number of columns wide, which must be positive. It takes the
+	/// place of `PrinterConfig::indent_width`. Alignment, as in the Lisp layout, is made of spaces after the tabs of the
+	/// line aligned to.
+	Tabs(i32),
+}
+
+/// Whether formatted text ends with a line break after its last form.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FinalNewline {
+	#[default]
+	Always,
+	/// Only if the input ends with one after its last form.
+	Preserve,
+	Never,
+}
+
+/// How the fixed-step layout breaks a list whose first element is a list broken over several lines as well.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MultilineHeads {
+	/// Pads the list with a space after its opening bracket and before its closing bracket, so that the elements of
+	/// the first element are indented past the other elements: `( (a\n      b)\n    c )`.
+	#[default]
+	Padded,
+	/// Puts the first element on a line of its own, like the other elements: `(\n  (a\n    b)\n  c)`.
+	OwnLine,
+	/// Leaves the list as it is: `((a\n    b)\n  c)`.
+	Block,
+}
+
+/// Where the closing bracket of a broken list goes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ClosingBrackets {
+	/// At the end of the last element, after any closing brackets of its own.
+	#[default]
+	Stacked,
+	/// On a line of its own, at the indentation of the line with the opening bracket in the fixed-step layout, and
+	/// under the opening bracket in the Lisp layout.
+	OwnLine,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Layout {
+	/// Each element of a broken list after the first on its own line, indented by the indent width.
+	#[default]
+	FixedStep,
+	/// Indentation relative to the opening bracket, as set out by `PrinterConfig::indent_specs`, for Lisp source.
+	Lisp,
+}
+
+/// How `Layout::Lisp` indents the arguments of a list, like Emacs' `lisp-indent-function`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IndentSpec {
+	/// The first argument stays on the line of the head, and the others are aligned under it.
+	Hanging,
+	/// A special form with the given number of distinguished arguments. The first stays on the line of the head, and
+	/// the others are indented by twice the indent width. The remaining arguments form the body, indented by the
+	/// indent width.
+	Special(usize),
+}
+
+/// The special forms of Scheme and Emacs Lisp.
+pub fn default_indent_specs() -> HashMap<String, IndentSpec> {
+	[
+		("begin", 0),
+		("case", 1),
+		("define", 1),
+		("define-syntax", 1),
+		("defmacro", 2),
+		("defun", 2),
+		("do", 2),
+		("if", 2),
+		("lambda", 1),
+		("let", 1),
+		("let*", 1),
+		("let-values", 1),
+		("letrec", 1),
+		("letrec*", 1),
+		("parameterize", 1),
+		("syntax-rules", 1),
+		("unless", 1),
+		("when", 1),
+	]
+	.into_iter()
+	.map(|(head, n)| (head.to_string(), IndentSpec::Special(n)))
+	.collect()
+}
+
 pub fn print_sexp(sexp_vec: Vec<SExp>) {
-	for sexp in sexp_vec.into_iter() {
-		let print_plan = plan(&sexp, MARGIN_WIDTH);
-		print_impl(sexp, print_plan, 0);
+	let config = PrinterConfig::default();
+	for sexp in sexp_vec.iter() {
+		print!("{}", format_sexp(sexp, &config));
+	}
+}
+
+/// Formats a single S-expression. The result does not end with a newline.
+/// Panics if `sexp` is so deeply nested that no thread can be spawned with the stack to format it; within the closure
+/// given to `with_form`, the stack is there for the form parsed.
+pub fn format_sexp(sexp: &SExp, config: &PrinterConfig) -> String {
+	with_stack_for_depth(sexp.depth(), || {
+		let mut next_group_id = 0;
+		render(
+			&doc_impl(sexp, None, None, config, &mut next_group_id),
+			config,
+		)
+	})
+	.expect(STACK_SPAWN_FAILED)
+}
+
+/// Like `format_sexp`, but keeping the blank lines between the elements of lists recorded in `locs`, as parsed along
+/// with `sexp`, up to `config.max_blank_lines` at a time. A list whose elements do not match those recorded, as when
+/// `sexp` was changed after parsing, is formatted as if nothing was recorded for it. Panics as `format_sexp` does.
+pub fn format_located_sexp(sexp: &SExp, locs: &LocTree, config: &PrinterConfig) -> String {
+	with_stack_for_depth(sexp.depth(), || {
+		let mut next_group_id = 0;
+		let doc = doc_impl(sexp, Some(locs), None, config, &mut next_group_id);
+		render(&with_comments(doc, Some(locs), config), config)
+	})
+	.expect(STACK_SPAWN_FAILED)
+}
+
+fn render(doc: &Doc, config: &PrinterConfig) -> String {
+	match config.indent_style {
+		IndentStyle::Spaces => doc.render(config.margin_width),
+		IndentStyle::Tabs(tab_width) => doc.render_with_tabs(config.margin_width, tab_width),
+	}
+}
+
+/// The document that `format_sexp` lays out. Beware that the document is recursive: for deeply nested expressions, it
+/// may overflow the stack when dropped. Panics as `format_sexp` does.
+pub fn sexp_to_doc(sexp: &SExp, config: &PrinterConfig) -> Doc {
+	with_stack_for_depth(sexp.depth(), || {
+		let mut next_group_id = 0;
+		doc_impl(sexp, None, None, config, &mut next_group_id)
+	})
+	.expect(STACK_SPAWN_FAILED)
+}
+
+impl PrinterConfig {
+	/// The display width of the tabs to indent with, if any.
+	pub(crate) fn tab_width(&self) -> Option<i64> {
+		match self.indent_style {
+			IndentStyle::Spaces => None,
+			IndentStyle::Tabs(tab_width) => Some(i64::from(tab_width)),
+		}
+	}
+
+	/// Rejects widths out of range: negative margin or indent widths, and tabs less than a column wide. Formatting
+	/// functions that return a `Result` check their configuration first.
+	pub fn check(&self) -> Result<()> {
+		if self.margin_width < 0 || self.indent_width < 0 {
+			return Err(SexpfmtError::invalid_config(
+				"margin_width and indent_width must not be negative",
+			));
+		}
+		if let IndentStyle::Tabs(tab_width) = self.indent_style
+			&& tab_width <= 0
+		{
+			return Err(SexpfmtError::invalid_config(format!(
+				"the tab width must be positive, not {}",
+				tab_width
+			)));
+		}
+		Ok(())
+	}
+
+	/// The number of columns by which each level of nesting is indented: `indent_width`, or the width of a tab.
+	pub(crate) fn indent_step(&self) -> i32 {
+		match self.indent_style {
+			IndentStyle::Spaces => self.indent_width,
+			IndentStyle::Tabs(tab_width) => tab_width,
+		}
+	}
+
+	/// Whether to end formatted text with a line break, given whether the input ends with one.
+	pub fn ends_with_line_break(&self, input_does: bool) -> bool {
+		match self.final_newline {
+			FinalNewline::Always => true,
+			FinalNewline::Preserve => input_does,
+			FinalNewline::Never => false,
+		}
+	}
+
+	/// The number of blank lines to put between two top-level forms that had `blank_lines` between them.
+	pub fn blank_lines_between_forms(&self, blank_lines: usize) -> usize {
+		if self.separate_top_level_forms {
+			1
+		} else {
+			blank_lines.min(self.max_blank_lines)
+		}
 	}
 }
 
-fn plan(sexp: &SExp, available_width: i32) -> PrintPlan {
+// `head_id` is given for the first element of a list, to identify its group if it is a list itself
+fn doc_impl(
+	sexp: &SExp,
+	locs: Option<&LocTree>,
+	head_id: Option<GroupId>,
+	config: &PrinterConfig,
+	next_group_id: &mut usize,
+) -> Doc {
 	match sexp {
-		SExp::Null(_) => PrintPlan::Null,
-		SExp::Atom(v) => {
-			// cannot line-break atoms
-			PrintPlan::Atom(v.len().try_into().unwrap())
-		}
-		SExp::List(es, _) => {
-			let elem_plans: Vec<PrintPlan> = es.iter().map(|x| plan(x, 0)).collect();
-			let monoline_width =
-				1 + elem_plans.iter().map(|x| x.width()).sum::<i32>() + ((es.len() - 1) as i32) + 1;
-			if available_width == 0 || monoline_width <= available_width {
-				// can fit this entire list on a single line
-				PrintPlan::List(monoline_width, elem_plans, ListPrintPlan::Monoline)
-			} else {
-				// multi-line:
-				let ml_elem_plans: Vec<PrintPlan> = es
-					.iter()
-					.map(|x| plan(x, available_width - INDENT_WIDTH))
-					.collect();
-				let width = INDENT_WIDTH + ml_elem_plans.iter().map(|x| x.width()).max().unwrap() + 1;
-				PrintPlan::List(width, ml_elem_plans, ListPrintPlan::Multiline)
+		SExp::Null(bookend_style) => match end_comments_doc(locs, config) {
+			Some((_, comments)) => {
+				let (open_token, close_token) = bookend_style.bookends();
+				Doc::group(Doc::Concat(vec![
+					Doc::text(open_token),
+					Doc::nest(config.indent_step(), comments),
+					Doc::SoftLine,
+					Doc::text(close_token),
+				]))
+			}
+			None => empty_list_doc(*bookend_style),
+		},
+		// only if built so, as the parser gives `SExp::Null`
+		SExp::List(es, bookend_style) if es.is_empty() => empty_list_doc(*bookend_style),
+		SExp::Atom(s) => Doc::text(s.as_str()),
+		SExp::List(es, bookend_style) => {
+			// what was recorded for another list is of no use
+			let locs = locs.filter(|locs| locs.children.len() == es.len());
+			match config.layout {
+				Layout::FixedStep => {
+					fixed_step_list_doc(es, locs, *bookend_style, head_id, config, next_group_id)
+				}
+				Layout::Lisp => lisp_list_doc(es, locs, *bookend_style, config, next_group_id),
 			}
 		}
 	}
 }
 
-fn print_impl(sexp: SExp, plan: PrintPlan, indent: i32) {
-	match (sexp, plan) {
-		(SExp::Null(bookend_style), PrintPlan::Null) => {
-			print!(
-				"{}",
-				match bookend_style {
-					SExpBookendStyle::Parentheses => "()",
-					SExpBookendStyle::CurlyBraces => "{}",
-					SExpBookendStyle::SquareBrackets => "[]",
-				}
+fn empty_list_doc(bookend_style: SExpBookendStyle) -> Doc {
+	let (open_token, close_token) = bookend_style.bookends();
+	Doc::text(format!("{}{}", open_token, close_token))
+}
+
+fn fixed_step_list_doc(
+	es: &[SExp],
+	locs: Option<&LocTree>,
+	bookend_style: SExpBookendStyle,
+	head_id: Option<GroupId>,
+	config: &PrinterConfig,
+	next_group_id: &mut usize,
+) -> Doc {
+	let (open_token, close_token) = bookend_style.bookends();
+	// a first element after comments is on a line of its own already
+	let head_comments = locs.is_some_and(|locs| {
+		locs
+			.children
+			.first()
+			.is_some_and(|head| !head.comments_before.is_empty())
+	});
+	let es_head_id = (matches!(es.first(), Some(SExp::List(..))) && !head_comments).then(|| {
+		*next_group_id += 1;
+		GroupId(*next_group_id - 1)
+	});
+	let elems: Vec<Doc> = es
+		.iter()
+		.enumerate()
+		.map(|(i, e)| {
+			let locs = locs.and_then(|locs| locs.children.get(i));
+			let doc = doc_impl(
+				e,
+				locs,
+				if i == 0 { es_head_id } else { None },
+				config,
+				next_group_id,
 			);
+			with_comments(doc, locs, config)
+		})
+		.collect();
+	let blank_lines = blank_lines_between(locs, 1, config);
+	let elems = keep_blank_lines(elems, &blank_lines);
+	let mut elems = tabulate(es, locs, elems, config, &blank_lines);
+	let fill = fills(es, config) && blank_lines.is_empty() && !has_comments(locs);
+	push_end_comments(&mut elems, locs, true, config);
+	let elems = if fill {
+		Doc::Fill(elems)
+	} else {
+		join_lines(elems)
+	};
+
+	let mut doc = Vec::with_capacity(5);
+	if head_id.is_some() {
+		// what goes before this list, if it is broken and it is the first element of another one.
+		match config.multiline_heads {
+			MultilineHeads::Padded => doc.push(Doc::if_broken(None, Doc::text(" "), Doc::Nil)),
+			MultilineHeads::OwnLine => doc.push(Doc::SoftLine),
+			MultilineHeads::Block => {}
 		}
-		(SExp::Atom(s), PrintPlan::Atom(_)) => {
-			print!("{}", s)
+	}
+	doc.push(Doc::text(open_token));
+	doc.push(Doc::nest(config.indent_step(), elems));
+	match config.closing_brackets {
+		// the comment runs to the end of the line
+		ClosingBrackets::Stacked if ends_with_comment(locs) => doc.push(Doc::SoftLine),
+		ClosingBrackets::Stacked => {
+			if es_head_id.is_some() && config.multiline_heads == MultilineHeads::Padded {
+				// the trailing padding space, if the first element was padded.
+				doc.push(Doc::if_broken(es_head_id, Doc::text(" "), Doc::Nil));
+			}
 		}
-		(SExp::List(es, bookend_style), PrintPlan::List(_, es_pps, linebreak)) => {
-			let es_len = es.len();
-			let insert_padding_space = if let PrintPlan::List(_, _, ListPrintPlan::Multiline) = es_pps[0]
-			{
-				// the first element is a multi-line list form itself.
-				true
+		ClosingBrackets::OwnLine => doc.push(Doc::SoftLine),
+	}
+	doc.push(Doc::text(close_token));
+	Doc::Group(head_id, Box::new(Doc::Concat(doc)))
+}
+
+fn lisp_list_doc(
+	es: &[SExp],
+	locs: Option<&LocTree>,
+	bookend_style: SExpBookendStyle,
+	config: &PrinterConfig,
+	next_group_id: &mut usize,
+) -> Doc {
+	let (open_token, close_token) = bookend_style.bookends();
+	let args: Vec<Doc> = es
+		.iter()
+		.enumerate()
+		.map(|(i, e)| {
+			let locs = locs.and_then(|locs| locs.children.get(i));
+			let doc = doc_impl(e, locs, None, config, next_group_id);
+			with_comments(doc, locs, config)
+		})
+		.collect();
+	let spec = es.first().and_then(SExp::as_symbol).map(|s| {
+		config
+			.indent_specs
+			.get(s)
+			.copied()
+			.unwrap_or(IndentSpec::Hanging)
+	});
+	// calls keep their first argument on the line of their head
+	let first = if spec.is_some() { 2 } else { 1 };
+	let blank_lines = blank_lines_between(locs, first, config);
+	let args = keep_blank_lines(args, &blank_lines);
+	let mut args = tabulate(es, locs, args, config, &blank_lines);
+	let fill = fills(es, config) && blank_lines.is_empty() && !has_comments(locs);
+	let with_blank_lines = args.len() >= first;
+	push_end_comments(&mut args, locs, with_blank_lines, config);
+	let head = args.remove(0);
+	// what goes between the head and the argument after it, on the next line if a comment ends the head's or
+	// starts the argument's, or the argument is the comments at the end of the list
+	let head_comment = locs.is_some_and(|locs| {
+		locs
+			.children
+			.first()
+			.is_some_and(|head| head.comment_after.is_some())
+			|| match locs.children.get(1) {
+				Some(arg) => !arg.comments_before.is_empty(),
+				None => !locs.comments_at_end.is_empty(),
+			}
+	});
+	let after_head = |indent| {
+		if head_comment {
+			Doc::nest(indent, Doc::Line)
+		} else {
+			Doc::text(" ")
+		}
+	};
+
+	let join = |docs| {
+		if fill {
+			Doc::Fill(docs)
+		} else {
+			join_lines(docs)
+		}
+	};
+
+	let mut doc = vec![Doc::text(open_token)];
+	match spec {
+		// lists of data, not calls, are aligned under their first element
+		None => {
+			args.insert(0, head);
+			doc.push(Doc::align(join(args)));
+		}
+		Some(_) if args.is_empty() => doc.push(head),
+		Some(IndentSpec::Special(n)) if !fill => {
+			let body = args.split_off(n.min(args.len()));
+			doc.push(head);
+			let mut args = args.into_iter();
+			if let Some(first) = args.next() {
+				doc.push(after_head(2 * config.indent_step()));
+				doc.push(first);
+			}
+			doc.push(Doc::nest(
+				2 * config.indent_step(),
+				Doc::Concat(args.flat_map(|arg| [Doc::Line, arg]).collect()),
+			));
+			doc.push(Doc::nest(
+				config.indent_step(),
+				Doc::Concat(body.into_iter().flat_map(|arg| [Doc::Line, arg]).collect()),
+			));
+		}
+		Some(_) => {
+			doc.push(head);
+			doc.push(after_head(1));
+			doc.push(Doc::align(join(args)));
+		}
+	}
+	if config.closing_brackets == ClosingBrackets::OwnLine || ends_with_comment(locs) {
+		doc.push(Doc::SoftLine);
+	}
+	doc.push(Doc::text(close_token));
+	Doc::align(Doc::group(Doc::Concat(doc)))
+}
+
+// The number of blank lines to keep before each element of a list from the `first` on, as recorded in `locs`, or
+// nothing if there are none to keep
+fn blank_lines_between(locs: Option<&LocTree>, first: usize, config: &PrinterConfig) -> Vec<usize> {
+	let Some(locs) = locs else {
+		return Vec::new();
+	};
+	let blank_lines: Vec<usize> = locs
+		.children
+		.iter()
+		.enumerate()
+		.map(|(i, child)| {
+			if i < first {
+				0
 			} else {
-				false
-			};
-			let (open_token, close_token) = match bookend_style {
-				SExpBookendStyle::Parentheses => ('(', ')'),
-				SExpBookendStyle::CurlyBraces => ('{', '}'),
-				SExpBookendStyle::SquareBrackets => ('[', ']'),
-			};
+				// those before the comments on lines of their own before the child, if any
+				let blank_lines = child
+					.comments_before
+					.first()
+					.map_or(child.blank_lines_before, |comment| {
+						comment.blank_lines_before
+					});
+				blank_lines.min(config.max_blank_lines)
+			}
+		})
+		.collect();
+	if blank_lines.iter().all(|&n| n == 0) {
+		return Vec::new();
+	}
+	blank_lines
+}
+
+// Ends each element with the blank lines that go before the next one. They are taken along with the line break between
+// the two, so they only suit separators that are line breaks when the list is broken.
+fn keep_blank_lines(elems: Vec<Doc>, blank_lines: &[usize]) -> Vec<Doc> {
+	if blank_lines.is_empty() {
+		return elems;
+	}
+	elems
+		.into_iter()
+		.zip(blank_lines[1..].iter().chain([&0]))
+		.map(|(doc, &n)| match n {
+			0 => doc,
+			n => Doc::Concat(
+				std::iter::once(doc)
+					.chain(std::iter::repeat_n(Doc::BlankLine, n))
+					.collect(),
+			),
+		})
+		.collect()
+}
+
+// Replaces the documents of the rows of a table with their padded text, for when the list is broken
+fn tabulate(
+	es: &[SExp],
+	locs: Option<&LocTree>,
+	elems: Vec<Doc>,
+	config: &PrinterConfig,
+	blank_lines: &[usize],
+) -> Vec<Doc> {
+	// rows are printed from `es`, which has no comments
+	if !config.align_tables
+		|| fills(es, config)
+		|| !blank_lines.is_empty()
+		|| locs.is_some_and(has_comments_within)
+	{
+		return elems;
+	}
+	let Some(row_texts) = table_row_texts(es) else {
+		return elems;
+	};
+	elems
+		.into_iter()
+		.zip(row_texts)
+		.map(|(doc, row_text)| match row_text {
+			Some(row_text) => Doc::if_broken(None, Doc::text(row_text), doc),
+			None => doc,
+		})
+		.collect()
+}
+
+// The rows of `es` as text, each on a single line with its cells padded so that columns line up, if `es` is a table
+fn table_row_texts(es: &[SExp]) -> Option<Vec<Option<String>>> {
+	let first_row = usize::from(es.first()?.is_atom());
+	let rows = &es[first_row..];
+	let SExp::List(first_cells, style) = rows.first()? else {
+		return None;
+	};
+	let is_row = |row: &SExp| matches!(row, SExp::List(cells, row_style) if cells.len() == first_cells.len() && row_style == style);
+	if rows.len() < 2 || first_cells.len() < 2 || !rows.iter().all(is_row) {
+		return None;
+	}
 
-			print!("{}", open_token);
-			if insert_padding_space {
-				print!(" ");
+	let cell_texts: Vec<Vec<String>> = rows
+		.iter()
+		.map(|row| row.iter().map(SExp::to_string).collect())
+		.collect();
+	let column_widths: Vec<usize> = (0..first_cells.len())
+		.map(|j| {
+			cell_texts
+				.iter()
+				.map(|cells| cells[j].chars().count())
+				.max()
+				.unwrap()
+		})
+		.collect();
+	let (open_token, close_token) = style.bookends();
+	let row_texts = cell_texts.iter().map(|cells| {
+		let mut row_text = String::from(open_token);
+		for (j, cell) in cells.iter().enumerate() {
+			if j > 0 {
+				row_text.push(' ');
 			}
-			match linebreak {
-				ListPrintPlan::Monoline => {
-					for (i, (e, pp)) in (0..es.len()).zip(es.into_iter().zip(es_pps.into_iter())) {
-						print_impl(e, pp, indent);
-						if i < es_len - 1 {
-							print!(" ")
-						}
-					}
-				}
-				ListPrintPlan::Multiline => {
-					for (i, (e, pp)) in (0..es.len()).zip(es.into_iter().zip(es_pps.into_iter())) {
-						print_impl(e, pp, indent + INDENT_WIDTH);
-						if i < es_len - 1 {
-							println!();
-							for _ in 0..(indent + INDENT_WIDTH) {
-								print!(" ");
-							}
-						}
-					}
+			row_text.push_str(cell);
+			if j + 1 < cells.len() {
+				for _ in cell.chars().count()..column_widths[j] {
+					row_text.push(' ');
 				}
 			}
-			if insert_padding_space {
-				print!(" ");
-			}
-			print!("{}", close_token);
 		}
-		_ => panic!("sexp-plan mismatch"),
+		row_text.push(close_token);
+		Some(row_text)
+	});
+	Some(
+		std::iter::repeat_n(None, first_row)
+			.chain(row_texts)
+			.collect(),
+	)
+}
+
+// Puts the comments of `locs` on lines of their own before the node whose document is `doc`, and the one after it on its
+// line, each followed by a line break.
+fn with_comments(doc: Doc, locs: Option<&LocTree>, config: &PrinterConfig) -> Doc {
+	let Some(locs) =
+		locs.filter(|locs| !locs.comments_before.is_empty() || locs.comment_after.is_some())
+	else {
+		return doc;
+	};
+	let mut res = Vec::new();
+	for (i, comment) in locs.comments_before.iter().enumerate() {
+		let blank_lines = locs
+			.comments_before
+			.get(i + 1)
+			.map_or(locs.blank_lines_before, |next| next.blank_lines_before);
+		res.push(Doc::text(comment.text.trim_end()));
+		res.push(Doc::BreakParent);
+		res.extend(std::iter::repeat_n(
+			Doc::BlankLine,
+			blank_lines.min(config.max_blank_lines),
+		));
+		res.push(Doc::Line);
+	}
+	res.push(doc);
+	if let Some(comment) = &locs.comment_after {
+		res.push(Doc::text(format!(" {}", comment.trim_end())));
+		res.push(Doc::BreakParent);
+	}
+	Doc::Concat(res)
+}
+
+// The comments on lines of their own at the end of the list of `locs`, if any, with the blank lines before them
+fn end_comments_doc(locs: Option<&LocTree>, config: &PrinterConfig) -> Option<(usize, Doc)> {
+	let comments = &locs?.comments_at_end;
+	let first = comments.first()?;
+	let mut res = Vec::new();
+	for comment in comments.iter() {
+		if !res.is_empty() {
+			let blank_lines = comment.blank_lines_before.min(config.max_blank_lines);
+			res.extend(std::iter::repeat_n(Doc::BlankLine, blank_lines));
+			res.push(Doc::Line);
+		}
+		res.push(Doc::text(comment.text.trim_end()));
+		res.push(Doc::BreakParent);
+	}
+	Some((first.blank_lines_before, Doc::Concat(res)))
+}
+
+// Adds the comments at the end of the list of `locs` as one more element, after the blank lines before them if
+// `with_blank_lines`
+fn push_end_comments(
+	elems: &mut Vec<Doc>,
+	locs: Option<&LocTree>,
+	with_blank_lines: bool,
+	config: &PrinterConfig,
+) {
+	let Some((blank_lines, comments)) = end_comments_doc(locs, config) else {
+		return;
+	};
+	if with_blank_lines && let Some(last) = elems.pop() {
+		elems.push(Doc::Concat(
+			std::iter::once(last)
+				.chain(std::iter::repeat_n(
+					Doc::BlankLine,
+					blank_lines.min(config.max_blank_lines),
+				))
+				.collect(),
+		));
+	}
+	elems.push(comments);
+}
+
+// Whether the list of `locs` ends with a comment, which its closing bracket must not follow on the same line
+fn ends_with_comment(locs: Option<&LocTree>) -> bool {
+	locs.is_some_and(|locs| {
+		!locs.comments_at_end.is_empty()
+			|| locs
+				.children
+				.last()
+				.is_some_and(|last| last.comment_after.is_some())
+	})
+}
+
+// Whether there are comments between the elements of the list of `locs`, which then cannot share lines at will
+fn has_comments(locs: Option<&LocTree>) -> bool {
+	locs.is_some_and(|locs| {
+		!locs.comments_at_end.is_empty()
+			|| locs
+				.children
+				.iter()
+				.any(|child| !child.comments_before.is_empty() || child.comment_after.is_some())
+	})
+}
+
+// Whether there are comments anywhere within the list of `locs`
+fn has_comments_within(locs: &LocTree) -> bool {
+	has_comments(Some(locs)) || locs.children.iter().any(has_comments_within)
+}
+
+fn fills(es: &[SExp], config: &PrinterConfig) -> bool {
+	(config.fill_atom_lists && es.iter().all(SExp::is_atom))
+		|| es
+			.first()
+			.and_then(SExp::as_symbol)
+			.is_some_and(|head| config.fill_heads.contains(head))
+}
+
+fn join_lines(docs: Vec<Doc>) -> Doc {
+	let mut res = Vec::with_capacity(2 * docs.len());
+	for doc in docs {
+		if !res.is_empty() {
+			res.push(Doc::Line);
+		}
+		res.push(doc);
+	}
+	Doc::Concat(res)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn format_with(text: &str, config: &PrinterConfig) -> String {
+		let sexp: SExp = text.parse().unwrap();
+		format_sexp(&sexp, config)
+	}
+
+	fn lisp_config(margin_width: i32) -> PrinterConfig {
+		PrinterConfig {
+			margin_width,
+			layout: Layout::Lisp,
+			..PrinterConfig::default()
+		}
+	}
+
+	#[test]
+	fn test_long_atoms() {
+		let long = "a".repeat(85);
+		// no line break would bring the atom within the margin
+		let text = format!("(f (g {long}))");
+		assert_eq!(format_with(&text, &PrinterConfig::default()), text);
+		assert_eq!(
+			format_with(&format!("(f (g {long}) b)"), &PrinterConfig::default()),
+			format!("(f\n  (g {long})\n  b)")
+		);
+	}
+
+	#[test]
+	fn test_lisp_layout_special_forms() {
+		let text =
+			r#"(define (f x) (let ((a 1) (b 2)) (if (< a b) (display "a is smaller") (display "b"))))"#;
+		assert_eq!(format_with(text, &lisp_config(100)), text);
+		assert_eq!(
+			format_with(text, &lisp_config(40)),
+			r#"(define (f x)
+  (let ((a 1) (b 2))
+    (if (< a b)
+        (display "a is smaller")
+      (display "b"))))"#
+		);
+		assert_eq!(
+			format_with("(begin (newline) (newline))", &lisp_config(20)),
+			"(begin\n  (newline)\n  (newline))"
+		);
+	}
+
+	#[test]
+	fn test_lisp_layout_hanging() {
+		assert_eq!(
+			format_with("(foo-bar alpha (beta gamma delta))", &lisp_config(24)),
+			"(foo-bar alpha\n         (beta gamma\n               delta))"
+		);
+		// data is aligned under the first element
+		assert_eq!(
+			format_with(r#"((a b) "c" (d e))"#, &lisp_config(10)),
+			"((a b)\n \"c\"\n (d e))"
+		);
+		let mut config = lisp_config(21);
+		config
+			.indent_specs
+			.insert("foo-bar".to_string(), IndentSpec::Special(1));
+		assert_eq!(
+			format_with("(foo-bar alpha (beta gamma delta))", &config),
+			"(foo-bar alpha\n  (beta gamma delta))"
+		);
+	}
+
+	#[test]
+	fn test_fill() {
+		let numbers: Vec<String> = (1..=20).map(|i| (i * i).to_string()).collect();
+		let text = format!(
+			"(data (bytes {}) [{}])",
+			numbers.join(" "),
+			numbers[..8].join(" ")
+		);
+		let mut config = PrinterConfig {
+			margin_width: 30,
+			fill_atom_lists: true,
+			..PrinterConfig::default()
+		};
+		assert_eq!(
+			format_with(&text, &config),
+			"(data
+  (bytes 1 4 9 16 25 36 49 64
+    81 100 121 144 169 196 225
+    256 289 324 361 400)
+  [1 4 9 16 25 36 49 64])"
+		);
+		config.layout = Layout::Lisp;
+		assert_eq!(
+			format_with(&text, &config),
+			"(data (bytes 1 4 9 16 25 36 49
+             64 81 100 121 144
+             169 196 225 256
+             289 324 361 400)
+      [1 4 9 16 25 36 49 64])"
+		);
+		// selected by head, even with nested lists
+		let config = PrinterConfig {
+			margin_width: 16,
+			fill_heads: HashSet::from(["row".to_string()]),
+			..PrinterConfig::default()
+		};
+		assert_eq!(
+			format_with("(row (a) (b) (c d) e (f g) h)", &config),
+			"(row (a) (b)\n  (c d) e (f g)\n  h)"
+		);
+	}
+
+	#[test]
+	fn test_align_tables() {
+		let config = PrinterConfig {
+			margin_width: 30,
+			align_tables: true,
+			..PrinterConfig::default()
+		};
+		let text = r#"(costs (latte 3.5 "tall") (croissant 2 "small") (tea 10.25 "-"))"#;
+		assert_eq!(
+			format_with(text, &config),
+			r#"(costs
+  (latte     3.5   "tall")
+  (croissant 2     "small")
+  (tea       10.25 "-"))"#
+		);
+		// only broken lists are aligned, and rows need the same shape
+		assert_eq!(format_with("(a (b 1) (cc 2))", &config), "(a (b 1) (cc 2))");
+		assert_eq!(
+			format_with("(abcdefgh (b 1 x) (cc 2) [ddd 3] (e 4 y))", &config),
+			"(abcdefgh\n  (b 1 x)\n  (cc 2)\n  [ddd 3]\n  (e 4 y))"
+		);
+		let config = PrinterConfig {
+			margin_width: 24,
+			layout: Layout::Lisp,
+			..config
+		};
+		assert_eq!(
+			format_with("(let ((a 1) (bbbbbbbbbb 2)) (+ a bbbbbbbbbb))", &config),
+			"(let ((a          1)\n      (bbbbbbbbbb 2))\n  (+ a bbbbbbbbbb))"
+		);
+	}
+
+	#[test]
+	fn test_closing_brackets_own_line() {
+		let mut config = PrinterConfig {
+			margin_width: 20,
+			closing_brackets: ClosingBrackets::OwnLine,
+			..PrinterConfig::default()
+		};
+		let text = "(order [item {name latte} {size tall}] (total 3))";
+		assert_eq!(
+			format_with(text, &config),
+			"(order
+  [item
+    {name latte}
+    {size tall}
+  ]
+  (total 3)
+)"
+		);
+		config.layout = Layout::Lisp;
+		config.margin_width = 26;
+		assert_eq!(
+			format_with(text, &config),
+			"(order [item {name latte}
+             {size tall}
+       ]
+       (total 3)
+)"
+		);
+		// closing brackets no longer count against the width of the last element, and padded lists are not padded
+		// before their closing bracket
+		config.layout = Layout::FixedStep;
+		config.margin_width = 18;
+		assert_eq!(
+			format_with("(x (yyyyyyyyyyyyyy))", &config),
+			"(x\n  (yyyyyyyyyyyyyy)\n)"
+		);
+		assert_eq!(
+			format_with(
+				"(f ((g a b) x))",
+				&PrinterConfig {
+					margin_width: 8,
+					..config
+				}
+			),
+			"(f\n  ( (g\n      a\n      b\n    )\n    x\n  )\n)"
+		);
+	}
+
+	#[test]
+	fn test_multiline_heads() {
+		let text = "(map ((list alpha beta gamma) 42) ((x) y))";
+		let mut config = PrinterConfig {
+			margin_width: 20,
+			..PrinterConfig::default()
+		};
+		let padded = "(map
+  ( (list
+      alpha
+      beta
+      gamma)
+    42 )
+  ((x) y))";
+		assert_eq!(config.multiline_heads, MultilineHeads::Padded);
+		assert_eq!(format_with(text, &config), padded);
+		config.multiline_heads = MultilineHeads::OwnLine;
+		assert_eq!(
+			format_with(text, &config),
+			"(map
+  (
+    (list
+      alpha
+      beta
+      gamma)
+    42)
+  ((x) y))"
+		);
+		config.multiline_heads = MultilineHeads::Block;
+		assert_eq!(
+			format_with(text, &config),
+			"(map
+  ((list
+      alpha
+      beta
+      gamma)
+    42)
+  ((x) y))"
+		);
+	}
+
+	#[test]
+	fn test_blank_lines() {
+		let text = "(define (f x)\n\n  (display x)\n\n\n  (newline))";
+		let (sexp, locs) = parse_form_with_locs(text.to_string(), Loc::new(0, 1, 1))
+			.unwrap()
+			.remove(0);
+		let mut config = lisp_config(80);
+		assert_eq!(
+			format_located_sexp(&sexp, &locs, &config),
+			"(define (f x)\n\n  (display x)\n\n  (newline))"
+		);
+		config.layout = Layout::FixedStep;
+		config.max_blank_lines = 2;
+		assert_eq!(
+			format_located_sexp(&sexp, &locs, &config),
+			"(define\n  (f x)\n\n  (display x)\n\n\n  (newline))"
+		);
+		assert_eq!(
+			format_sexp(&sexp, &config),
+			"(define (f x) (display x) (newline))"
+		);
+	}
+
+	#[test]
+	fn test_located_sexp_changed_after_parsing() {
+		let text = "(a ; comment\n\n  (b c))";
+		let (mut sexp, locs) = parse_form_with_locs(text.to_string(), Loc::new(0, 1, 1))
+			.unwrap()
+			.remove(0);
+		let config = PrinterConfig::default();
+		// the lists whose elements differ from those parsed are formatted without what was recorded for them
+		sexp.as_list_mut().unwrap()[1]
+			.as_list_mut()
+			.unwrap()
+			.push(SExp::atom("d"));
+		assert_eq!(
+			format_located_sexp(&sexp, &locs, &config),
+			"(a ; comment\n\n  (b c d))"
+		);
+		sexp.as_list_mut().unwrap().push(SExp::atom("e"));
+		assert_eq!(format_located_sexp(&sexp, &locs, &config), "(a (b c d) e)");
+		for layout in [Layout::FixedStep, Layout::Lisp] {
+			let config = PrinterConfig {
+				layout,
+				..PrinterConfig::default()
+			};
+			format_located_sexp(&SExp::list([SExp::atom("x")]), &locs, &config);
+		}
+	}
+
+	#[test]
+	fn test_empty_list() {
+		let mut sexp = sexp!((a[b]));
+		sexp.as_list_mut().unwrap()[1]
+			.as_list_mut()
+			.unwrap()
+			.clear();
+		for layout in [Layout::FixedStep, Layout::Lisp] {
+			let config = PrinterConfig {
+				layout,
+				fill_atom_lists: true,
+				align_tables: true,
+				..PrinterConfig::default()
+			};
+			assert_eq!(format_sexp(&sexp, &config), "(a [])");
+		}
+		sexp.as_list_mut().unwrap().clear();
+		assert_eq!(format_sexp(&sexp, &PrinterConfig::default()), "()");
+	}
+
+	#[test]
+	fn test_indent_style_tabs() {
+		let text = "(define (f x) (let ((a 1)) (display a)))";
+		let mut config = lisp_config(24);
+		config.indent_style = IndentStyle::Tabs(4);
+		// one tab per level, in place of the indent width
+		assert_eq!(
+			format_with(text, &config),
+			"(define (f x)\n\t(let ((a 1))\n\t\t(display a)))"
+		);
+		// with spaces for alignment, after the tabs of the line aligned to
+		assert_eq!(
+			format_with("(define (f x) (foo (bar a b) (let ((a 1)) c)))", &config),
+			"(define (f x)\n\t(foo (bar a b)\n\t     (let ((a 1))\n\t         c)))"
+		);
+		config.layout = Layout::FixedStep;
+		assert_eq!(
+			format_with(text, &config),
+			"(define\n\t(f x)\n\t(let\n\t\t((a 1))\n\t\t(display a)))"
+		);
+		for tab_width in [0, -1] {
+			config.indent_style = IndentStyle::Tabs(tab_width);
+			assert!(matches!(
+				format_str(text, &config),
+				Err(SexpfmtError::InvalidConfig { .. })
+			));
+		}
 	}
 }
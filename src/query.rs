@@ -0,0 +1,247 @@
+// A small query language for selecting subtrees, similar in spirit to XPath or jq.
+// A query is a sequence of steps separated by '/'. Starting from the top-level forms, each step selects the elements
+// of the currently selected lists that match it:
+// - an atom such as `name` matches that atom, and any list whose head is that atom.
+// - `_` and `*` match anything.
+// - a list such as `(quantity _)` matches lists with the same bookends whose leading elements match each of its
+//   elements in turn, so `(object)` matches any list whose head is `object`.
+// - `**` selects the currently selected nodes along with all of their descendants, so that `**/(quantity _)` finds
+//   `quantity` entries at any depth.
+// Steps are themselves S-expressions, so they are parsed with `parse_form`. A '/' within a step, outside of bookends
+// and strings, is written `\/`, as in `path\/to`; the character literal `#\/` is left as it is.
+
+use super::*;
+
+use std::collections::HashSet;
+use std::str::FromStr;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Query {
+	steps: Vec<QueryStep>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum QueryStep {
+	Descendants,
+	Match(SExp),
+}
+
+impl Query {
+	pub fn parse(text: &str) -> Result<Self> {
+		let mut steps = Vec::new();
+		for (offset, step_text) in split_steps(text.strip_prefix('/').unwrap_or(text)) {
+			let offset = offset + usize::from(text.starts_with('/'));
+			let loc = Loc::new(offset, 1, offset + 1);
+			let mut sexp_vec = parse_form(step_text, loc)?;
+			if sexp_vec.len() != 1 {
+				return Err(SexpfmtError::invalid_input(
+					format!(
+						"Expected exactly one pattern in query step, got {}",
+						sexp_vec.len()
+					),
+					loc,
+				));
+			}
+			steps.push(match sexp_vec.pop().unwrap() {
+				SExp::Atom(s) if s == "**" => QueryStep::Descendants,
+				pattern => QueryStep::Match(pattern),
+			});
+		}
+		Ok(Self { steps })
+	}
+
+	/// Returns every subtree of `forms` selected by this query, in document order.
+	pub fn select<'a>(&self, forms: &'a [SExp]) -> Vec<&'a SExp> {
+		// `None` stands for the (virtual) root, whose elements are the top-level forms.
+		let mut selected: Vec<Option<&'a SExp>> = vec![None];
+		for step in self.steps.iter() {
+			let mut next = Vec::new();
+			for node in selected.into_iter() {
+				match step {
+					QueryStep::Descendants => collect_descendants(node, forms, &mut next),
+					QueryStep::Match(pattern) => {
+						for e in elements(node, forms).iter() {
+							if matches_pattern(pattern, e) {
+								next.push(Some(e));
+							}
+						}
+					}
+				}
+			}
+			selected = dedup_nodes(next);
+		}
+		selected.into_iter().flatten().collect()
+	}
+}
+
+impl FromStr for Query {
+	type Err = SexpfmtError;
+
+	fn from_str(s: &str) -> Result<Self> {
+		Self::parse(s)
+	}
+}
+
+// Splits a query on the '/' characters that are not nested in bookends or strings, returning each step with its byte
+// offset, and with its escaped '/' characters unescaped.
+fn split_steps(text: &str) -> Vec<(usize, String)> {
+	let mut steps = Vec::new();
+	let mut depth = 0;
+	let mut in_string = false;
+	let mut escaped = false;
+	let mut step_start = 0;
+	let mut step = String::new();
+	let mut chars = text.char_indices().peekable();
+	while let Some((i, c)) = chars.next() {
+		if in_string {
+			match c {
+				_ if escaped => escaped = false,
+				'\\' => escaped = true,
+				'"' => in_string = false,
+				_ => {}
+			}
+			step.push(c);
+			continue;
+		}
+		match c {
+			'"' => in_string = true,
+			'(' | '[' | '{' => depth += 1,
+			')' | ']' | '}' => depth -= 1,
+			'\\' if !step.ends_with('#') && chars.next_if(|&(_, c)| c == '/').is_some() => {
+				step.push('/');
+				continue;
+			}
+			'/' if depth == 0 => {
+				steps.push((step_start, std::mem::take(&mut step)));
+				step_start = i + 1;
+				continue;
+			}
+			_ => {}
+		}
+		step.push(c);
+	}
+	steps.push((step_start, step));
+	steps
+}
+
+fn elements<'a>(node: Option<&'a SExp>, forms: &'a [SExp]) -> &'a [SExp] {
+	match node {
+		None => forms,
+		Some(SExp::List(es, _)) => es,
+		Some(_) => &[],
+	}
+}
+
+fn collect_descendants<'a>(
+	node: Option<&'a SExp>,
+	forms: &'a [SExp],
+	out: &mut Vec<Option<&'a SExp>>,
+) {
+	out.push(node);
+	for e in elements(node, forms).iter() {
+		collect_descendants(Some(e), forms, out);
+	}
+}
+
+// Removes repeated selections of the same node (e.g. from `**/**`), keeping the first.
+fn dedup_nodes(nodes: Vec<Option<&SExp>>) -> Vec<Option<&SExp>> {
+	let mut seen = HashSet::new();
+	nodes
+		.into_iter()
+		.filter(|node| seen.insert(node.map(|e| e as *const SExp)))
+		.collect()
+}
+
+fn matches_pattern(pattern: &SExp, sexp: &SExp) -> bool {
+	match (pattern, sexp) {
+		(SExp::Atom(p), _) if p == "_" || p == "*" => true,
+		(SExp::Atom(p), SExp::Atom(s)) => p == s,
+		(SExp::Atom(p), SExp::List(es, _)) => matches!(es.first(), Some(SExp::Atom(s)) if s == p),
+		(SExp::List(ps, p_style), SExp::List(es, e_style)) => {
+			p_style == e_style
+				&& ps.len() <= es.len()
+				&& ps.iter().zip(es.iter()).all(|(p, e)| matches_pattern(p, e))
+		}
+		(SExp::Null(p_style), SExp::List(_, e_style) | SExp::Null(e_style)) => p_style == e_style,
+		_ => false,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const CAFE_ORDER: &str = r#"
+		(object
+			(object (name "croissant") (quantity 2))
+			(object (name "latte") (quantity 1) (size "tall")))
+		(total 3)
+	"#;
+
+	fn select(query: &str, text: &str) -> Vec<SExp> {
+		let forms = parse_form(text.to_string(), Loc::new(0, 1, 1)).unwrap();
+		let query: Query = query.parse().unwrap();
+		query.select(&forms).into_iter().cloned().collect()
+	}
+
+	fn parse(text: &str) -> Vec<SExp> {
+		parse_form(text.to_string(), Loc::new(0, 1, 1)).unwrap()
+	}
+
+	#[test]
+	fn test_query_child_steps() {
+		assert_eq!(
+			select("(object)/object/name", CAFE_ORDER),
+			parse(r#"(name "croissant") (name "latte")"#)
+		);
+		assert_eq!(select("total/_", CAFE_ORDER), parse("total 3"));
+	}
+
+	#[test]
+	fn test_query_descendants() {
+		assert_eq!(
+			select("**/(quantity _)", CAFE_ORDER),
+			parse("(quantity 2) (quantity 1)")
+		);
+		assert_eq!(
+			select("**/**/(size)", CAFE_ORDER),
+			parse(r#"(size "tall")"#)
+		);
+		assert_eq!(
+			select("**/(object (name \"latte\"))/size/*", CAFE_ORDER),
+			parse(r#"size "tall""#)
+		);
+	}
+
+	#[test]
+	fn test_query_bookends_must_match() {
+		assert_eq!(select("[a]", "(a 1) [a 2]"), parse("[a 2]"));
+		assert_eq!(select("[]", "(a 1) [a 2] []"), parse("[a 2] []"));
+	}
+
+	#[test]
+	fn test_query_escaped_slash() {
+		assert_eq!(select(r"a\/b/_", "(a/b 1) (a 2)"), parse("a/b 1"));
+		assert_eq!(select(r"(c #\/)", r"(c #\/) (c #\a)"), parse(r"(c #\/)"));
+	}
+
+	#[test]
+	fn test_query_empty_list() {
+		let forms = vec![SExp::List(Vec::new(), SExpBookendStyle::Parentheses)];
+		let query: Query = "a".parse().unwrap();
+		assert!(query.select(&forms).is_empty());
+	}
+
+	#[test]
+	fn test_query_parse_error() {
+		let err = Query::parse("(object)/(name").unwrap_err();
+		assert!(matches!(
+			err,
+			SexpfmtError::Parse { position, .. } if position.column() >= 10
+		));
+		assert!(matches!(
+			Query::parse("a//b"),
+			Err(SexpfmtError::InvalidInput { .. })
+		));
+	}
+}
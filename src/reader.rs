@@ -6,14 +6,37 @@ use std::io;
 
 pub struct FormReader<R: io::Read> {
 	inner: ByteReader<R>,
+	max_depth: usize,
+	// line breaks skipped since the last form read
+	newlines_skipped: usize,
 }
 
 impl<R: io::Read> FormReader<R> {
 	pub fn new(inner: R) -> io::Result<Self> {
+		Self::with_start_loc(inner, Loc::new(0, 1, 1))
+	}
+	/// Creates a reader for input that starts at `start` in some larger document, so that reported locations are
+	/// relative to that document.
+	pub fn with_start_loc(inner: R, start: Loc) -> io::Result<Self> {
 		Ok(Self {
-			inner: ByteReader::new(inner)?,
+			inner: ByteReader::new(inner, start)?,
+			max_depth: DEFAULT_MAX_DEPTH,
+			// input starting at the start of a line is as if it followed a line break
+			newlines_skipped: usize::from(start.column() == 1),
 		})
 	}
+	/// Rejects forms whose lists are nested more than `max_depth` deep, which is `DEFAULT_MAX_DEPTH` unless set.
+	pub fn set_max_depth(&mut self, max_depth: usize) {
+		self.max_depth = max_depth;
+	}
+	/// The first line ending read so far, if any.
+	pub fn line_ending(&self) -> Option<LineEnding> {
+		self.inner.line_ending
+	}
+	/// Whether the input started with a UTF-8 byte order mark, which is skipped.
+	pub fn has_bom(&self) -> bool {
+		self.inner.has_bom
+	}
 }
 impl<R: io::Read> FormReader<R> {
 	fn is_whitespace_byte(b: u8) -> bool {
@@ -26,14 +49,34 @@ impl<R: io::Read> FormReader<R> {
 impl<R: io::Read> FormReader<R> {
 	pub fn get(&mut self) -> Result<Option<(String, Loc)>> {
 		self.skip_whitespace_prefix()?;
+		self.newlines_skipped = 0;
 		let position = self.inner.peek_loc();
 		self.get_without_whitespace_prefix(position)
 	}
+	/// Returns the number of blank lines between the last form read, or the start of the input, and the next form, or
+	/// the end of the input.
+	pub fn peek_blank_lines(&mut self) -> Result<usize> {
+		Ok(self.peek_line_breaks()?.saturating_sub(1))
+	}
+	/// Returns the number of line breaks between the last form read, or the start of the input, and the next form, or
+	/// the end of the input.
+	pub fn peek_line_breaks(&mut self) -> Result<usize> {
+		self.skip_whitespace_prefix()?;
+		Ok(self.newlines_skipped)
+	}
+	/// Returns where the next form starts, without reading it, or `None` at EOF.
+	pub fn peek_loc(&mut self) -> Result<Option<Loc>> {
+		self.skip_whitespace_prefix()?;
+		Ok(self.inner.peek().map(|_| self.inner.peek_loc()))
+	}
 	fn skip_whitespace_prefix(&mut self) -> Result<()> {
 		loop {
 			match self.inner.peek() {
 				Some(b) if Self::is_whitespace_byte(b) => {
 					_ = self.inner.get()?;
+					if b == b'\n' || (b == b'\r' && self.inner.peek() != Some(b'\n')) {
+						self.newlines_skipped += 1;
+					}
 					continue;
 				}
 				_ => {
@@ -47,6 +90,7 @@ impl<R: io::Read> FormReader<R> {
 			Some(b'(') | Some(b')') => self.get_list_without_whitespace_prefix(position),
 			Some(b'[') | Some(b']') => self.get_list_without_whitespace_prefix(position),
 			Some(b'{') | Some(b'}') => self.get_list_without_whitespace_prefix(position),
+			Some(b';') => self.get_comment_without_whitespace_prefix(position),
 			Some(_) => self.get_atom_without_whitespace_prefix(position),
 			None => Ok(None),
 		}
@@ -54,6 +98,9 @@ impl<R: io::Read> FormReader<R> {
 	fn get_list_without_whitespace_prefix(&mut self, position: Loc) -> Result<Option<(String, Loc)>> {
 		let mut bookend_stack = Vec::default();
 		let mut bytes = Vec::default();
+		// bookends in strings and comments do not count
+		let mut in_string = false;
+		let mut in_comment = false;
 
 		macro_rules! pop_bookend {
 			($x:expr) => {
@@ -98,7 +145,17 @@ impl<R: io::Read> FormReader<R> {
 			};
 		}
 
+		macro_rules! push_bookend {
+			($x:expr, $loc:expr) => {{
+				if bookend_stack.len() == self.max_depth {
+					return Err(SexpfmtError::nesting_too_deep($loc, self.max_depth));
+				}
+				bookend_stack.push($x);
+			}};
+		}
+
 		loop {
+			let loc = self.inner.peek_loc();
 			let b = self.inner.get()?;
 
 			if let Some(b) = b {
@@ -106,53 +163,139 @@ impl<R: io::Read> FormReader<R> {
 			}
 
 			match b {
-				Some(b'(') => bookend_stack.push(SExpBookendStyle::Parentheses),
-				Some(b'[') => bookend_stack.push(SExpBookendStyle::SquareBrackets),
-				Some(b'{') => bookend_stack.push(SExpBookendStyle::CurlyBraces),
+				None => handle_eof!(),
+				Some(b) if in_comment => in_comment = b != b'\n' && b != b'\r',
+				Some(b) if in_string => in_string = !self.ends_string(b, &mut bytes)?,
+				Some(b'"') => in_string = true,
+				Some(b';') => in_comment = true,
+				Some(b'(') => push_bookend!(SExpBookendStyle::Parentheses, loc),
+				Some(b'[') => push_bookend!(SExpBookendStyle::SquareBrackets, loc),
+				Some(b'{') => push_bookend!(SExpBookendStyle::CurlyBraces, loc),
 				Some(b')') => pop_bookend!(SExpBookendStyle::Parentheses),
 				Some(b']') => pop_bookend!(SExpBookendStyle::SquareBrackets),
 				Some(b'}') => pop_bookend!(SExpBookendStyle::CurlyBraces),
-				None => handle_eof!(),
 				Some(_) => {}
 			}
 		}
 	}
 	fn get_atom_without_whitespace_prefix(&mut self, position: Loc) -> Result<Option<(String, Loc)>> {
 		let mut bytes = Vec::default();
+		let mut in_string = false;
 		loop {
-			match self.inner.get()? {
-				Some(b) if !Self::is_whitespace_byte(b) => {
+			// the whitespace after the atom is left for `skip_whitespace_prefix` to count
+			match self.inner.peek() {
+				Some(b) if in_string => {
+					_ = self.inner.get()?;
 					bytes.push(b);
+					in_string = !self.ends_string(b, &mut bytes)?;
 				}
-				_ => {
-					let string = String::from_utf8(bytes).map_err(|e| {
-						SexpfmtError::form_reader_error(
-							"Invalid UTF-8 in atom",
-							Some(position),
-							Some(Box::new(e)),
-						)
-					})?;
-					return Ok(Some((string, position)));
+				Some(b) if !Self::is_whitespace_byte(b) => {
+					bytes.push(b);
+					_ = self.inner.get()?;
+					in_string = b == b'"';
 				}
+				_ => return Self::into_form(bytes, position, "Invalid UTF-8 in atom"),
 			}
 		}
 	}
+	// A comment runs to the end of the line, which is left for `skip_whitespace_prefix` to count.
+	fn get_comment_without_whitespace_prefix(
+		&mut self,
+		position: Loc,
+	) -> Result<Option<(String, Loc)>> {
+		let mut bytes = Vec::default();
+		while let Some(b) = self.inner.peek().filter(|&b| b != b'\n' && b != b'\r') {
+			bytes.push(b);
+			_ = self.inner.get()?;
+		}
+		Self::into_form(bytes, position, "Invalid UTF-8 in comment")
+	}
+	// Whether byte `b`, just read and pushed onto `bytes` inside a string, ends it. An escaped quote is read along with
+	// its backslash, and a line break ends the string as far as the reader is concerned, leaving the error to the parser.
+	fn ends_string(&mut self, b: u8, bytes: &mut Vec<u8>) -> Result<bool> {
+		match b {
+			b'\\' if self.inner.peek() == Some(b'"') => {
+				bytes.extend(self.inner.get()?);
+				Ok(false)
+			}
+			b'"' | b'\n' | b'\r' => Ok(true),
+			_ => Ok(false),
+		}
+	}
+	fn into_form(bytes: Vec<u8>, position: Loc, message: &str) -> Result<Option<(String, Loc)>> {
+		let string = String::from_utf8(bytes)
+			.map_err(|e| SexpfmtError::form_reader_error(message, Some(position), Some(Box::new(e))))?;
+		Ok(Some((string, position)))
+	}
+}
+
+/// A kind of line break.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LineEnding {
+	Lf,
+	CrLf,
+	Cr,
+}
+impl LineEnding {
+	pub fn as_str(self) -> &'static str {
+		match self {
+			LineEnding::Lf => "\n",
+			LineEnding::CrLf => "\r\n",
+			LineEnding::Cr => "\r",
+		}
+	}
+	/// The first line ending in `text`, if any.
+	pub fn detect(text: &str) -> Option<Self> {
+		let i = text.find(['\n', '\r'])?;
+		Some(match &text.as_bytes()[i..] {
+			[b'\r', b'\n', ..] => LineEnding::CrLf,
+			[b'\r', ..] => LineEnding::Cr,
+			_ => LineEnding::Lf,
+		})
+	}
+	/// Replaces the `\n` line breaks of `text` with this line ending.
+	pub fn apply(self, text: String) -> String {
+		match self {
+			LineEnding::Lf => text,
+			_ => text.replace('\n', self.as_str()),
+		}
+	}
 }
 
+const BOM: [u8; 3] = [0xEF, 0xBB, 0xBF];
+
 struct ByteReader<R: io::Read> {
 	inner: R,
 	peek: Option<u8>,
 	peek_loc: Loc,
+	// bytes read ahead of `peek`, next last
+	pushed_back: Vec<u8>,
+	line_ending: Option<LineEnding>,
+	has_bom: bool,
 }
 
 impl<R: io::Read> ByteReader<R> {
-	fn new(inner: R) -> io::Result<Self> {
+	fn new(inner: R, start: Loc) -> io::Result<Self> {
 		let mut v = Self {
 			inner,
 			peek: None,
-			peek_loc: Loc::new(0, 1, 1),
+			peek_loc: start,
+			pushed_back: Vec::new(),
+			line_ending: None,
+			has_bom: false,
 		};
 		assert_eq!(None, v.get()?);
+		if start.offset() == 0 && v.peek == Some(BOM[0]) {
+			let rest = [v.get_without_peek()?, v.get_without_peek()?];
+			if rest == [Some(BOM[1]), Some(BOM[2])] {
+				// the byte order mark takes up bytes, but no columns
+				v.has_bom = true;
+				v.peek = v.get_without_peek()?;
+				v.peek_loc = Loc::new(start.offset() + BOM.len(), start.line(), start.column());
+			} else {
+				v.pushed_back.extend(rest.into_iter().rev().flatten());
+			}
+		}
 		Ok(v)
 	}
 }
@@ -164,12 +307,23 @@ impl<R: io::Read> ByteReader<R> {
 
 		// Update position tracking for the byte we're returning
 		if let Some(byte) = v {
-			self.peek_loc = Self::next_loc(self.peek_loc, byte);
+			self.peek_loc = Self::next_loc(self.peek_loc, byte, self.peek);
+			if self.line_ending.is_none() {
+				self.line_ending = match (byte, self.peek) {
+					(b'\r', Some(b'\n')) => Some(LineEnding::CrLf),
+					(b'\r', _) => Some(LineEnding::Cr),
+					(b'\n', _) => Some(LineEnding::Lf),
+					_ => None,
+				};
+			}
 		}
 
 		Ok(v)
 	}
 	fn get_without_peek(&mut self) -> io::Result<Option<u8>> {
+		if let Some(b) = self.pushed_back.pop() {
+			return Ok(Some(b));
+		}
 		let mut buf: [u8; 1] = Default::default();
 		let n = self.inner.read(&mut buf)?;
 		if n > 0 {
@@ -186,9 +340,10 @@ impl<R: io::Read> ByteReader<R> {
 		self.peek_loc
 	}
 
-	fn next_loc(old: Loc, byte: u8) -> Loc {
+	// A `\r` is a line break unless it is followed by a `\n`, which then makes up the line break of a `\r\n`.
+	fn next_loc(old: Loc, byte: u8, next: Option<u8>) -> Loc {
 		let new_offset = old.offset() + 1;
-		if byte == b'\n' {
+		if byte == b'\n' || (byte == b'\r' && next != Some(b'\n')) {
 			Loc::new(new_offset, old.line() + 1, 1)
 		} else {
 			Loc::new(new_offset, old.line(), old.column() + 1)
@@ -299,4 +454,53 @@ mod tests {
 		// EOF
 		assert_eq!(r.get().unwrap(), None);
 	}
+
+	#[test]
+	fn test_form_reader_max_depth() {
+		let mut r = FormReader::new(StringReader::new("[(a)] ([{b}])")).unwrap();
+		r.set_max_depth(2);
+		assert_eq!(r.get().unwrap().unwrap().0, "[(a)]");
+		let err = r.get().unwrap_err();
+		assert!(matches!(err, SexpfmtError::InvalidInput { .. }));
+		assert_eq!(err.position(), Some(Loc::new(8, 1, 9)));
+	}
+
+	#[test]
+	fn test_form_reader_blank_lines() {
+		let mut r = FormReader::new(StringReader::new("\n\n(a\n\n b) c\n\n\n  d\n")).unwrap();
+		let mut blank_lines = Vec::new();
+		while r.peek_loc().unwrap().is_some() {
+			blank_lines.push(r.peek_blank_lines().unwrap());
+			r.get().unwrap();
+		}
+		// blank lines within a form do not count
+		assert_eq!(blank_lines, vec![2, 0, 2]);
+		assert_eq!(r.peek_blank_lines().unwrap(), 0);
+	}
+
+	#[test]
+	fn test_form_reader_line_endings() {
+		let mut r = FormReader::new(StringReader::new("\u{FEFF}a\r\n\r\n(b\r\n c)\rd")).unwrap();
+		assert!(r.has_bom());
+		assert_eq!(
+			r.get().unwrap().unwrap(),
+			("a".to_string(), Loc::new(3, 1, 1))
+		);
+		assert_eq!(r.peek_line_breaks().unwrap(), 2);
+		assert_eq!(r.line_ending(), Some(LineEnding::CrLf));
+		assert_eq!(r.get().unwrap().unwrap().1, Loc::new(8, 3, 1));
+		assert_eq!(r.peek_line_breaks().unwrap(), 1);
+		assert_eq!(r.get().unwrap().unwrap().1, Loc::new(16, 5, 1));
+		assert_eq!(
+			Loc::at_offset("\u{FEFF}a\r\n\r\n(b\r\n c)\rd", 16),
+			Loc::new(16, 5, 1)
+		);
+
+		// anything else starting like a byte order mark is read as it is
+		let mut r = FormReader::new(StringReader::new("\u{FE00}")).unwrap();
+		assert!(!r.has_bom());
+		assert_eq!(r.get().unwrap().unwrap().0, "\u{FE00}");
+		assert_eq!(LineEnding::detect("a\rb\r\n"), Some(LineEnding::Cr));
+		assert_eq!(LineEnding::detect("a"), None);
+	}
 }
@@ -0,0 +1,266 @@
+// Re-indentation, like Emacs' `indent-region`: the line breaks and comments of the text are kept as they are, and only
+// the whitespace at either end of each line is replaced. Each line is indented for the first token on it, following
+// `PrinterConfig::layout`: by the indent width per level of nesting in the fixed-step layout, and relative to the
+// opening bracket of the innermost open list in the Lisp layout, where the columns of the tokens before a line are
+// those they have once their own lines are re-indented.
+
+use super::*;
+
+/// Re-indents every line of `text`, and removes trailing whitespace, keeping every line break where it is.
+pub fn reindent_str(text: &str, config: &PrinterConfig) -> Result<String> {
+	config.check()?;
+	let (bom, text) = match text.strip_prefix('\u{FEFF}') {
+		Some(rest) => ("\u{FEFF}", rest),
+		None => ("", text),
+	};
+	// reports unbalanced brackets and unterminated strings
+	check_balanced(text, config.max_depth)?;
+
+	let mut res = String::with_capacity(bom.len() + text.len());
+	res.push_str(bom);
+	let mut scanner = Scanner::new(text);
+	let mut next_token = scanner.next_token()?;
+	let mut stack: Vec<OpenList> = Vec::new();
+	let mut line = 0;
+	let mut line_start = 0;
+	while line_start < text.len() {
+		let (line_end, next_line_start) = line_bounds(text, line_start);
+		let content = text[line_start..line_end].trim();
+		if !content.is_empty() {
+			let content_start = line_end - text[line_start..line_end].trim_start().len();
+			let first_token = next_token
+				.as_ref()
+				.filter(|(offset, _)| *offset == content_start)
+				.map(|(_, token)| token);
+			let indent = line_indent(&stack, first_token, config);
+			indent.push_to(&mut res);
+			res.push_str(content);
+
+			while let Some((offset, token)) = next_token.take_if(|(offset, _)| *offset < line_end) {
+				let column =
+					indent.width(config.tab_width()) + text[content_start..offset].chars().count() as i64;
+				match token {
+					Token::Open(_) => {
+						add_element(&mut stack, line, column, None, config);
+						stack.push(OpenList {
+							line_indent: indent,
+							column,
+							elements: 0,
+							head: None,
+							spec: None,
+							first_arg: None,
+						});
+					}
+					Token::Close(_) => {
+						stack.pop();
+					}
+					Token::Atom(end) => {
+						add_element(&mut stack, line, column, Some(&text[offset..end]), config)
+					}
+				}
+				next_token = scanner.next_token()?;
+			}
+		}
+		res.push_str(&text[line_end..next_line_start]);
+		line += 1;
+		line_start = next_line_start;
+	}
+	Ok(res)
+}
+
+// A list whose closing bracket is yet to come
+struct OpenList {
+	// the indentation of the line with the opening bracket
+	line_indent: Indent,
+	// the column of the opening bracket
+	column: i64,
+	elements: usize,
+	head: Option<Element>,
+	// how `Layout::Lisp` indents the list, if its head is a symbol
+	spec: Option<IndentSpec>,
+	first_arg: Option<Element>,
+}
+
+#[derive(Clone, Copy)]
+struct Element {
+	line: usize,
+	column: i64,
+}
+
+// Notes an element of the innermost open list starting at `column` of `line`, with its text if it is an atom
+fn add_element(
+	stack: &mut [OpenList],
+	line: usize,
+	column: i64,
+	atom: Option<&str>,
+	config: &PrinterConfig,
+) {
+	let Some(list) = stack.last_mut() else {
+		return;
+	};
+	let element = Some(Element { line, column });
+	match list.elements {
+		0 => {
+			list.head = element;
+			list.spec = atom
+				.filter(|s| AtomKind::classify(s) == AtomKind::Symbol)
+				.map(|s| {
+					config
+						.indent_specs
+						.get(s)
+						.copied()
+						.unwrap_or(IndentSpec::Hanging)
+				});
+		}
+		1 => list.first_arg = element,
+		_ => {}
+	}
+	list.elements += 1;
+}
+
+// The indentation of a line starting with `first_token`, or with a comment if `None`, within the lists of `stack`
+fn line_indent(stack: &[OpenList], first_token: Option<&Token>, config: &PrinterConfig) -> Indent {
+	let Some(list) = stack.last() else {
+		return Indent::default();
+	};
+	let depth = stack.len() as i64;
+	let indent_width = i64::from(config.indent_step());
+	let tab_width = config.tab_width();
+	let aligned = |column| list.line_indent.aligned(column, tab_width);
+	if let Some(Token::Close(_)) = first_token {
+		return match config.layout {
+			Layout::FixedStep => Indent::default().nested((depth - 1) * indent_width, tab_width),
+			Layout::Lisp => aligned(list.column),
+		};
+	}
+	match config.layout {
+		Layout::FixedStep => Indent::default().nested(depth * indent_width, tab_width),
+		Layout::Lisp => match (list.head, list.spec) {
+			(None, _) => aligned(list.column + 1),
+			// lists of data, not calls, are aligned under their first element
+			(Some(head), None) => aligned(head.column),
+			(Some(_), Some(IndentSpec::Special(n))) if list.elements <= n => {
+				aligned(list.column).nested(2 * indent_width, tab_width)
+			}
+			(Some(_), Some(IndentSpec::Special(_))) => {
+				aligned(list.column).nested(indent_width, tab_width)
+			}
+			(Some(head), Some(IndentSpec::Hanging)) => match list.first_arg {
+				Some(arg) if arg.line == head.line => aligned(arg.column),
+				_ => aligned(head.column),
+			},
+		},
+	}
+}
+
+// The end of the line starting at `start`, and the start of the next one
+fn line_bounds(text: &str, start: usize) -> (usize, usize) {
+	match text[start..].find(['\n', '\r']) {
+		Some(i) if text[start + i..].starts_with("\r\n") => (start + i, start + i + 2),
+		Some(i) => (start + i, start + i + 1),
+		None => (text.len(), text.len()),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_reindent_fixed_step() {
+		let text = "(a b   \n        c ; note\n(d\ne\n    )\n\n   ; trailing\n)\n  (f)";
+		assert_eq!(
+			reindent_str(text, &PrinterConfig::default()).unwrap(),
+			"(a b\n  c ; note\n  (d\n    e\n  )\n\n  ; trailing\n)\n(f)"
+		);
+	}
+
+	#[test]
+	fn test_reindent_lisp() {
+		let text = "(define (f x)\n(let ((a 1)\n(b 2))\n(foo a\nb)\n(bar\nc)\n[1\n2]))";
+		let config = PrinterConfig {
+			layout: Layout::Lisp,
+			closing_brackets: ClosingBrackets::OwnLine,
+			..PrinterConfig::default()
+		};
+		assert_eq!(
+			reindent_str(text, &config).unwrap(),
+			"(define (f x)
+  (let ((a 1)
+        (b 2))
+    (foo a
+         b)
+    (bar
+     c)
+    [1
+     2]))"
+		);
+		assert_eq!(
+			reindent_str("(let\n((a 1))\na\n)", &config).unwrap(),
+			"(let\n    ((a 1))\n  a\n)"
+		);
+		// one tab per level, with spaces for alignment after the tabs of the line aligned to
+		let config = PrinterConfig {
+			indent_style: IndentStyle::Tabs(4),
+			..config
+		};
+		assert_eq!(
+			reindent_str(text, &config).unwrap(),
+			"(define (f x)\n\t(let ((a 1)\n\t      (b 2))\n\t\t(foo a\n\t\t     b)\n\t\t(bar\n\t\t c)\n\t\t[1\n\t\t 2]))"
+		);
+	}
+
+	#[test]
+	fn test_reindent_keeps_line_endings_and_bom() {
+		let config = PrinterConfig {
+			indent_style: IndentStyle::Tabs(2),
+			..PrinterConfig::default()
+		};
+		assert_eq!(
+			reindent_str("\u{FEFF}(a\r\n (b\r\n c))  \r\n", &config).unwrap(),
+			"\u{FEFF}(a\r\n\t(b\r\n\t\tc))\r\n"
+		);
+		assert!(matches!(
+			reindent_str("(a\n  [b)", &config),
+			Err(SexpfmtError::MismatchedBookends { .. })
+		));
+		let config = PrinterConfig {
+			max_depth: 2,
+			..PrinterConfig::default()
+		};
+		assert_eq!(reindent_str("((a)\n(b))", &config).unwrap(), "((a)\n  (b))");
+		assert!(matches!(
+			reindent_str("(((a)))", &config),
+			Err(SexpfmtError::InvalidInput { .. })
+		));
+	}
+
+	#[test]
+	fn test_reindent_formatted_text() {
+		// re-indenting formatted text changes nothing
+		let configs = [
+			PrinterConfig {
+				margin_width: 20,
+				multiline_heads: MultilineHeads::Block,
+				..PrinterConfig::default()
+			},
+			PrinterConfig {
+				margin_width: 30,
+				layout: Layout::Lisp,
+				closing_brackets: ClosingBrackets::OwnLine,
+				..PrinterConfig::default()
+			},
+		];
+		for entry in std::fs::read_dir("test").unwrap() {
+			let path = entry.unwrap().path();
+			if path.extension().is_some_and(|ext| ext == "sexp") {
+				let text = std::fs::read_to_string(&path).unwrap();
+				for config in configs.iter() {
+					if let Ok(formatted) = format_str(&text, config) {
+						assert_eq!(reindent_str(&formatted, config).unwrap(), formatted);
+					}
+				}
+			}
+		}
+	}
+}
@@ -0,0 +1,516 @@
+// Structural rewriting using pattern templates, in the style of Scheme's `syntax-rules`.
+// A rule is written as three S-expressions, `PATTERN => TEMPLATE`, for example:
+//   (old-name ?x ...) => (new-name ?x ...)
+// In a pattern:
+// - an atom starting with '?' is a pattern variable that matches any subtree.
+// - `_` matches any subtree without binding it.
+// - any other atom matches only itself.
+// - a list matches lists with the same bookends whose elements match its elements one by one.
+// - an element followed by `...` matches zero or more consecutive elements. Variables inside it are bound to the
+//   sequence of subtrees they matched, and must be followed by as many `...` in the template.
+// Rules are applied bottom-up: the elements of a list are rewritten before the list itself. Each node is rewritten by
+// the first rule that matches it, and the result is not rewritten again, so rules such as `(a ?x) => (a (a ?x))`
+// terminate.
+
+use super::*;
+
+use std::collections::HashMap;
+use std::ops::Range;
+use std::str::FromStr;
+
+const ELLIPSIS: &str = "...";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RewriteRules {
+	rules: Vec<RewriteRule>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct RewriteRule {
+	pattern: SExp,
+	template: SExp,
+}
+
+#[derive(Clone, Debug)]
+enum Binding {
+	One(SExp),
+	Many(Vec<Binding>),
+}
+
+type Bindings = HashMap<String, Binding>;
+
+impl RewriteRules {
+	/// Parses any number of `PATTERN => TEMPLATE` rules.
+	pub fn parse(text: &str) -> Result<Self> {
+		let mut forms = parse_form_with_locs(text.to_string(), Loc::new(0, 1, 1))?.into_iter();
+		let mut rules = Vec::new();
+		while let Some((pattern, pattern_locs)) = forms.next() {
+			let arrow = forms.next();
+			if !matches!(&arrow, Some((SExp::Atom(s), _)) if s == "=>") {
+				return Err(SexpfmtError::invalid_input(
+					"Expected '=>' after rewrite pattern",
+					arrow.map_or(pattern_locs.loc, |(_, locs)| locs.loc),
+				));
+			}
+			let Some((template, _)) = forms.next() else {
+				return Err(SexpfmtError::invalid_input(
+					"Expected template after '=>'",
+					pattern_locs.loc,
+				));
+			};
+
+			let mut var_depths = HashMap::new();
+			collect_vars(&pattern, 0, &mut var_depths)
+				.and_then(|_| check_template(&template, 0, &var_depths))
+				.map_err(|message| SexpfmtError::invalid_input(message, pattern_locs.loc))?;
+			rules.push(RewriteRule { pattern, template });
+		}
+		Ok(Self { rules })
+	}
+
+	/// Rewrites every subtree of `sexp` that matches one of the rules.
+	pub fn rewrite(&self, sexp: SExp) -> SExp {
+		let sexp = match sexp {
+			SExp::List(es, bookend_style) => SExp::List(
+				es.into_iter().map(|e| self.rewrite(e)).collect(),
+				bookend_style,
+			),
+			sexp => sexp,
+		};
+		self.apply(&sexp).unwrap_or(sexp)
+	}
+
+	/// Returns an edit for each top-level form of `text` that the rules change: the byte span of the form, and the
+	/// text to replace it with. Within the form, each rewritten subtree replaces the text it was parsed from, and the
+	/// result is formatted as `format_str` would, so that the comments outside the rewritten subtrees are kept.
+	pub fn rewrite_edits(
+		&self,
+		text: &str,
+		config: &PrinterConfig,
+	) -> Result<Vec<(Range<usize>, String)>> {
+		config.check()?;
+		let line_ending = config
+			.line_ending
+			.or(LineEnding::detect(text))
+			.unwrap_or(LineEnding::Lf);
+		let mut reader = FormReader::new(text.as_bytes())?;
+		reader.set_max_depth(config.max_depth);
+		let mut edits = Vec::new();
+		while let Some((s, position)) = reader.get()? {
+			// comments are read as forms that parse to nothing
+			if s.starts_with(';') {
+				continue;
+			}
+			let span = position.offset()..position.offset() + s.len();
+			// rewritten where the form is parsed, so that deeply nested forms are dropped with the stack for it
+			let subtree_edits = with_located_form(s.clone(), position, config.max_depth, |form| {
+				let mut subtree_edits = Vec::new();
+				for (sexp, locs) in form {
+					self.rewrite_located(sexp, &locs, span.start, &mut subtree_edits);
+				}
+				subtree_edits
+			})?;
+			if subtree_edits.is_empty() {
+				continue;
+			}
+			let mut rewritten = String::with_capacity(s.len());
+			let mut copied_up_to = 0;
+			for (subtree_span, subtree_text) in subtree_edits {
+				rewritten.push_str(&s[copied_up_to..subtree_span.start]);
+				rewritten.push_str(&subtree_text);
+				copied_up_to = subtree_span.end;
+			}
+			rewritten.push_str(&s[copied_up_to..]);
+			let formatted: String = with_located_form(rewritten, position, config.max_depth, |form| {
+				form
+					.iter()
+					.map(|(sexp, locs)| format_located_sexp(sexp, locs, config))
+					.collect()
+			})?;
+			edits.push((span, line_ending.apply(formatted)));
+		}
+		Ok(edits)
+	}
+
+	/// Rewrites every top-level form of `text` that the rules change, as `rewrite_edits` does, leaving everything else
+	/// as written.
+	pub fn rewrite_str(&self, text: &str, config: &PrinterConfig) -> Result<String> {
+		let mut res = String::with_capacity(text.len());
+		let mut copied_up_to = 0;
+		for (span, rewritten) in self.rewrite_edits(text, config)?.into_iter() {
+			res.push_str(&text[copied_up_to..span.start]);
+			res.push_str(&rewritten);
+			copied_up_to = span.end;
+		}
+		res.push_str(&text[copied_up_to..]);
+		Ok(res)
+	}
+
+	// Rewrites `sexp`, parsed with `locs` from a form starting at byte `form_start`, as `rewrite` does, pushing an edit
+	// for each outermost subtree that a rule rewrote: its byte span within the form, and its rewritten text. Returns
+	// the result, and whether it differs.
+	fn rewrite_located(
+		&self,
+		sexp: SExp,
+		locs: &LocTree,
+		form_start: usize,
+		edits: &mut Vec<(Range<usize>, String)>,
+	) -> (SExp, bool) {
+		let edits_before = edits.len();
+		let (sexp, changed) = match sexp {
+			SExp::List(es, bookend_style) => {
+				let mut changed = false;
+				let es = es
+					.into_iter()
+					.zip(locs.children.iter())
+					.map(|(e, locs)| {
+						let (e, e_changed) = self.rewrite_located(e, locs, form_start, edits);
+						changed |= e_changed;
+						e
+					})
+					.collect();
+				(SExp::List(es, bookend_style), changed)
+			}
+			sexp => (sexp, false),
+		};
+		match self.apply(&sexp) {
+			// a rule that gives back what it matched changes nothing
+			Some(res) if changed || res != sexp => {
+				edits.truncate(edits_before);
+				let span = locs.loc.offset() - form_start..locs.end.offset() - form_start;
+				edits.push((span, res.to_string()));
+				(res, true)
+			}
+			_ => (sexp, changed),
+		}
+	}
+
+	// Rewrites `sexp` itself by the first rule that matches it, if any.
+	fn apply(&self, sexp: &SExp) -> Option<SExp> {
+		self.rules.iter().find_map(|rule| {
+			let mut bindings = Bindings::new();
+			if match_pattern(&rule.pattern, sexp, &mut bindings) {
+				expand(&rule.template, &bindings)
+			} else {
+				None
+			}
+		})
+	}
+}
+
+impl FromStr for RewriteRules {
+	type Err = SexpfmtError;
+
+	fn from_str(s: &str) -> Result<Self> {
+		Self::parse(s)
+	}
+}
+
+fn is_var(s: &str) -> bool {
+	s.len() > 1 && s.starts_with('?')
+}
+
+fn is_ellipsis(sexp: Option<&SExp>) -> bool {
+	matches!(sexp, Some(SExp::Atom(s)) if s == ELLIPSIS)
+}
+
+// Views a list or null as its bookend style and elements.
+fn as_seq(sexp: &SExp) -> Option<(&[SExp], SExpBookendStyle)> {
+	match sexp {
+		SExp::List(es, bookend_style) => Some((es, *bookend_style)),
+		SExp::Null(bookend_style) => Some((&[], *bookend_style)),
+		SExp::Atom(_) => None,
+	}
+}
+
+fn collect_vars(
+	pattern: &SExp,
+	depth: usize,
+	var_depths: &mut HashMap<String, usize>,
+) -> std::result::Result<(), String> {
+	match pattern {
+		SExp::Atom(s) if is_var(s) => {
+			if var_depths.insert(s.to_string(), depth).is_some() {
+				return Err(format!("Pattern variable '{}' is bound more than once", s));
+			}
+			Ok(())
+		}
+		SExp::Atom(s) if s == ELLIPSIS => Err("'...' must follow a pattern element".to_string()),
+		SExp::Atom(_) | SExp::Null(_) => Ok(()),
+		SExp::List(ps, _) => {
+			let mut ellipsis_count = 0;
+			let mut i = 0;
+			while i < ps.len() {
+				if is_ellipsis(ps.get(i + 1)) {
+					ellipsis_count += 1;
+					collect_vars(&ps[i], depth + 1, var_depths)?;
+					i += 2;
+				} else {
+					collect_vars(&ps[i], depth, var_depths)?;
+					i += 1;
+				}
+			}
+			if ellipsis_count > 1 {
+				return Err("At most one '...' is allowed per list in a pattern".to_string());
+			}
+			Ok(())
+		}
+	}
+}
+
+fn check_template(
+	template: &SExp,
+	depth: usize,
+	var_depths: &HashMap<String, usize>,
+) -> std::result::Result<(), String> {
+	match template {
+		SExp::Atom(s) if is_var(s) => match var_depths.get(s.as_str()) {
+			None => Err(format!(
+				"Template variable '{}' is not bound by the pattern",
+				s
+			)),
+			Some(&var_depth) if var_depth != depth => Err(format!(
+				"Template variable '{}' is used under {} '...', but bound under {}",
+				s, depth, var_depth
+			)),
+			Some(_) => Ok(()),
+		},
+		SExp::Atom(s) if s == ELLIPSIS => Err("'...' must follow a template element".to_string()),
+		SExp::Atom(_) | SExp::Null(_) => Ok(()),
+		SExp::List(ts, _) => {
+			let mut i = 0;
+			while i < ts.len() {
+				if is_ellipsis(ts.get(i + 1)) {
+					let mut vars = Vec::new();
+					template_vars(&ts[i], &mut vars);
+					if !vars
+						.iter()
+						.any(|v| var_depths.get(*v).is_some_and(|&d| d > depth))
+					{
+						return Err(
+							"'...' in template must follow an element using a repeated variable".to_string(),
+						);
+					}
+					check_template(&ts[i], depth + 1, var_depths)?;
+					i += 2;
+				} else {
+					check_template(&ts[i], depth, var_depths)?;
+					i += 1;
+				}
+			}
+			Ok(())
+		}
+	}
+}
+
+fn template_vars<'a>(template: &'a SExp, out: &mut Vec<&'a str>) {
+	match template {
+		SExp::Atom(s) if is_var(s) => out.push(s),
+		SExp::List(ts, _) => {
+			for t in ts.iter() {
+				template_vars(t, out);
+			}
+		}
+		_ => {}
+	}
+}
+
+fn match_pattern(pattern: &SExp, sexp: &SExp, bindings: &mut Bindings) -> bool {
+	match pattern {
+		SExp::Atom(p) if is_var(p) => {
+			bindings.insert(p.to_string(), Binding::One(sexp.clone()));
+			true
+		}
+		SExp::Atom(p) if p == "_" => true,
+		SExp::Atom(p) => matches!(sexp, SExp::Atom(s) if s == p),
+		SExp::List(..) | SExp::Null(_) => {
+			let (ps, p_style) = as_seq(pattern).unwrap();
+			match as_seq(sexp) {
+				Some((es, e_style)) if e_style == p_style => match_seq(ps, es, bindings),
+				_ => false,
+			}
+		}
+	}
+}
+
+fn match_seq(ps: &[SExp], es: &[SExp], bindings: &mut Bindings) -> bool {
+	let Some(k) = (0..ps.len()).find(|&k| is_ellipsis(ps.get(k + 1))) else {
+		return ps.len() == es.len()
+			&& ps
+				.iter()
+				.zip(es.iter())
+				.all(|(p, e)| match_pattern(p, e, bindings));
+	};
+
+	let (before, repeated, after) = (&ps[..k], &ps[k], &ps[k + 2..]);
+	if es.len() < before.len() + after.len() {
+		return false;
+	}
+	let (es_before, rest) = es.split_at(before.len());
+	let (es_repeated, es_after) = rest.split_at(rest.len() - after.len());
+	if !match_seq(before, es_before, bindings) || !match_seq(after, es_after, bindings) {
+		return false;
+	}
+
+	let mut repetitions = Vec::with_capacity(es_repeated.len());
+	for e in es_repeated.iter() {
+		let mut repetition = Bindings::new();
+		if !match_pattern(repeated, e, &mut repetition) {
+			return false;
+		}
+		repetitions.push(repetition);
+	}
+	let mut vars = HashMap::new();
+	collect_vars(repeated, 0, &mut vars).unwrap();
+	for var in vars.into_keys() {
+		let seq = repetitions
+			.iter_mut()
+			.map(|repetition| repetition.remove(&var).unwrap())
+			.collect();
+		bindings.insert(var, Binding::Many(seq));
+	}
+	true
+}
+
+// Returns `None` if the sequences bound to variables under the same '...' have different lengths.
+fn expand(template: &SExp, bindings: &Bindings) -> Option<SExp> {
+	match template {
+		SExp::Atom(t) if is_var(t) => match bindings.get(t.as_str()) {
+			Some(Binding::One(sexp)) => Some(sexp.clone()),
+			_ => None,
+		},
+		SExp::Atom(_) | SExp::Null(_) => Some(template.clone()),
+		SExp::List(ts, bookend_style) => {
+			let mut res = Vec::with_capacity(ts.len());
+			let mut i = 0;
+			while i < ts.len() {
+				if is_ellipsis(ts.get(i + 1)) {
+					expand_repeated(&ts[i], bindings, &mut res)?;
+					i += 2;
+				} else {
+					res.push(expand(&ts[i], bindings)?);
+					i += 1;
+				}
+			}
+			if res.is_empty() {
+				Some(SExp::Null(*bookend_style))
+			} else {
+				Some(SExp::List(res, *bookend_style))
+			}
+		}
+	}
+}
+
+fn expand_repeated(template: &SExp, bindings: &Bindings, res: &mut Vec<SExp>) -> Option<()> {
+	let mut vars = Vec::new();
+	template_vars(template, &mut vars);
+	let seqs: Vec<(&str, &Vec<Binding>)> = vars
+		.into_iter()
+		.filter_map(|var| match bindings.get(var) {
+			Some(Binding::Many(seq)) => Some((var, seq)),
+			_ => None,
+		})
+		.collect();
+	let len = seqs.first()?.1.len();
+	if seqs.iter().any(|(_, seq)| seq.len() != len) {
+		return None;
+	}
+	for k in 0..len {
+		let mut repetition = bindings.clone();
+		for (var, seq) in seqs.iter() {
+			repetition.insert(var.to_string(), seq[k].clone());
+		}
+		res.push(expand(template, &repetition)?);
+	}
+	Some(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn rewrite(rules: &str, text: &str) -> Vec<SExp> {
+		let rules: RewriteRules = rules.parse().unwrap();
+		parse_form(text.to_string(), Loc::new(0, 1, 1))
+			.unwrap()
+			.into_iter()
+			.map(|sexp| rules.rewrite(sexp))
+			.collect()
+	}
+
+	fn parse(text: &str) -> Vec<SExp> {
+		parse_form(text.to_string(), Loc::new(0, 1, 1)).unwrap()
+	}
+
+	#[test]
+	fn test_rewrite_rename_head() {
+		assert_eq!(
+			rewrite(
+				"(old-name ?x ...) => (new-name ?x ...)",
+				"(config (old-name 1 (old-name)) [old-name 2])"
+			),
+			parse("(config (new-name 1 (new-name)) [old-name 2])")
+		);
+	}
+
+	#[test]
+	fn test_rewrite_nested_ellipsis() {
+		assert_eq!(
+			rewrite(
+				"(let ((?name ?value) ...) ?body ...) => ((lambda (?name ...) ?body ...) ?value ...)",
+				"(let ((x 1) (y 2)) (print x) (+ x y))"
+			),
+			parse("((lambda (x y) (print x) (+ x y)) 1 2)")
+		);
+	}
+
+	#[test]
+	fn test_rewrite_first_matching_rule_bottom_up() {
+		assert_eq!(
+			rewrite(
+				"(size ?s) => (size ?s ?s) (_ ?x) => (wrapped ?x)",
+				"(a (size 1))"
+			),
+			parse("(wrapped (size 1 1))")
+		);
+		assert_eq!(
+			rewrite("(a ?x) => (a (a ?x))", "(a (a 1))"),
+			parse("(a (a (a (a 1))))")
+		);
+	}
+
+	#[test]
+	fn test_rewrite_rule_errors() {
+		assert!(matches!(
+			RewriteRules::parse("(a ?x) (b ?x)"),
+			Err(SexpfmtError::InvalidInput { .. })
+		));
+		assert!(matches!(
+			RewriteRules::parse("(a ?x) => (b ?y)"),
+			Err(SexpfmtError::InvalidInput { .. })
+		));
+		assert!(matches!(
+			RewriteRules::parse("(a ?x ...) => (b ?x)"),
+			Err(SexpfmtError::InvalidInput { .. })
+		));
+		assert!(matches!(
+			RewriteRules::parse("(a ?x ... ?y ...) => (b)"),
+			Err(SexpfmtError::InvalidInput { .. })
+		));
+	}
+
+	#[test]
+	fn test_rewrite_str() {
+		let rules: RewriteRules = "(old ?x ...) => (new ?x ...)".parse().unwrap();
+		let config = PrinterConfig::default();
+		let text =
+			";; header\n(keep   this)  ; as is\n\n(a ; first\n  (old 1 2)\n  ; last\n  (b (old 3)))\n";
+		assert_eq!(
+			rules.rewrite_str(text, &config).unwrap(),
+			";; header\n(keep   this)  ; as is\n\n(a ; first\n  (new 1 2)\n  ; last\n  (b (new 3)))\n"
+		);
+		// a rule that gives back what it matched leaves the form as written
+		let rules: RewriteRules = "(keep ?x) => (keep ?x)".parse().unwrap();
+		assert_eq!(rules.rewrite_str(text, &config).unwrap(), text);
+	}
+}
@@ -1,7 +1,12 @@
-#[derive(Clone, Debug, PartialEq, Eq)]
+use super::*;
+
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Clone, Debug)]
 pub enum SExp {
 	List(Vec<SExp>, SExpBookendStyle),
-	Atom(String),
+	Atom(Atom),
 	Null(SExpBookendStyle),
 }
 
@@ -11,3 +16,380 @@ pub enum SExpBookendStyle {
 	SquareBrackets,
 	CurlyBraces,
 }
+
+impl SExpBookendStyle {
+	/// The style of an opening or closing bookend character.
+	pub fn from_char(c: char) -> Option<Self> {
+		match c {
+			'(' | ')' => Some(SExpBookendStyle::Parentheses),
+			'[' | ']' => Some(SExpBookendStyle::SquareBrackets),
+			'{' | '}' => Some(SExpBookendStyle::CurlyBraces),
+			_ => None,
+		}
+	}
+
+	/// The opening and closing characters.
+	pub fn bookends(self) -> (char, char) {
+		match self {
+			SExpBookendStyle::Parentheses => ('(', ')'),
+			SExpBookendStyle::SquareBrackets => ('[', ']'),
+			SExpBookendStyle::CurlyBraces => ('{', '}'),
+		}
+	}
+}
+
+impl SExp {
+	pub fn atom<S: Into<String>>(s: S) -> Self {
+		SExp::Atom(Atom::new(s))
+	}
+
+	/// A parenthesized list; `SExp::Null` if `elems` is empty, as the parser would produce.
+	pub fn list<I: IntoIterator<Item = SExp>>(elems: I) -> Self {
+		Self::list_with_style(elems, SExpBookendStyle::Parentheses)
+	}
+
+	pub fn list_with_style<I: IntoIterator<Item = SExp>>(elems: I, style: SExpBookendStyle) -> Self {
+		let elems: Vec<SExp> = elems.into_iter().collect();
+		if elems.is_empty() {
+			SExp::Null(style)
+		} else {
+			SExp::List(elems, style)
+		}
+	}
+
+	/// `()`.
+	pub fn null() -> Self {
+		SExp::Null(SExpBookendStyle::Parentheses)
+	}
+
+	pub fn is_atom(&self) -> bool {
+		matches!(self, SExp::Atom(_))
+	}
+
+	/// Whether this is a list, empty or not.
+	pub fn is_list(&self) -> bool {
+		!self.is_atom()
+	}
+
+	/// Whether this is an empty list.
+	pub fn is_null(&self) -> bool {
+		self.as_list().is_some_and(<[SExp]>::is_empty)
+	}
+
+	pub fn as_atom(&self) -> Option<&str> {
+		match self {
+			SExp::Atom(s) => Some(s),
+			_ => None,
+		}
+	}
+
+	/// The elements of a list, which are none for `SExp::Null`.
+	pub fn as_list(&self) -> Option<&[SExp]> {
+		match self {
+			SExp::List(es, _) => Some(es),
+			SExp::Null(_) => Some(&[]),
+			SExp::Atom(_) => None,
+		}
+	}
+
+	/// The elements of a list, to be changed in place. `SExp::Null` becomes an empty `SExp::List`, so that elements can
+	/// be pushed onto it.
+	pub fn as_list_mut(&mut self) -> Option<&mut Vec<SExp>> {
+		if let SExp::Null(style) = *self {
+			*self = SExp::List(Vec::new(), style);
+		}
+		match self {
+			SExp::List(es, _) => Some(es),
+			_ => None,
+		}
+	}
+
+	pub fn bookend_style(&self) -> Option<SExpBookendStyle> {
+		match self {
+			SExp::List(_, style) | SExp::Null(style) => Some(*style),
+			SExp::Atom(_) => None,
+		}
+	}
+
+	/// The first element of a non-empty list.
+	pub fn head(&self) -> Option<&SExp> {
+		self.as_list()?.first()
+	}
+
+	/// The elements of a non-empty list after the first.
+	pub fn tail(&self) -> Option<&[SExp]> {
+		self.as_list()?.get(1..)
+	}
+
+	/// How deeply lists are nested within this expression: 0 for an atom, and 1 for a list of atoms.
+	pub fn depth(&self) -> usize {
+		let mut deepest = 0;
+		let mut stack = vec![(self, 0)];
+		while let Some((sexp, depth)) = stack.pop() {
+			if sexp.is_list() {
+				deepest = deepest.max(depth + 1);
+				stack.extend(sexp.iter().map(|e| (e, depth + 1)));
+			}
+		}
+		deepest
+	}
+
+	/// The number of elements of a list; atoms have none.
+	pub fn len(&self) -> Option<usize> {
+		self.as_list().map(<[SExp]>::len)
+	}
+
+	/// Whether a list is empty; atoms are neither.
+	pub fn is_empty(&self) -> Option<bool> {
+		self.as_list().map(<[SExp]>::is_empty)
+	}
+
+	/// Iterates over the elements of a list; atoms have none.
+	pub fn iter(&self) -> std::slice::Iter<'_, SExp> {
+		self.as_list().unwrap_or_default().iter()
+	}
+
+	/// Looks up `key` in a property list, such as `(:name "x" :size 3)` or `(item :name "x")`: returns the element
+	/// following the first atom spelled `key`.
+	pub fn get(&self, key: &str) -> Option<&SExp> {
+		let es = self.as_list()?;
+		let i = es.iter().position(|e| e.as_atom() == Some(key))?;
+		es.get(i + 1)
+	}
+}
+
+impl PartialEq for SExp {
+	/// An empty `SExp::List` is equal to the `SExp::Null` of the same style, as they print the same.
+	fn eq(&self, other: &Self) -> bool {
+		match (self, other) {
+			(SExp::Atom(a), SExp::Atom(b)) => a == b,
+			(SExp::Atom(_), _) | (_, SExp::Atom(_)) => false,
+			_ => self.bookend_style() == other.bookend_style() && self.as_list() == other.as_list(),
+		}
+	}
+}
+
+impl Eq for SExp {}
+
+impl From<&str> for SExp {
+	fn from(s: &str) -> Self {
+		SExp::atom(s)
+	}
+}
+
+impl From<String> for SExp {
+	fn from(s: String) -> Self {
+		SExp::atom(s)
+	}
+}
+
+impl From<Vec<SExp>> for SExp {
+	fn from(elems: Vec<SExp>) -> Self {
+		SExp::list(elems)
+	}
+}
+
+impl fmt::Display for SExp {
+	/// Writes the expression on a single line, with elements separated by single spaces. The alternate form `{:#}`
+	/// writes it as `format_sexp` would with the default configuration.
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		if f.alternate() {
+			return f.write_str(&format_sexp(self, &PrinterConfig::default()));
+		}
+		match self {
+			SExp::Atom(s) => f.write_str(s),
+			SExp::Null(style) => {
+				let (lp, rp) = style.bookends();
+				write!(f, "{}{}", lp, rp)
+			}
+			SExp::List(es, style) => {
+				let (lp, rp) = style.bookends();
+				write!(f, "{}", lp)?;
+				for (i, e) in es.iter().enumerate() {
+					if i > 0 {
+						write!(f, " ")?;
+					}
+					write!(f, "{}", e)?;
+				}
+				write!(f, "{}", rp)
+			}
+		}
+	}
+}
+
+impl FromStr for SExp {
+	type Err = SexpfmtError;
+
+	/// Parses a single expression, which may be surrounded by whitespace and comments.
+	fn from_str(s: &str) -> Result<Self> {
+		let start = Loc::new(0, 1, 1);
+		let mut sexp_vec = parse_form(s.to_string(), start)?;
+		if sexp_vec.len() != 1 {
+			return Err(SexpfmtError::invalid_input(
+				format!("Expected one expression, found {}", sexp_vec.len()),
+				start,
+			));
+		}
+		Ok(sexp_vec.pop().unwrap())
+	}
+}
+
+impl<'a> IntoIterator for &'a SExp {
+	type Item = &'a SExp;
+	type IntoIter = std::slice::Iter<'a, SExp>;
+
+	fn into_iter(self) -> Self::IntoIter {
+		self.iter()
+	}
+}
+
+impl std::ops::Index<usize> for SExp {
+	type Output = SExp;
+
+	/// Panics if this is not a list with more than `index` elements.
+	fn index(&self, index: usize) -> &SExp {
+		match self.as_list() {
+			Some(es) => &es[index],
+			None => panic!("cannot index into atom {:?}", self),
+		}
+	}
+}
+
+impl std::ops::Index<&str> for SExp {
+	type Output = SExp;
+
+	/// Panics if `get(key)` would return `None`.
+	fn index(&self, key: &str) -> &SExp {
+		match self.get(key) {
+			Some(e) => e,
+			None => panic!("no key {:?} in {:?}", key, self),
+		}
+	}
+}
+
+/// Builds an `SExp` from Lisp-like syntax: `sexp!(define (square x) [* x x])` is the list
+/// `(define (square x) [* x x])`. Each Rust token becomes an atom spelled as written, and `()`, `[]` and `{}` groups
+/// become lists with those bookends. Several tokens are wrapped in a parenthesized list, a single one is not.
+///
+/// Rust splits some spellings into several tokens, such as `-1` or `:key`: write these as `@"-1"`. More generally,
+/// `@x` or `@(expr)` inserts anything that converts into an `SExp`.
+#[macro_export]
+macro_rules! sexp {
+	(( $($inner:tt)* )) => {
+		$crate::SExp::list_with_style(
+			$crate::__sexp_elems!(@acc [] $($inner)*),
+			$crate::SExpBookendStyle::Parentheses,
+		)
+	};
+	([ $($inner:tt)* ]) => {
+		$crate::SExp::list_with_style(
+			$crate::__sexp_elems!(@acc [] $($inner)*),
+			$crate::SExpBookendStyle::SquareBrackets,
+		)
+	};
+	({ $($inner:tt)* }) => {
+		$crate::SExp::list_with_style(
+			$crate::__sexp_elems!(@acc [] $($inner)*),
+			$crate::SExpBookendStyle::CurlyBraces,
+		)
+	};
+	(@ $e:tt) => {
+		$crate::SExp::from($e)
+	};
+	($atom:tt) => {
+		$crate::SExp::atom(stringify!($atom))
+	};
+	($($inner:tt)*) => {
+		$crate::sexp!(( $($inner)* ))
+	};
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __sexp_elems {
+	(@acc [$($out:expr,)*]) => {
+		vec![$($out,)*]
+	};
+	(@acc [$($out:expr,)*] @ $e:tt $($rest:tt)*) => {
+		$crate::__sexp_elems!(@acc [$($out,)* $crate::SExp::from($e),] $($rest)*)
+	};
+	(@acc [$($out:expr,)*] $t:tt $($rest:tt)*) => {
+		$crate::__sexp_elems!(@acc [$($out,)* $crate::sexp!($t),] $($rest)*)
+	};
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn parse(text: &str) -> SExp {
+		text.parse().unwrap()
+	}
+
+	#[test]
+	fn test_sexp_macro() {
+		let name = "\"Ada\"";
+		assert_eq!(
+			sexp!(person {name @name} [tags] () @"-1" @(SExp::list(["x".into()]))),
+			parse("(person {name \"Ada\"} [tags] () -1 (x))")
+		);
+		assert_eq!(sexp!(x), SExp::atom("x"));
+		assert_eq!(sexp!("a \"b\""), parse(r#""a \"b\"""#));
+		assert_eq!(sexp!([]), SExp::Null(SExpBookendStyle::SquareBrackets));
+	}
+
+	#[test]
+	fn test_sexp_accessors() {
+		let e = parse("(item :name \"x\" :size (3 4))");
+		assert_eq!(e.head().and_then(SExp::as_atom), Some("item"));
+		assert_eq!(e.tail().map(<[SExp]>::len), Some(4));
+		assert_eq!(e.get(":name"), Some(&SExp::atom("\"x\"")));
+		assert_eq!(e.get(":missing"), None);
+		assert_eq!(e[":size"][1], SExp::atom("4"));
+		assert_eq!(e.len(), Some(5));
+		assert_eq!(SExp::atom("a").len(), None);
+		assert_eq!(SExp::atom("a").is_empty(), None);
+		assert_eq!(e.iter().filter(|e| e.is_atom()).count(), 4);
+
+		let mut null = SExp::null();
+		assert!(null.is_null() && null.is_list() && null.is_empty() == Some(true));
+		null.as_list_mut().unwrap().push(SExp::atom("a"));
+		assert_eq!(null, sexp!((a)));
+		null.as_list_mut().unwrap().clear();
+		assert!(null.is_null());
+		assert_eq!(null, SExp::null());
+		assert_ne!(null, SExp::Null(SExpBookendStyle::SquareBrackets));
+		assert_eq!(null.head(), None);
+		assert_eq!(null.tail(), None);
+		assert_eq!(SExp::atom("a").as_list(), None);
+		assert_eq!(SExp::atom("a").iter().count(), 0);
+	}
+
+	#[test]
+	fn test_sexp_display() {
+		let e = parse("(define\n  (f x) ; comment\n  [g {}   \"a  b\"])");
+		assert_eq!(e.to_string(), "(define (f x) [g {} \"a  b\"])");
+		assert_eq!(e.to_string().parse::<SExp>().unwrap(), e);
+		assert_eq!(
+			format!("{:#}", e),
+			format_sexp(&e, &PrinterConfig::default())
+		);
+		assert_eq!(format!("{:#}", sexp!(a)), "a");
+	}
+
+	#[test]
+	fn test_sexp_from_str_errors() {
+		assert!(matches!(
+			"a b".parse::<SExp>(),
+			Err(SexpfmtError::InvalidInput { .. })
+		));
+		assert!(matches!(
+			" ; nothing".parse::<SExp>(),
+			Err(SexpfmtError::InvalidInput { .. })
+		));
+		assert!(matches!(
+			"(a".parse::<SExp>(),
+			Err(SexpfmtError::Parse { .. })
+		));
+	}
+}
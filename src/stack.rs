@@ -0,0 +1,52 @@
+// The parser and printer recurse on nested lists, so deeply nested input is handled on a thread of its own, with a
+// stack sized for its depth. Shallow input, by far the common case, is handled on the calling thread.
+
+use super::*;
+
+use std::cell::Cell;
+
+// The depth that any thread's stack is assumed to have room for, even in debug builds, where each level of the parser
+// takes about 11 KiB
+const INLINE_DEPTH: usize = 64;
+// The stack taken by each level of nesting, with room to spare
+const STACK_PER_LEVEL: usize = 32 * 1024;
+const BASE_STACK: usize = 1024 * 1024;
+
+// What functions that cannot fail panic with when `with_stack_for_depth` does
+pub(crate) const STACK_SPAWN_FAILED: &str = "failed to spawn a thread for deeply nested input";
+
+thread_local! {
+	// The depth that the stack of this thread has room for
+	static ROOM: Cell<usize> = const { Cell::new(INLINE_DEPTH) };
+}
+
+/// Runs `f`, which recurses on lists nested up to `depth` deep, on a thread with a stack deep enough for it unless the
+/// current thread is known to have the room. Threads are not available on WebAssembly, where `f` always runs inline.
+/// Fails with `SexpfmtError::Io` if no thread can be spawned with that stack.
+pub(crate) fn with_stack_for_depth<T: Send>(
+	depth: usize,
+	f: impl FnOnce() -> T + Send,
+) -> Result<T> {
+	if cfg!(target_family = "wasm") || depth <= ROOM.get() {
+		return Ok(f());
+	}
+	std::thread::scope(|scope| {
+		let handle = std::thread::Builder::new()
+			.stack_size(BASE_STACK.saturating_add(depth.saturating_mul(STACK_PER_LEVEL)))
+			.spawn_scoped(scope, || {
+				ROOM.set(depth);
+				f()
+			})
+			.map_err(|e| {
+				std::io::Error::new(
+					e.kind(),
+					format!("cannot spawn a thread with the stack for lists nested {depth} deep: {e}"),
+				)
+			})?;
+		Ok(
+			handle
+				.join()
+				.unwrap_or_else(|panic| std::panic::resume_unwind(panic)),
+		)
+	})
+}
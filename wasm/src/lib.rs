@@ -0,0 +1,160 @@
+//! WebAssembly bindings for `sexpfmt`, for use from JavaScript in browsers and Node.
+//!
+//! Build with `wasm-pack build wasm --target web` (or `--target nodejs`), then:
+//!
+//! ```js
+//! import init, { format, FormatOptions } from "./pkg/sexpfmt_wasm.js";
+//! await init();
+//! const { output, diagnostics } = format("(a\n b)", new FormatOptions());
+//! ```
+//!
+//! `format` throws if the options are out of range, as when a width is negative:
+//!
+//! ```js
+//! const options = new FormatOptions();
+//! options.indent_width = -1;
+//! format("(a)", options); // throws Error: margin_width and indent_width must not be negative
+//! ```
+
+use sexpfmt::{PrinterConfig, format_str};
+
+use wasm_bindgen::prelude::*;
+
+// The deepest nesting accepted. WebAssembly has no threads to give deeply nested input a deeper stack, and its stack is
+// 1 MiB by default, while each level of the parser and printer takes a few kilobytes.
+const MAX_DEPTH: usize = 256;
+
+/// Options controlling the layout of formatted output.
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FormatOptions {
+	/// The width that lines should fit in, if possible.
+	pub margin_width: i32,
+	/// The number of spaces by which each level of nesting is indented.
+	pub indent_width: i32,
+}
+
+#[wasm_bindgen]
+impl FormatOptions {
+	/// Returns the options used by the `sexpfmt` command-line tool.
+	#[wasm_bindgen(constructor)]
+	pub fn new() -> Self {
+		let config = PrinterConfig::default();
+		Self {
+			margin_width: config.margin_width,
+			indent_width: config.indent_width,
+		}
+	}
+}
+
+impl Default for FormatOptions {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+/// A problem found in the input. Lines and columns start at 1, and are `undefined` when unknown.
+#[wasm_bindgen(getter_with_clone)]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+	pub message: String,
+	pub line: Option<usize>,
+	pub column: Option<usize>,
+	pub offset: Option<usize>,
+}
+
+/// The formatted text, or `undefined` if the input could not be formatted, in which case `diagnostics` says why.
+#[wasm_bindgen(getter_with_clone)]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FormatResult {
+	pub output: Option<String>,
+	pub diagnostics: Vec<Diagnostic>,
+}
+
+/// Formats `text` as the `sexpfmt` command-line tool would. If `options` is omitted, the defaults are used.
+/// Throws an `Error` if the options are out of range.
+#[wasm_bindgen]
+pub fn format(text: &str, options: Option<FormatOptions>) -> Result<FormatResult, JsError> {
+	let config = config_of(options.unwrap_or_default()).map_err(JsError::new)?;
+	Ok(format_with(text, &config))
+}
+
+fn config_of(options: FormatOptions) -> Result<PrinterConfig, &'static str> {
+	if options.margin_width < 0 || options.indent_width < 0 {
+		return Err("margin_width and indent_width must not be negative");
+	}
+	Ok(PrinterConfig {
+		margin_width: options.margin_width,
+		indent_width: options.indent_width,
+		max_depth: MAX_DEPTH,
+		..PrinterConfig::default()
+	})
+}
+
+fn format_with(text: &str, config: &PrinterConfig) -> FormatResult {
+	match format_str(text, config) {
+		Ok(output) => FormatResult {
+			output: Some(output),
+			diagnostics: Vec::new(),
+		},
+		Err(e) => {
+			let position = e.position();
+			FormatResult {
+				output: None,
+				diagnostics: vec![Diagnostic {
+					message: e.to_string(),
+					line: position.map(|p| p.line()),
+					column: position.map(|p| p.column()),
+					offset: position.map(|p| p.offset()),
+				}],
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn format(text: &str, options: Option<FormatOptions>) -> FormatResult {
+		format_with(text, &config_of(options.unwrap_or_default()).unwrap())
+	}
+
+	#[test]
+	fn test_format_ok() {
+		let res = format("(hello\n  world)", None);
+		assert_eq!(res.output.as_deref(), Some("(hello world)\n"));
+		assert!(res.diagnostics.is_empty());
+	}
+
+	#[test]
+	fn test_format_options() {
+		let options = FormatOptions {
+			margin_width: 10,
+			indent_width: 4,
+		};
+		let res = format("(hello world)", Some(options));
+		assert_eq!(res.output.as_deref(), Some("(hello\n    world)\n"));
+	}
+
+	#[test]
+	fn test_invalid_options() {
+		for (margin_width, indent_width) in [(-1, 2), (80, -2)] {
+			let options = FormatOptions {
+				margin_width,
+				indent_width,
+			};
+			assert!(config_of(options).is_err());
+		}
+	}
+
+	#[test]
+	fn test_format_diagnostics() {
+		let res = format("(a]", None);
+		assert_eq!(res.output, None);
+		assert_eq!(res.diagnostics.len(), 1);
+		assert!(res.diagnostics[0].message.contains("Mismatched bookends"));
+		assert_eq!(res.diagnostics[0].line, Some(1));
+		assert_eq!(res.diagnostics[0].column, Some(1));
+	}
+}